@@ -15,10 +15,11 @@ fuzz_target!(|data: &[u8]| {
             serverip: "192.168.0.1".parse().unwrap(),
             ifindex: 1,
             if_mtu: Some(1500),
+            if_name: None,
             if_router: None,
         };
 
-        if let Ok(reply) = erbium::dhcp::handle_pkt(&mut pools, &request, serverids, &cfg) {
+        if let Ok(Some(reply)) = erbium::dhcp::handle_pkt(&mut pools, &request, serverids, &cfg) {
             let _ = reply.serialise();
         }
     }
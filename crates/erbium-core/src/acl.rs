@@ -39,6 +39,8 @@ pub struct Permission {
 
 pub struct Attributes {
     pub addr: NetAddr,
+    /// The name of the interface the request arrived on, if known.
+    pub interface: Option<String>,
 }
 
 impl std::fmt::Display for Attributes {
@@ -53,15 +55,17 @@ impl Default for Attributes {
     fn default() -> Self {
         Self {
             addr: UNSPECIFIED6.with_port(0),
+            interface: None,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct Acl {
+/// Which clients something applies to.  Every condition that is set must match.
+#[derive(Debug, Default)]
+pub struct ClientMatch {
     pub subnet: Option<Vec<Prefix>>,
     pub unix: Option<bool>,
-    pub permission: Permission,
+    pub interfaces: Option<Vec<String>>,
 }
 
 fn check_subnet(attr: &Attributes, prefix: &Prefix) -> bool {
@@ -71,8 +75,8 @@ fn check_subnet(attr: &Attributes, prefix: &Prefix) -> bool {
     }
 }
 
-impl Acl {
-    fn check(&self, attr: &Attributes) -> Option<&'_ Permission> {
+impl ClientMatch {
+    pub fn matches(&self, attr: &Attributes) -> bool {
         let mut ok = true;
         /* Check that the addr is contained within any of the subnets */
         ok = ok
@@ -85,8 +89,45 @@ impl Acl {
         if let Some(unix) = self.unix {
             ok = ok && attr.addr.as_unix_addr().is_some() == unix;
         }
+        /* Check that the request arrived on any of the interfaces */
+        if let Some(interfaces) = &self.interfaces {
+            ok = ok
+                && attr.interface.as_ref().is_some_and(|name| {
+                    interfaces
+                        .iter()
+                        .any(|pattern| interface_matches(pattern, name))
+                });
+        }
+        ok
+    }
+
+    /* Parses one of the match-* keys, returning false if this isn't a key we know about. */
+    pub(crate) fn parse_key(&mut self, key: &str, value: &yaml::Yaml) -> Result<bool, Error> {
+        match key {
+            "match-subnets" => {
+                self.subnet = parse_array("match-subnets", value, parse_string_prefix)?;
+            }
+            "match-unix" => {
+                self.unix = parse_boolean("match-unix", value)?;
+            }
+            "match-interfaces" => {
+                self.interfaces = parse_array("match-interfaces", value, parse_string)?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug)]
+pub struct Acl {
+    pub client: ClientMatch,
+    pub permission: Permission,
+}
 
-        if ok {
+impl Acl {
+    fn check(&self, attr: &Attributes) -> Option<&'_ Permission> {
+        if self.client.matches(attr) {
             Some(&self.permission)
         } else {
             None
@@ -171,8 +212,10 @@ pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
     vec![
         Acl {
             /* Any address we hand out by DHCP we should also accept DNS requests from */
-            subnet: Some(addresses.to_vec()),
-            unix: None,
+            client: ClientMatch {
+                subnet: Some(addresses.to_vec()),
+                ..Default::default()
+            },
             permission: Permission {
                 allow_dns_recursion: true,
                 allow_http_leases: true,
@@ -182,17 +225,19 @@ pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
         },
         Acl {
             /* Any v4/v6 localhost should also be able to accept DNS requests. */
-            subnet: Some(vec![
-                Prefix::V4(Prefix4 {
-                    addr: "127.0.0.0".parse().unwrap(),
-                    prefixlen: 8,
-                }),
-                Prefix::V6(Prefix6 {
-                    addr: "::1".parse().unwrap(),
-                    prefixlen: 128,
-                }),
-            ]),
-            unix: None,
+            client: ClientMatch {
+                subnet: Some(vec![
+                    Prefix::V4(Prefix4 {
+                        addr: "127.0.0.0".parse().unwrap(),
+                        prefixlen: 8,
+                    }),
+                    Prefix::V6(Prefix6 {
+                        addr: "::1".parse().unwrap(),
+                        prefixlen: 128,
+                    }),
+                ]),
+                ..Default::default()
+            },
             permission: Permission {
                 allow_dns_recursion: true,
                 allow_http_leases: true,
@@ -202,8 +247,10 @@ pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
         },
         Acl {
             /* Allow API access over the unix domain socket */
-            subnet: None,
-            unix: Some(true),
+            client: ClientMatch {
+                unix: Some(true),
+                ..Default::default()
+            },
             permission: Permission {
                 allow_dns_recursion: false,
                 allow_http_leases: true,
@@ -217,17 +264,11 @@ pub fn default_acls(addresses: &[Prefix]) -> Vec<Acl> {
 pub(crate) fn parse_acl(name: &str, fragment: &yaml::Yaml) -> Result<Option<Acl>, Error> {
     match fragment {
         yaml::Yaml::Hash(h) => {
-            let mut subnet = None;
-            let mut unix = None;
+            let mut client = ClientMatch::default();
             let mut accesses = vec![];
             for (k, v) in h {
                 match (k.as_str(), v) {
-                    (Some("match-subnets"), s) => {
-                        subnet = parse_array("match-subnets", s, parse_string_prefix)?;
-                    }
-                    (Some("match-unix"), s) => {
-                        unix = parse_boolean("match-unix", s)?;
-                    }
+                    (Some(m), s) if client.parse_key(m, s)? => (),
                     (Some("apply-access"), a) => {
                         accesses =
                             parse_array("apply-access", a, parse_string)?.ok_or_else(|| {
@@ -267,8 +308,7 @@ pub(crate) fn parse_acl(name: &str, fragment: &yaml::Yaml) -> Result<Option<Acl>
                 }
             }
             Ok(Some(Acl {
-                subnet,
-                unix,
+                client,
                 permission: Permission {
                     allow_dns_recursion,
                     allow_http,
@@ -289,11 +329,13 @@ pub(crate) fn parse_acl(name: &str, fragment: &yaml::Yaml) -> Result<Option<Acl>
 fn acl_not_authenticated() {
     use erbium_net::addr::{Ipv4Addr, ToNetAddr as _, WithPort as _};
     let test_acls = vec![Acl {
-        subnet: Some(vec![Prefix::V4(Prefix4 {
-            addr: "192.0.2.0".parse().unwrap(),
-            prefixlen: 24,
-        })]),
-        unix: None,
+        client: ClientMatch {
+            subnet: Some(vec![Prefix::V4(Prefix4 {
+                addr: "192.0.2.0".parse().unwrap(),
+                prefixlen: 24,
+            })]),
+            ..Default::default()
+        },
         permission: Permission {
             allow_dns_recursion: true,
             allow_http: false,
@@ -306,6 +348,7 @@ fn acl_not_authenticated() {
 
     let client = Attributes {
         addr: ip.to_net_addr(),
+        ..Default::default()
     };
 
     assert_eq!(
@@ -320,11 +363,13 @@ fn acl_not_authenticated() {
 fn acl_not_authorized() {
     use erbium_net::addr::{Ipv4Addr, ToNetAddr as _, WithPort as _};
     let test_acls = vec![Acl {
-        subnet: Some(vec![Prefix::V4(Prefix4 {
-            addr: "192.0.2.0".parse().unwrap(),
-            prefixlen: 24,
-        })]),
-        unix: None,
+        client: ClientMatch {
+            subnet: Some(vec![Prefix::V4(Prefix4 {
+                addr: "192.0.2.0".parse().unwrap(),
+                prefixlen: 24,
+            })]),
+            ..Default::default()
+        },
         permission: Permission {
             allow_dns_recursion: false,
             allow_http: false,
@@ -337,6 +382,7 @@ fn acl_not_authorized() {
 
     let client = Attributes {
         addr: ip.to_net_addr(),
+        ..Default::default()
     };
 
     assert_eq!(
@@ -351,11 +397,13 @@ fn acl_not_authorized() {
 fn acl_allowed() {
     use erbium_net::addr::{Ipv4Addr, ToNetAddr as _, WithPort as _};
     let test_acls = vec![Acl {
-        subnet: Some(vec![Prefix::V4(Prefix4 {
-            addr: "192.0.2.0".parse().unwrap(),
-            prefixlen: 24,
-        })]),
-        unix: None,
+        client: ClientMatch {
+            subnet: Some(vec![Prefix::V4(Prefix4 {
+                addr: "192.0.2.0".parse().unwrap(),
+                prefixlen: 24,
+            })]),
+            ..Default::default()
+        },
         permission: Permission {
             allow_dns_recursion: true,
             allow_http: false,
@@ -368,6 +416,7 @@ fn acl_allowed() {
 
     let client = Attributes {
         addr: ip.to_net_addr(),
+        ..Default::default()
     };
 
     assert_eq!(
@@ -428,3 +477,31 @@ fn acl_parse_fail() {
         "Invalid Configuration: apply-access cannot be null"
     );
 }
+
+#[test]
+fn acl_match_interfaces() {
+    use erbium_net::addr::{Ipv4Addr, ToNetAddr as _, WithPort as _};
+    let client = ClientMatch {
+        interfaces: Some(vec!["guest*".into()]),
+        ..Default::default()
+    };
+    let addr = "192.0.2.1"
+        .parse::<Ipv4Addr>()
+        .unwrap()
+        .with_port(0)
+        .to_net_addr();
+
+    assert!(client.matches(&Attributes {
+        addr,
+        interface: Some("guest0".into()),
+    }));
+    assert!(!client.matches(&Attributes {
+        addr,
+        interface: Some("eth0".into()),
+    }));
+    /* If we don't know the interface, it can't match */
+    assert!(!client.matches(&Attributes {
+        addr,
+        interface: None,
+    }));
+}
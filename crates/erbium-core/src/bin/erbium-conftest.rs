@@ -34,5 +34,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Loading config from {}", config_file.display());
     let conf = erbium::config::load_config_from_path(config_file).await?;
     println!("Parse config: {:#?}", conf.read().await);
+    #[cfg(feature = "dhcp")]
+    {
+        let netinfo = erbium_net::netinfo::SharedNetInfo::new().await;
+        let interfaces = netinfo.get_interfaces().await;
+        for pattern in conf.read().await.dhcp.get_match_interfaces() {
+            if !interfaces
+                .iter()
+                .any(|name| erbium::dhcp::config::interface_matches(&pattern, name))
+            {
+                println!(
+                    "Warning: match-interface {} does not match any interface on this machine",
+                    pattern
+                );
+            }
+        }
+    }
     Ok(())
 }
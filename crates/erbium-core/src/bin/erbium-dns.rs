@@ -26,6 +26,7 @@ use erbium::dns;
 enum Error {
     Config(erbium::config::Error),
     Dns(dns::Error),
+    Signal(std::io::Error),
 }
 
 #[cfg(feature = "dns")]
@@ -35,6 +36,7 @@ impl std::fmt::Display for Error {
         match self {
             Config(e) => write!(f, "Failed to load config: {}", e),
             Dns(e) => write!(f, "Dns Error: {}", e),
+            Signal(e) => write!(f, "Failed to listen for signals: {}", e),
         }
     }
 }
@@ -66,13 +68,19 @@ async fn go() -> Result<(), Error> {
     .await
     .map_err(Error::Dns)?;
 
+    let dns_copy = dns.clone();
     services.push(tokio::spawn(async move {
-        dns.run().await.map_err(|err| err.to_string())
+        dns_copy.run().await.map_err(|err| err.to_string())
     }));
 
-    while let Some(x) = services.next().await {
-        println!("Service complete: {:?}", x)
+    tokio::select! {
+        Some(x) = services.next() => println!("Service complete: {:?}", x),
+        x = erbium::shutdown_signal() => {
+            x.map_err(Error::Signal)?;
+            log::info!("Shutting down")
+        }
     }
+    dns.shutdown().await;
 
     Ok(())
 }
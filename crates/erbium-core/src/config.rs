@@ -529,6 +529,23 @@ pub fn parse_duration(
     }
 }
 
+/// Does an interface name match a match-interface pattern?
+///
+/// Patterns are shell style globs, where `*` matches any number of characters and `?` matches
+/// exactly one character, so `vlan*` matches `vlan10` and `vlan20`.
+pub fn interface_matches(pattern: &str, name: &str) -> bool {
+    fn glob(p: &[u8], n: &[u8]) -> bool {
+        match (p.first(), n.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => glob(&p[1..], n) || (!n.is_empty() && glob(p, &n[1..])),
+            (Some(b'?'), Some(_)) => glob(&p[1..], &n[1..]),
+            (Some(a), Some(b)) if a == b => glob(&p[1..], &n[1..]),
+            _ => false,
+        }
+    }
+    glob(pattern.as_bytes(), name.as_bytes())
+}
+
 pub trait PrefixOps {
     type Ip;
     fn network(&self) -> Self::Ip;
@@ -773,6 +790,13 @@ impl Default for AddressType {
     }
 }
 
+/// A certificate and private key, both in PEM format.
+#[derive(Debug)]
+pub struct TlsCertificate {
+    pub certificate: std::path::PathBuf,
+    pub private_key: std::path::PathBuf,
+}
+
 #[derive(Debug, Default)]
 pub struct Config {
     #[cfg(feature = "dhcp")]
@@ -784,8 +808,17 @@ pub struct Config {
     pub addresses: Vec<Prefix>,
     pub listeners: Vec<NetAddr>,
     pub dns_listeners: AddressType,
+    pub dns_tls_listeners: AddressType,
+    pub dns_https_listeners: Vec<NetAddr>,
+    pub dns_tls_certificate: Option<TlsCertificate>,
     #[cfg(feature = "dns")]
     pub dns_routes: Vec<crate::dns::config::Route>,
+    #[cfg(feature = "dns")]
+    pub dns_cache: crate::dns::config::CacheConfig,
+    #[cfg(feature = "dns")]
+    pub dns_views: Vec<crate::dns::config::View>,
+    #[cfg(feature = "dns")]
+    pub dns_rpz: Vec<std::sync::Arc<crate::dns::config::PolicyZone>>,
     pub acls: Vec<crate::acl::Acl>,
 }
 
@@ -818,6 +851,10 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
         let mut ra = None;
         #[cfg(feature = "dhcp")]
         let mut dhcp = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_decline_quarantine = None;
+        #[cfg(feature = "dhcp")]
+        let mut dhcp_conflict_detection = None;
         #[cfg(feature = "dns")]
         let mut dns_servers = vec![INTERFACE4, INTERFACE6];
         #[cfg(not(feature = "dns"))]
@@ -827,8 +864,18 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
         let mut addresses = None;
         let mut listeners = None;
         let mut dns_listeners = None;
+        let mut dns_tls_listeners = None;
+        let mut dns_https_listeners = None;
+        let mut dns_tls_certificate = None;
+        let mut dns_tls_private_key = None;
         #[cfg(feature = "dns")]
         let mut dns_routes = None;
+        #[cfg(feature = "dns")]
+        let mut dns_cache = None;
+        #[cfg(feature = "dns")]
+        let mut dns_views = None;
+        #[cfg(feature = "dns")]
+        let mut dns_rpz = None;
         let mut default_listen_style = DefaultAddressType::Unspecified;
         let mut acls = None;
         for (k, v) in fragment {
@@ -839,6 +886,18 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                     .map_err(|e| e.annotate("while parsing dhcp-policies"))?,
                 #[cfg(not(feature = "dhcp"))]
                 (Some("dhcp-policies"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-decline-quarantine"), d) => {
+                    dhcp_decline_quarantine = parse_duration("dhcp-decline-quarantine", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-decline-quarantine"), _) => (),
+                #[cfg(feature = "dhcp")]
+                (Some("dhcp-conflict-detection"), d) => {
+                    dhcp_conflict_detection = parse_boolean("dhcp-conflict-detection", d)?;
+                }
+                #[cfg(not(feature = "dhcp"))]
+                (Some("dhcp-conflict-detection"), _) => (),
                 (Some("router-advertisements"), r) => ra = crate::radv::config::parse(r)
                     .map_err(|e| e.annotate("while parsing router-advertisements"))?,
                 (Some("dns-servers"), s) => {
@@ -865,6 +924,19 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                     dns_listeners = parse_array("dns-listeners",s, parse_string_sockaddr)?
                         .map(AddressType::Addresses);
                 }
+                (Some("dns-tls-listeners"), s) => {
+                    dns_tls_listeners = parse_array("dns-tls-listeners", s, parse_string_sockaddr)?
+                        .map(AddressType::Addresses);
+                }
+                (Some("dns-https-listeners"), s) => {
+                    dns_https_listeners = parse_array("dns-https-listeners", s, parse_string_sockaddr)?;
+                }
+                (Some("dns-tls-certificate"), s) => {
+                    dns_tls_certificate = parse_string("dns-tls-certificate", s)?;
+                }
+                (Some("dns-tls-private-key"), s) => {
+                    dns_tls_private_key = parse_string("dns-tls-private-key", s)?;
+                }
                 (Some("default-listen-style"), s) => {
                     match s.as_str() {
                         None => return Err(Error::InvalidConfig(format!("invalid default-listen-style type: {}",
@@ -882,6 +954,21 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                     dns_routes = crate::dns::config::parse_dns_routes("dns-routes", s)?;
                     }
                 }
+                (Some("dns-cache"), s) => {
+                    #[cfg(feature = "dns")] {
+                    dns_cache = crate::dns::config::parse_cache_config("dns-cache", s)?;
+                    }
+                }
+                (Some("dns-views"), s) => {
+                    #[cfg(feature = "dns")] {
+                    dns_views = crate::dns::config::parse_dns_views("dns-views", s)?;
+                    }
+                }
+                (Some("dns-rpz"), s) => {
+                    #[cfg(feature = "dns")] {
+                    dns_rpz = crate::dns::config::parse_policy_zones("dns-rpz", s)?;
+                    }
+                }
                 (Some(x), _) => {
                     return Err(Error::InvalidConfig(format!(
                         "Unknown configuration option {}",
@@ -897,9 +984,36 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
             }
         }
         let addresses = addresses.unwrap_or_default();
+        let dns_tls_certificate = match (dns_tls_certificate, dns_tls_private_key) {
+            (Some(certificate), Some(private_key)) => Some(TlsCertificate {
+                certificate: certificate.into(),
+                private_key: private_key.into(),
+            }),
+            (None, None) => None,
+            (Some(_), None) => {
+                return Err(Error::InvalidConfig(
+                    "dns-tls-certificate requires dns-tls-private-key".into(),
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(Error::InvalidConfig(
+                    "dns-tls-private-key requires dns-tls-certificate".into(),
+                ))
+            }
+        };
+        let dns_https_listeners = dns_https_listeners.unwrap_or_default();
+        if dns_tls_certificate.is_none() && !dns_https_listeners.is_empty() {
+            return Err(Error::InvalidConfig(
+                "dns-https-listeners requires dns-tls-certificate".into(),
+            ));
+        }
         let conf = Config {
             #[cfg(feature = "dhcp")]
-            dhcp: dhcp.unwrap_or_default(),
+            dhcp: crate::dhcp::config::Config {
+                decline_quarantine: dhcp_decline_quarantine,
+                conflict_detection: dhcp_conflict_detection.unwrap_or(false),
+                ..dhcp.unwrap_or_default()
+            },
             ra: ra.unwrap_or_default(),
             dns_servers,
             dns_search,
@@ -915,8 +1029,28 @@ fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
                 }
                 DefaultAddressType::Interface => AddressType::BindInterface,
             }),
+            dns_tls_listeners: dns_tls_listeners.unwrap_or_else(|| match default_listen_style {
+                DefaultAddressType::Unspecified => {
+                    AddressType::Addresses(vec![std::net::SocketAddrV6::new(
+                        UNSPECIFIED6,
+                        853,
+                        0,
+                        0,
+                    )
+                    .into()])
+                }
+                DefaultAddressType::Interface => AddressType::BindInterface,
+            }),
+            dns_https_listeners,
+            dns_tls_certificate,
             #[cfg(feature = "dns")]
             dns_routes: dns_routes.unwrap_or_default(),
+            #[cfg(feature = "dns")]
+            dns_cache: dns_cache.unwrap_or_default(),
+            #[cfg(feature = "dns")]
+            dns_views: dns_views.unwrap_or_default(),
+            #[cfg(feature = "dns")]
+            dns_rpz: dns_rpz.unwrap_or_default(),
             captive_portal,
             listeners: listeners.unwrap_or_else(|| {
                 vec![UnixAddr::new("/var/lib/erbium/control")
@@ -1046,6 +1180,29 @@ dns-listeners: [192.0.2.0:53]
     Ok(())
 }
 
+#[test]
+fn test_tls_listeners_parse() -> Result<(), Error> {
+    load_config_from_string(
+        "---
+dns-tls-listeners: ['[::]:853']
+dns-https-listeners: ['[::]:443']
+dns-tls-certificate: /etc/erbium/cert.pem
+dns-tls-private-key: /etc/erbium/key.pem
+",
+    )?;
+    for cfg in [
+        "---
+dns-tls-certificate: /etc/erbium/cert.pem
+",
+        "---
+dns-https-listeners: ['[::]:443']
+",
+    ] {
+        assert!(load_config_from_string(cfg).is_err());
+    }
+    Ok(())
+}
+
 #[test]
 fn test_duration() {
     assert_eq!(
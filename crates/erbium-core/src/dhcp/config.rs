@@ -18,7 +18,8 @@
  */
 use super::dhcppkt;
 use std::convert::TryFrom as _;
-use std::ops::Sub;
+use std::ops::BitOr as _;
+use std::ops::Sub as _;
 use yaml_rust::yaml;
 
 pub use crate::config::*;
@@ -29,6 +30,8 @@ pub struct Policy {
     pub match_interface: Option<Option<String>>,
     pub match_chaddr: Option<Vec<u8>>,
     pub match_subnet: Option<erbium_net::Ipv4Subnet>,
+    pub match_circuit_id: Option<Vec<u8>>,
+    pub match_remote_id: Option<Vec<u8>>,
     pub match_other:
         std::collections::HashMap<dhcppkt::DhcpOption, Option<dhcppkt::DhcpOptionTypeValue>>,
     pub apply_address: Option<super::pool::PoolAddresses>,
@@ -47,6 +50,8 @@ impl Clone for Policy {
             address_cache: Default::default(),
             match_interface: self.match_interface.clone(),
             match_chaddr: self.match_chaddr.clone(),
+            match_circuit_id: self.match_circuit_id.clone(),
+            match_remote_id: self.match_remote_id.clone(),
             match_other: self.match_other.clone(),
             apply_address: self.apply_address.clone(),
             apply_other: self.apply_other.clone(),
@@ -57,27 +62,38 @@ impl Clone for Policy {
 }
 
 impl Policy {
-    fn get_all_used_addresses(&self) -> std::collections::HashSet<std::net::Ipv4Addr> {
+    fn get_all_used_addresses(&self) -> super::pool::PoolAddresses {
         /* We cache the result of this. */
         if self.address_cache.lock().unwrap().borrow().is_none() {
             /* Cache is cold, heat it. */
-            let mut addrset: std::collections::HashSet<std::net::Ipv4Addr> = Default::default();
-            if let Some(address) = &self.apply_address {
-                addrset.extend(address.iter());
-            }
+            let mut addrset = self.apply_address.clone().unwrap_or_default();
             for p in &self.policies {
-                addrset.extend(p.get_all_used_addresses().iter());
+                addrset = addrset.bitor(&p.get_all_used_addresses());
             }
             self.address_cache.lock().unwrap().replace(Some(addrset));
         }
 
         self.address_cache.lock().unwrap().borrow().clone().unwrap()
     }
+
+    fn get_match_interfaces(&self) -> Vec<String> {
+        let mut interfaces: Vec<String> = self.match_interface.iter().flatten().cloned().collect();
+        for p in &self.policies {
+            interfaces.extend(p.get_match_interfaces());
+        }
+        interfaces
+    }
 }
 
+pub use crate::config::interface_matches;
+
 #[derive(Debug, Default)]
 pub struct Config {
     pub policies: Vec<Policy>,
+    /// How long to avoid handing out an address after a client has declined it.
+    pub decline_quarantine: Option<std::time::Duration>,
+    /// Probe addresses before offering them to a new client, to see if they are already in use.
+    pub conflict_detection: bool,
 }
 
 impl Config {
@@ -91,21 +107,25 @@ impl Config {
                 ),
                 ..Default::default()
             }],
+            ..Default::default()
         }
     }
 
-    pub fn get_all_used_addresses(&self) -> std::collections::HashSet<std::net::Ipv4Addr> {
+    pub fn get_all_used_addresses(&self) -> super::pool::PoolAddresses {
         self.policies
             .iter()
             .map(Policy::get_all_used_addresses)
-            .fold(
-                std::collections::HashSet::<std::net::Ipv4Addr>::new(),
-                |mut acc, e| {
-                    acc.extend(e);
-                    acc
-                },
-            )
+            .fold(super::pool::PoolAddresses::new(), |acc, e| acc.bitor(&e))
     }
+
+    /// Returns all the interface patterns used by match-interface in any policy.
+    pub fn get_match_interfaces(&self) -> Vec<String> {
+        self.policies
+            .iter()
+            .flat_map(Policy::get_match_interfaces)
+            .collect()
+    }
+
     fn parse_routes(fragment: &yaml::Yaml) -> Result<Option<Vec<dhcppkt::Route>>, Error> {
         match fragment {
             yaml::Yaml::Null => Ok(None),
@@ -346,7 +366,7 @@ impl Config {
     fn parse_policy(fragment: &yaml::Yaml) -> Result<Policy, Error> {
         if let Some(h) = fragment.as_hash() {
             let mut policy: Policy = Default::default();
-            let mut addresses: Option<Vec<std::net::Ipv4Addr>> = None;
+            let mut addresses: Option<super::pool::PoolAddresses> = None;
             for (k, v) in h {
                 match k.as_str() {
                     Some("match-interface") => {
@@ -378,6 +398,36 @@ impl Config {
                                 })?,
                         );
                     }
+                    Some("match-circuit-id") => {
+                        if policy.match_circuit_id.is_some() {
+                            return Err(Error::InvalidConfig(
+                                "match-circuit-id specified twice".into(),
+                            ));
+                        }
+                        policy.match_circuit_id = Some(
+                            parse_string("match-circuit-id", v)
+                                .map_err(|x| x.annotate("Failed to parse match-circuit-id"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("match-circuit-id cannot be nil".into())
+                                })?
+                                .into_bytes(),
+                        );
+                    }
+                    Some("match-remote-id") => {
+                        if policy.match_remote_id.is_some() {
+                            return Err(Error::InvalidConfig(
+                                "match-remote-id specified twice".into(),
+                            ));
+                        }
+                        policy.match_remote_id = Some(
+                            parse_string("match-remote-id", v)
+                                .map_err(|x| x.annotate("Failed to parse match-remote-id"))?
+                                .ok_or_else(|| {
+                                    Error::InvalidConfig("match-remote-id cannot be nil".into())
+                                })?
+                                .into_bytes(),
+                        );
+                    }
                     Some(x) if x.starts_with("match-") => {
                         let name = &x[6..];
                         let (opt, value) = Config::parse_generic(name, v)
@@ -385,8 +435,8 @@ impl Config {
                         policy.match_other.insert(opt, value);
                     }
                     Some("apply-address") => {
-                        let addresses = addresses.get_or_insert_with(Vec::new);
-                        addresses.push(
+                        let addresses = addresses.get_or_insert_with(Default::default);
+                        addresses.insert(
                             parse_string_ip4("apply-address", v)
                                 .map_err(|x| x.annotate("Failed to parse apply-address"))?
                                 .ok_or_else(|| {
@@ -464,10 +514,9 @@ impl Config {
                             let end = end.ok_or_else(|| {
                                 Error::InvalidConfig("Missing end in range".into())
                             })?;
-                            let addresses = addresses.get_or_insert_with(Vec::new);
-                            for i in u32::from(start)..=u32::from(end) {
-                                addresses.push(i.into());
-                            }
+                            addresses
+                                .get_or_insert_with(Default::default)
+                                .insert_range(start, end);
                         } else {
                             return Err(Error::InvalidConfig(format!(
                                 "Range should be a hash, not '{:?}'",
@@ -482,9 +531,11 @@ impl Config {
                                 Error::InvalidConfig("apply-subnet cannot be nil".into())
                             })?;
                         let base: u32 = subnet.network().into();
-                        let addresses = addresses.get_or_insert_with(Vec::new);
-                        for i in 1..(((1 << (32 - subnet.prefixlen)) - 1) - 1) {
-                            addresses.push((base + i).into())
+                        let size = 1u64 << (32 - subnet.prefixlen);
+                        let addresses = addresses.get_or_insert_with(Default::default);
+                        if size > 3 {
+                            addresses
+                                .insert_range((base + 1).into(), (base + (size - 3) as u32).into());
                         }
                     }
                     Some(x) if x.starts_with("apply-") => {
@@ -517,9 +568,7 @@ impl Config {
             }
             /* If this Policy overrides addresses, then remove any addresses that are reserved for
              * sub policies */
-            if let Some(mut addrvec) = addresses {
-                let mut addrset: std::collections::HashSet<std::net::Ipv4Addr> = Default::default();
-                addrset.extend(addrvec.drain(..));
+            if let Some(mut addrset) = addresses {
                 for p in &policy.policies {
                     addrset = addrset.sub(&p.get_all_used_addresses());
                 }
@@ -548,6 +597,7 @@ impl Config {
     pub fn new(y: &yaml::Yaml) -> Result<Option<Self>, Error> {
         Ok(Some(Config {
             policies: Config::parse_policies(y)?,
+            ..Default::default()
         }))
     }
 }
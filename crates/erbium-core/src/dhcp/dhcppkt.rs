@@ -61,6 +61,9 @@ pub struct DhcpOp(u8);
 pub const OP_BOOTREQUEST: DhcpOp = DhcpOp(1);
 pub const OP_BOOTREPLY: DhcpOp = DhcpOp(2);
 
+/* RFC2131 Section 2: The leftmost bit of the 'flags' field is the BROADCAST (B) flag. */
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
 impl ToString for DhcpOp {
     fn to_string(&self) -> String {
         match self {
@@ -206,6 +209,7 @@ pub const OPTION_STREETTALK: DhcpOption = DhcpOption(75);
 pub const OPTION_STDA: DhcpOption = DhcpOption(76);
 pub const OPTION_USERCLASS: DhcpOption = DhcpOption(77); /* RFC3004 */
 pub const OPTION_FQDN: DhcpOption = DhcpOption(81); /* RFC4702 */
+pub const OPTION_RELAYAGENTINFO: DhcpOption = DhcpOption(82); /* RFC3046 */
 pub const OPTION_UUID: DhcpOption = DhcpOption(97); /* RFC4578 */
 pub const OPTION_PCODE: DhcpOption = DhcpOption(100); /* RFC4833 */
 pub const OPTION_TCODE: DhcpOption = DhcpOption(101); /* RFC4833 */
@@ -359,7 +363,8 @@ const OPT_INFO: &[(&str, DhcpOption, DhcpOptionType)] = &[
     // 80
     //("rapid-commit", OPTION_RAPID_COMMIT
     ("fqdn", OPTION_FQDN, DhcpOptionType::String),
-    // option 82 (relay agent information) needs special handling.
+    // Decoded into RelayAgentInfo, so can't be applied or matched on directly.
+    ("relay-agent-info", OPTION_RELAYAGENTINFO, DhcpOptionType::Unknown),
     // iSNS
     // NDS Servers
     // NDS Tree
@@ -753,6 +758,43 @@ impl DhcpParse for String {
     }
 }
 
+/* Sub-options of the Relay Agent Information option */
+pub const RELAYINFO_CIRCUITID: u8 = 1; /* RFC3046 */
+pub const RELAYINFO_REMOTEID: u8 = 2; /* RFC3046 */
+pub const RELAYINFO_SUBSCRIBERID: u8 = 6; /* RFC3993 */
+
+/// The Relay Agent Information option (82), added to requests by relay agents to identify where
+/// the client is attached.
+#[derive(Debug, Clone, PartialEq, Default, Eq)]
+pub struct RelayAgentInfo {
+    /// Identifies the circuit (eg the switch port) the request was received on.
+    pub circuit_id: Option<Vec<u8>>,
+    /// Identifies the remote host end of the circuit (eg a modem or the switch).
+    pub remote_id: Option<Vec<u8>>,
+    /// Identifies the subscriber, as provisioned on the relay agent.
+    pub subscriber_id: Option<String>,
+}
+
+impl DhcpParse for RelayAgentInfo {
+    type Item = Self;
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut buf = pktparser::Buffer::new(v);
+        let mut ret = RelayAgentInfo::default();
+        while !buf.empty() {
+            match buf.get_tlv()? {
+                (RELAYINFO_CIRCUITID, id) => ret.circuit_id = Some(id.to_vec()),
+                (RELAYINFO_REMOTEID, id) => ret.remote_id = Some(id.to_vec()),
+                (RELAYINFO_SUBSCRIBERID, id) => {
+                    ret.subscriber_id = Some(String::from_utf8_lossy(id).to_string())
+                }
+                /* Sub-options we don't understand are ignored, but still echoed back */
+                _ => (),
+            }
+        }
+        Some(ret)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Default, Eq)]
 pub struct DhcpOptions {
     pub other: collections::HashMap<DhcpOption, Vec<u8>>,
@@ -787,6 +829,10 @@ impl DhcpOptions {
         self.get_option::<String>(&OPTION_HOSTNAME)
     }
 
+    pub fn get_relay_agent_info(&self) -> Option<RelayAgentInfo> {
+        self.get_option::<RelayAgentInfo>(&OPTION_RELAYAGENTINFO)
+    }
+
     #[must_use]
     pub fn set_raw_option(mut self, option: &DhcpOption, value: &[u8]) -> Self {
         self.other.insert(*option, value.to_vec());
@@ -1059,7 +1105,16 @@ where
 impl Serialise for DhcpOptions {
     fn serialise(&self, v: &mut Vec<u8>) {
         for (o, p) in self.other.iter() {
-            serialise_option(*o, p, v);
+            if *o != OPTION_RELAYAGENTINFO {
+                serialise_option(*o, p, v);
+            }
+        }
+
+        /* RFC3046 Section 2.1: the Relay Agent Information option ... SHALL be the last option
+         * (but before 'End Option' 255) in the DHCP options field.
+         */
+        if let Some(p) = self.other.get(&OPTION_RELAYAGENTINFO) {
+            serialise_option(OPTION_RELAYAGENTINFO, p, v);
         }
 
         /* Add end of options marker */
@@ -1250,3 +1305,35 @@ fn test_parse() {
         "192.0.2.0/24->192.0.2.254,198.51.100.0/24->192.0.2.254"
     );
 }
+
+#[test]
+fn test_relay_agent_info() {
+    let raw = [
+        1, 4, b'p', b'o', b'r', b't', /* Circuit ID */
+        2, 2, 0xab, 0xcd, /* Remote ID */
+        9, 1, 0x00, /* Some unknown sub-option */
+        6, 3, b's', b'u', b'b', /* Subscriber ID */
+    ];
+    assert_eq!(
+        RelayAgentInfo::parse_into(&raw),
+        Some(RelayAgentInfo {
+            circuit_id: Some(b"port".to_vec()),
+            remote_id: Some(vec![0xab, 0xcd]),
+            subscriber_id: Some("sub".into()),
+        })
+    );
+    /* Truncated sub-options are rejected */
+    assert_eq!(RelayAgentInfo::parse_into(&raw[..3]), None);
+
+    /* The option must be serialised last */
+    let mut v = vec![];
+    DhcpOptions::default()
+        .set_raw_option(&OPTION_RELAYAGENTINFO, &raw)
+        .set_option(&OPTION_MSGTYPE, &DHCPACK)
+        .set_option(&OPTION_HOSTNAME, &String::from("host"))
+        .serialise(&mut v);
+    let tail = &v[v.len() - raw.len() - 3..];
+    assert_eq!(tail[..2], [82, raw.len() as u8]);
+    assert_eq!(tail[2..tail.len() - 1], raw);
+    assert_eq!(tail[tail.len() - 1], 255);
+}
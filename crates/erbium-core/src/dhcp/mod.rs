@@ -32,6 +32,7 @@ use erbium_net::udp;
 pub mod config;
 pub mod dhcppkt;
 pub mod pool;
+mod probe;
 #[cfg(test)]
 mod test;
 
@@ -58,6 +59,12 @@ lazy_static::lazy_static! {
         &["reason"]
     )
     .unwrap();
+    static ref DHCP_NAKS: prometheus::IntCounter =
+        prometheus::register_int_counter!("dhcp_naks", "Number of DHCP requests refused with a DHCPNAK")
+            .unwrap();
+    static ref DHCP_CONFLICTS: prometheus::IntCounter =
+        prometheus::register_int_counter!("dhcp_conflicts", "Number of addresses found in use when probed before offering them")
+            .unwrap();
     static ref DHCP_ACTIVE_LEASES: prometheus::IntGauge = prometheus::register_int_gauge!(
         "dhcp_active_leases",
         "Counts of leases that are currently in use"
@@ -113,6 +120,7 @@ impl DhcpError {
             NoPolicyConfigured => "NO_POLICY",
             PoolError(pool::Error::NoAssignableAddress) => "NO_ADDRESS",
             PoolError(pool::Error::RequestedAddressInUse) => "ADDRESS_IN_USE",
+            PoolError(pool::Error::LeaseNotFound) => "LEASE_NOT_FOUND",
             PoolError(_) => "INTERNAL_POOL_ERROR",
         }
     }
@@ -126,10 +134,27 @@ pub struct DHCPRequest {
     pub serverip: std::net::Ipv4Addr,
     /// The interface index that the request was received on.
     pub ifindex: u32,
+    /// The name of the interface that the request was received on.
+    pub if_name: Option<String>,
     pub if_mtu: Option<u32>,
     pub if_router: Option<std::net::Ipv4Addr>,
 }
 
+impl DHCPRequest {
+    /// The address used to identify the network the client is on.
+    ///
+    /// RFC2131 Section 4.3.1: the address is selected based on the subnet from which the message
+    /// was received (if 'giaddr' is 0) or on the address of the relay agent that forwarded the
+    /// message ('giaddr' when not 0).
+    pub fn get_network_addr(&self) -> std::net::Ipv4Addr {
+        if self.pkt.giaddr.is_unspecified() {
+            self.serverip
+        } else {
+            self.pkt.giaddr
+        }
+    }
+}
+
 #[cfg(test)]
 impl std::default::Default for DHCPRequest {
     fn default() -> Self {
@@ -156,6 +181,7 @@ impl std::default::Default for DHCPRequest {
             },
             serverip: "0.0.0.0".parse().unwrap(),
             ifindex: 0,
+            if_name: None,
             if_mtu: None,
             if_router: None,
         }
@@ -171,10 +197,20 @@ enum PolicyMatch {
 
 fn check_policy(req: &DHCPRequest, policy: &config::Policy) -> PolicyMatch {
     let mut outcome = PolicyMatch::NoMatch;
-    //if let Some(policy.match_interface ...
     if policy.match_all {
         outcome = PolicyMatch::MatchSucceeded;
     }
+    if let Some(match_interface) = &policy.match_interface {
+        outcome = PolicyMatch::MatchSucceeded;
+        if !match (match_interface, &req.if_name) {
+            (Some(pattern), Some(name)) => config::interface_matches(pattern, name),
+            /* null only matches if we don't know what the interface is */
+            (None, None) => true,
+            _ => false,
+        } {
+            return PolicyMatch::MatchFailed;
+        }
+    }
     if let Some(match_chaddr) = &policy.match_chaddr {
         outcome = PolicyMatch::MatchSucceeded;
         if req.pkt.chaddr != *match_chaddr {
@@ -183,10 +219,25 @@ fn check_policy(req: &DHCPRequest, policy: &config::Policy) -> PolicyMatch {
     }
     if let Some(match_subnet) = &policy.match_subnet {
         outcome = PolicyMatch::MatchSucceeded;
-        if !match_subnet.contains(req.serverip) {
+        if !match_subnet.contains(req.get_network_addr()) {
             return PolicyMatch::MatchFailed;
         }
     }
+    if policy.match_circuit_id.is_some() || policy.match_remote_id.is_some() {
+        let relayinfo = req.pkt.options.get_relay_agent_info().unwrap_or_default();
+        if let Some(match_circuit_id) = &policy.match_circuit_id {
+            outcome = PolicyMatch::MatchSucceeded;
+            if relayinfo.circuit_id.as_ref() != Some(match_circuit_id) {
+                return PolicyMatch::MatchFailed;
+            }
+        }
+        if let Some(match_remote_id) = &policy.match_remote_id {
+            outcome = PolicyMatch::MatchSucceeded;
+            if relayinfo.remote_id.as_ref() != Some(match_remote_id) {
+                return PolicyMatch::MatchFailed;
+            }
+        }
+    }
 
     for (k, m) in policy.match_other.iter() {
         if match (m, req.pkt.options.other.get(k)) {
@@ -364,13 +415,25 @@ struct Response {
     maxlease: Option<std::time::Duration>,
 }
 
+#[cfg(test)]
 fn handle_discover(
     pools: &mut pool::Pool,
     req: &DHCPRequest,
-    _serverids: &ServerIds,
+    serverids: &ServerIds,
     base: &[config::Policy],
     conf: &super::config::Config,
 ) -> Result<dhcppkt::Dhcp, DhcpError> {
+    offer_address(pools, req, serverids, base, conf).map(|(offer, _)| offer)
+}
+
+/// Handles a DHCPDISCOVER, returning the DHCPOFFER along with how the offered address was chosen.
+fn offer_address(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    _serverids: &ServerIds,
+    base: &[config::Policy],
+    conf: &super::config::Config,
+) -> Result<(dhcppkt::Dhcp, pool::LeaseType), DhcpError> {
     /* Build the default response we are about to reply with, it will be filled in later */
     let mut response: Response = Response {
         options: ResponseOptions::default()
@@ -409,27 +472,30 @@ fn handle_discover(
                     lease.lease_type
                 );
 
-                Ok(dhcppkt::Dhcp {
-                    op: dhcppkt::OP_BOOTREPLY,
-                    htype: dhcppkt::HWTYPE_ETHERNET,
-                    hlen: 6,
-                    hops: 0,
-                    xid: req.pkt.xid,
-                    secs: 0,
-                    flags: req.pkt.flags,
-                    ciaddr: net::Ipv4Addr::UNSPECIFIED,
-                    yiaddr: lease.ip,
-                    siaddr: net::Ipv4Addr::UNSPECIFIED,
-                    giaddr: req.pkt.giaddr,
-                    chaddr: req.pkt.chaddr.clone(),
-                    sname: vec![],
-                    file: vec![],
-                    options: response
-                        .options
-                        .clone()
-                        .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip)
-                        .to_options(),
-                })
+                Ok((
+                    dhcppkt::Dhcp {
+                        op: dhcppkt::OP_BOOTREPLY,
+                        htype: dhcppkt::HWTYPE_ETHERNET,
+                        hlen: 6,
+                        hops: 0,
+                        xid: req.pkt.xid,
+                        secs: 0,
+                        flags: req.pkt.flags,
+                        ciaddr: net::Ipv4Addr::UNSPECIFIED,
+                        yiaddr: lease.ip,
+                        siaddr: net::Ipv4Addr::UNSPECIFIED,
+                        giaddr: req.pkt.giaddr,
+                        chaddr: req.pkt.chaddr.clone(),
+                        sname: vec![],
+                        file: vec![],
+                        options: response
+                            .options
+                            .clone()
+                            .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip)
+                            .to_options(),
+                    },
+                    lease.lease_type,
+                ))
             }
             /* Some error occurred, document it. */
             Err(e) => Err(DhcpError::PoolError(e)),
@@ -463,15 +529,30 @@ fn handle_request(
     if !base_policy && !conf_policy {
         Err(DhcpError::NoPolicyConfigured)
     } else if let Some(addresses) = response.address {
+        let requested = if !req.pkt.ciaddr.is_unspecified() {
+            Some(req.pkt.ciaddr)
+        } else {
+            req.pkt.options.get_address_request()
+        };
+        /* RFC2131 Section 4.3.2: If the DHCP server determines that the client's notion of its
+         * address is incorrect, or the client is on the wrong network, the server SHOULD respond
+         * with a DHCPNAK.  So only allow the pool to hand back the address the client asked for.
+         */
+        let addresses = match requested {
+            Some(addr) if addresses.contains(&addr) => [addr].iter().copied().collect(),
+            Some(addr) => {
+                return Ok(build_nak(
+                    req,
+                    &format!("{} is not a valid address on this network", addr),
+                ))
+            }
+            None => addresses,
+        };
         let mut raw_options = Vec::new();
         req.pkt.options.serialise(&mut raw_options);
         match pools.allocate_address(
             &req.pkt.get_client_id(),
-            if !req.pkt.ciaddr.is_unspecified() {
-                Some(req.pkt.ciaddr)
-            } else {
-                req.pkt.options.get_address_request()
-            },
+            requested,
             &addresses,
             response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
             response.maxlease.unwrap_or(pool::DEFAULT_MAX_LEASE),
@@ -513,6 +594,11 @@ fn handle_request(
                         .to_options(),
                 })
             }
+            /* The requested address has been given to someone else (or quarantined) */
+            Err(pool::Error::NoAssignableAddress) if requested.is_some() => Ok(build_nak(
+                req,
+                &format!("{} is not available", requested.unwrap()),
+            )),
             Err(e) => Err(DhcpError::PoolError(e)),
         }
     } else {
@@ -520,6 +606,171 @@ fn handle_request(
     }
 }
 
+/// Builds a DHCPNAK refusing a clients DHCPREQUEST, with a human readable reason.
+fn build_nak(req: &DHCPRequest, reason: &str) -> dhcppkt::Dhcp {
+    DHCP_NAKS.inc();
+    log::info!("{}: Refusing request: {}", format_client(&req.pkt), reason);
+    /* RFC2131 Section 4.1: If the 'giaddr' field in a DHCP message from a client is non-zero, the
+     * server sends any return messages to the 'DHCP server' port on the BOOTP relay agent whose
+     * address appears in 'giaddr'.  ... In all cases, when 'giaddr' is zero, the server broadcasts
+     * any DHCPNAK messages to 0xffffffff.
+     *
+     * RFC2131 Section 4.3.2: If 'giaddr' is set in the DHCPREQUEST message, the server MUST set
+     * the broadcast bit in the DHCPNAK, so that the relay agent will broadcast the DHCPNAK to the
+     * client, because the client may not have a correct network address or subnet mask.
+     */
+    let flags = if req.pkt.giaddr.is_unspecified() {
+        req.pkt.flags
+    } else {
+        req.pkt.flags | dhcppkt::FLAG_BROADCAST
+    };
+    /* RFC2131 Table 3: A DHCPNAK only carries the message type, server identifier, an optional
+     * message, and the client identifier; every other field is zero.
+     */
+    let mut options = dhcppkt::DhcpOptions::default()
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPNAK)
+        .set_option(
+            &dhcppkt::OPTION_SERVERID,
+            &req.pkt.options.get_serverid().unwrap_or(req.serverip),
+        )
+        .set_option(&dhcppkt::OPTION_MESSAGE, &reason.to_string());
+    if let Some(clientid) = req.pkt.options.get_raw_option(&dhcppkt::OPTION_CLIENTID) {
+        options = options.set_raw_option(&dhcppkt::OPTION_CLIENTID, clientid);
+    }
+    dhcppkt::Dhcp {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.pkt.xid,
+        secs: 0,
+        flags,
+        ciaddr: net::Ipv4Addr::UNSPECIFIED,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: net::Ipv4Addr::UNSPECIFIED,
+        giaddr: req.pkt.giaddr,
+        chaddr: req.pkt.chaddr.clone(),
+        sname: vec![],
+        file: vec![],
+        options,
+    }
+}
+
+fn handle_inform(
+    req: &DHCPRequest,
+    base: &[config::Policy],
+    conf: &super::config::Config,
+) -> Result<dhcppkt::Dhcp, DhcpError> {
+    /* RFC2131 Section 4.3.5: The client has already obtained its network address through some
+     * other means, which it MUST place in 'ciaddr'.
+     */
+    if req.pkt.ciaddr.is_unspecified() {
+        return Err(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket));
+    }
+    let mut response: Response = Response {
+        options: ResponseOptions::default()
+            .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
+            .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip),
+        ..Default::default()
+    };
+    let base_policy = apply_policies(req, base, &mut response);
+    let conf_policy = apply_policies(req, &conf.dhcp.policies, &mut response);
+    if !base_policy && !conf_policy {
+        return Err(DhcpError::NoPolicyConfigured);
+    }
+    /* RFC2131 Section 4.3.5: The server then sends a DHCPACK to the client... The server MUST NOT
+     * send a lease expiration time to the client and SHOULD NOT fill in 'yiaddr'.
+     *
+     * Any addresses from the policies are ignored, and the pool is never consulted, so there is no
+     * lease to describe.  Remove any lease timers a policy may have configured.
+     */
+    for option in [
+        dhcppkt::OPTION_LEASETIME,
+        dhcppkt::OPTION_RENEWALTIME,
+        dhcppkt::OPTION_REBINDTIME,
+    ] {
+        response.options.mutate_option::<u32>(&option, None);
+    }
+    Ok(dhcppkt::Dhcp {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.pkt.xid,
+        secs: 0,
+        flags: req.pkt.flags,
+        ciaddr: req.pkt.ciaddr,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: net::Ipv4Addr::UNSPECIFIED,
+        giaddr: req.pkt.giaddr,
+        chaddr: req.pkt.chaddr.clone(),
+        sname: vec![],
+        file: vec![],
+        options: response.options.to_options(),
+    })
+}
+
+fn handle_release(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    serverids: &ServerIds,
+) -> Result<(), DhcpError> {
+    if let Some(si) = req.pkt.options.get_serverid() {
+        if !serverids.contains(&si) {
+            return Err(DhcpError::OtherServer(si));
+        }
+    }
+    /* RFC2131 Section 4.4.6: The client identifies the lease to be released with its 'client
+     * identifier', or 'chaddr' and network address in the DHCPRELEASE message.
+     */
+    pools
+        .release_address(&req.pkt.get_client_id(), req.pkt.ciaddr)
+        .map_err(DhcpError::PoolError)?;
+    log::info!(
+        "{}: Released lease: {}",
+        format_client(&req.pkt),
+        req.pkt.ciaddr
+    );
+    Ok(())
+}
+
+fn handle_decline(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    serverids: &ServerIds,
+    conf: &super::config::Config,
+) -> Result<(), DhcpError> {
+    if let Some(si) = req.pkt.options.get_serverid() {
+        if !serverids.contains(&si) {
+            return Err(DhcpError::OtherServer(si));
+        }
+    }
+    /* RFC2131 Section 4.3.3: If the server receives a DHCPDECLINE message, the client has
+     * discovered through some other means that the suggested network address is already in use.
+     * The server MUST mark the network address as not available and SHOULD notify the local
+     * system administrator of a possible configuration problem.
+     */
+    let addr = req
+        .pkt
+        .options
+        .get_address_request()
+        .ok_or(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket))?;
+    let quarantine = conf
+        .dhcp
+        .decline_quarantine
+        .unwrap_or(pool::DEFAULT_DECLINE_QUARANTINE);
+    pools
+        .decline_address(&req.pkt.get_client_id(), addr, quarantine)
+        .map_err(DhcpError::PoolError)?;
+    log::warn!(
+        "{}: Declined {} as already in use, possible address conflict.  Not using it for {:?}",
+        format_client(&req.pkt),
+        addr,
+        quarantine
+    );
+    Ok(())
+}
+
 fn format_mac(v: &[u8]) -> String {
     v.iter()
         .map(|b| format!("{:0>2x}", b))
@@ -666,22 +917,27 @@ pub fn build_default_config(conf: &crate::config::Config, request: &DHCPRequest)
                 use crate::config::Match as _;
                 use crate::config::PrefixOps as _;
                 let subnet = erbium_net::Ipv4Subnet::new(p4.network(), p4.prefixlen).ok()?;
+                let base = u32::from(subnet.network());
+                let size = 1u64 << (32 - p4.prefixlen);
+                let mut addresses = pool::PoolAddresses::new();
+                if size > 3 {
+                    addresses.insert_range((base + 1).into(), (base + (size - 3) as u32).into());
+                }
+                // TODO: This removes one IP from the list, it should also remove any
+                // others found on the local machine.  Probably fine for now, but
+                // likely to cause confusion in the future.
+                addresses.remove(request.serverip);
+                // The relay agent is using its address on the client's network.
+                addresses.remove(request.pkt.giaddr);
                 let mut ret = config::Policy {
                     match_subnet: Some(subnet),
-                    apply_address: Some(
-                        (1..((1 << (32 - p4.prefixlen)) - 2))
-                            .map(|offset| (u32::from(subnet.network()) + offset).into())
-                            // TODO: This removes one IP from the list, it should also remove any
-                            // others found on the local machine.  Probably fine for now, but
-                            // likely to cause confusion in the future.
-                            .filter(|ip4| *ip4 != request.serverip)
-                            .collect::<pool::PoolAddresses>()
-                            .sub(&all_addrs),
-                    ),
+                    apply_address: Some(addresses.sub(&all_addrs)),
                     ..Default::default()
                 };
-                /* If this is the interface the request is coming in, then we can do extra stuff */
-                if p4.contains(request.serverip) {
+                /* If this is the interface the request is coming in, then we can do extra stuff.
+                 * Relayed clients are on some other network, so none of this applies to them.
+                 */
+                if request.pkt.giaddr.is_unspecified() && p4.contains(request.serverip) {
                     // Add the MTU
                     // TODO: Perhaps don't send it if it's default?
                     if let Some(mtu) = request.if_mtu {
@@ -707,26 +963,70 @@ pub fn build_default_config(conf: &crate::config::Config, request: &DHCPRequest)
     default_policy
 }
 
+/// Handles a DHCP packet, returning the reply to send (if any).
 pub fn handle_pkt(
     pools: &mut pool::Pool,
     request: &DHCPRequest,
     serverids: ServerIds,
     conf: &super::config::Config,
-) -> Result<dhcppkt::Dhcp, DhcpError> {
-    match request.pkt.options.get_messagetype() {
+) -> Result<Option<dhcppkt::Dhcp>, DhcpError> {
+    process_pkt(pools, request, serverids, conf).map(|(reply, _)| reply)
+}
+
+/// Like handle_pkt, but if an address was allocated for a DHCPOFFER, also returns how it was
+/// chosen, so the caller can check new addresses aren't already in use before sending the offer.
+fn process_pkt(
+    pools: &mut pool::Pool,
+    request: &DHCPRequest,
+    serverids: ServerIds,
+    conf: &super::config::Config,
+) -> Result<(Option<dhcppkt::Dhcp>, Option<pool::LeaseType>), DhcpError> {
+    let (reply, lease_type) = match request.pkt.options.get_messagetype() {
         Some(dhcppkt::DHCPDISCOVER) => {
             let base = [build_default_config(conf, request)];
-            handle_discover(pools, request, &serverids, &base, conf)
+            offer_address(pools, request, &serverids, &base, conf)
+                .map(|(offer, lease_type)| (Some(offer), Some(lease_type)))
         }
         Some(dhcppkt::DHCPREQUEST) => {
             let base = [build_default_config(conf, request)];
-            handle_request(pools, request, &serverids, &base, conf)
+            handle_request(pools, request, &serverids, &base, conf).map(|ack| (Some(ack), None))
+        }
+        Some(dhcppkt::DHCPINFORM) => {
+            let base = [build_default_config(conf, request)];
+            handle_inform(request, &base, conf).map(|ack| (Some(ack), None))
+        }
+        /* RELEASE and DECLINE are never replied to */
+        Some(dhcppkt::DHCPRELEASE) => {
+            handle_release(pools, request, &serverids).map(|()| (None, None))
+        }
+        Some(dhcppkt::DHCPDECLINE) => {
+            handle_decline(pools, request, &serverids, conf).map(|()| (None, None))
         }
         Some(x) => Err(DhcpError::UnknownMessageType(x)),
         None => Err(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket)),
-    }
+    }?;
+    /* RFC3046 Section 2.2: DHCP servers claiming to support the Relay Agent Information option
+     * SHALL echo the entire contents of the Relay Agent Information option in all replies.
+     */
+    let reply = reply.map(|mut reply| {
+        if let Some(relayinfo) = request
+            .pkt
+            .options
+            .get_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO)
+        {
+            reply.options = reply
+                .options
+                .set_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO, relayinfo);
+        }
+        reply
+    });
+    Ok((reply, lease_type))
 }
 
+/// How many addresses to try offering to a client before giving up, when they keep turning out to be
+/// in use.
+const MAX_PROBE_ATTEMPTS: u32 = 3;
+
 async fn send_raw(raw: Arc<raw::RawSocket>, buf: &[u8], intf: i32) -> Result<(), std::io::Error> {
     DHCP_TX_PACKETS.inc();
     raw.send_msg(
@@ -780,6 +1080,31 @@ pub struct DhcpService {
 }
 
 impl DhcpService {
+    /// Checks if something is already using addr, with an ARP probe for clients on the local
+    /// segment, or an ICMP echo request for relayed clients.
+    async fn probe_address(
+        &self,
+        request: &DHCPRequest,
+        addr: net::Ipv4Addr,
+    ) -> Option<probe::Conflict> {
+        let result = if request.pkt.giaddr.is_unspecified() {
+            match self.netinfo.get_linkaddr_by_ifidx(request.ifindex).await {
+                Some(erbium_net::netinfo::LinkLayer::Ethernet(srcmac)) => {
+                    probe::probe_arp(request.ifindex, &srcmac, addr, probe::PROBE_TIMEOUT).await
+                }
+                /* Not an ethernet link, so we can't ARP, try ICMP instead */
+                _ => probe::probe_icmp(addr, probe::PROBE_TIMEOUT).await,
+            }
+        } else {
+            probe::probe_icmp(addr, probe::PROBE_TIMEOUT).await
+        };
+        result.unwrap_or_else(|e| {
+            /* If we can't probe, it's better to hand out the address than to not answer at all */
+            log::warn!("Failed to probe {}: {}", addr, e);
+            None
+        })
+    }
+
     async fn recvdhcp(&self, pkt: &[u8], src: NetAddr, intf: u32) {
         let raw = self.rawsock.clone();
         /* First, lets find the various metadata IP addresses */
@@ -807,6 +1132,7 @@ impl DhcpService {
         };
 
         /* Log what we've got */
+        let if_name = self.netinfo.get_name_by_ifidx(intf).await;
         let if_mtu = self.netinfo.get_mtu_by_ifidx(intf).await;
         let if_router = match self.netinfo.get_ipv4_default_route().await {
             /* If the default route points out a different interface, then this is the default route */
@@ -820,42 +1146,97 @@ impl DhcpService {
             pkt: req,
             serverip: optional_dst.unwrap(),
             ifindex: intf,
+            if_name,
             if_mtu,
             if_router,
         };
         log_pkt(&request, &self.netinfo).await;
 
         /* Now, lets process the packet we've found */
-        let reply;
-        {
-            /* Limit the amount of time we have these locked to just handling the packet */
-            let mut pool = self.pool.lock().await;
-            let lockedconf = self.conf.read().await;
-
-            reply = match handle_pkt(
-                &mut pool,
-                &request,
-                get_serverids(&self.serverids).await,
-                &lockedconf,
-            ) {
-                Err(e) => {
-                    log::warn!(
-                        "{}: Failed to handle {}: {}",
-                        format_client(&request.pkt),
-                        request
-                            .pkt
-                            .options
-                            .get_messagetype()
-                            .map(|x| x.to_string())
-                            .unwrap_or_else(|| "packet".into()),
-                        e
-                    );
-                    DHCP_ERRORS.with_label_values(&[e.get_variant_name()]).inc();
-                    return;
-                }
-                Ok(r) => r,
+        let mut attempts = 0;
+        let reply = loop {
+            let (reply, lease_type, conflict_detection, quarantine);
+            {
+                /* Limit the amount of time we have these locked to just handling the packet */
+                let mut pool = self.pool.lock().await;
+                let lockedconf = self.conf.read().await;
+
+                (reply, lease_type) = match process_pkt(
+                    &mut pool,
+                    &request,
+                    get_serverids(&self.serverids).await,
+                    &lockedconf,
+                ) {
+                    Err(e) => {
+                        log::warn!(
+                            "{}: Failed to handle {}: {}",
+                            format_client(&request.pkt),
+                            request
+                                .pkt
+                                .options
+                                .get_messagetype()
+                                .map(|x| x.to_string())
+                                .unwrap_or_else(|| "packet".into()),
+                            e
+                        );
+                        DHCP_ERRORS.with_label_values(&[e.get_variant_name()]).inc();
+                        return;
+                    }
+                    Ok((None, _)) => return,
+                    Ok((Some(r), lease_type)) => (r, lease_type),
+                };
+                conflict_detection = lockedconf.dhcp.conflict_detection;
+                quarantine = lockedconf
+                    .dhcp
+                    .decline_quarantine
+                    .unwrap_or(pool::DEFAULT_DECLINE_QUARANTINE);
+            }
+
+            /* RFC2131 Section 4.4.1: The server SHOULD probe the address before offering it, so
+             * check addresses that have never been handed out to this client before aren't
+             * already in use.  The pool is not locked while we wait for an answer.
+             */
+            if !conflict_detection || lease_type != Some(pool::LeaseType::NewAddress) {
+                break reply;
+            }
+            let conflict = match self.probe_address(&request, reply.yiaddr).await {
+                None => break reply,
+                Some(conflict) => conflict,
             };
-        }
+            DHCP_CONFLICTS.inc();
+            log::warn!(
+                "{}: {} is already in use ({}), possible address conflict.  Not using it for {:?}",
+                format_client(&request.pkt),
+                reply.yiaddr,
+                conflict,
+                quarantine
+            );
+            if let Err(e) = self
+                .pool
+                .lock()
+                .await
+                .quarantine_address(reply.yiaddr, quarantine)
+            {
+                log::warn!(
+                    "{}: Failed to mark {} as in use: {}",
+                    format_client(&request.pkt),
+                    reply.yiaddr,
+                    e
+                );
+                DHCP_ERRORS.with_label_values(&["POOL_ERROR"]).inc();
+                return;
+            }
+            attempts += 1;
+            if attempts >= MAX_PROBE_ATTEMPTS {
+                log::warn!(
+                    "{}: Giving up after finding {} addresses in use",
+                    format_client(&request.pkt),
+                    attempts
+                );
+                DHCP_ERRORS.with_label_values(&["ADDRESS_CONFLICT"]).inc();
+                return;
+            }
+        };
 
         /* Now, we should have a packet ready to send */
         /* First, if we're claiming to be particular IP, we should remember that as an IP that is one
@@ -886,6 +1267,35 @@ impl DhcpService {
         );
         log_options(&reply);
 
+        let replybuf = reply.serialise();
+
+        /* RFC2131 Section 4.1: If the 'giaddr' field in a DHCP message from a client is non-zero,
+         * the server sends any return messages to the 'DHCP server' port on the BOOTP relay agent
+         * whose address appears in 'giaddr'.
+         */
+        if !reply.giaddr.is_unspecified() {
+            DHCP_TX_PACKETS.inc();
+            if let Err(e) = self
+                .listener
+                .send_msg(
+                    &replybuf,
+                    &udp::ControlMessage::new().set_send_from(Some(request.serverip.into())),
+                    udp::MsgFlags::empty(),
+                    Some(&reply.giaddr.with_port(67)),
+                )
+                .await
+            {
+                log::warn!(
+                    "{}: Failed to send reply to relay {}: {:?}",
+                    format_client(&reply),
+                    reply.giaddr,
+                    e
+                );
+                DHCP_ERRORS.with_label_values(&["SEND_ERROR"]).inc();
+            }
+            return;
+        }
+
         /* Collect metadata ready to send */
         let srcll = if let Some(erbium_net::netinfo::LinkLayer::Ethernet(srcll)) =
             self.netinfo.get_linkaddr_by_ifidx(intf).await
@@ -909,13 +1319,37 @@ impl DhcpService {
             return;
         };
 
+        let is_nak = reply.options.get_messagetype() == Some(dhcppkt::DHCPNAK);
+        let (dst, dstll) = if is_nak {
+            /* RFC2131 Section 4.1: when 'giaddr' is zero, the server broadcasts any DHCPNAK
+             * messages to 0xffffffff.
+             */
+            (
+                *net::Ipv4Addr::BROADCAST
+                    .with_port(68)
+                    .as_sockaddr_in()
+                    .unwrap(),
+                [0xff; 6],
+            )
+        } else if request.pkt.options.get_messagetype() == Some(dhcppkt::DHCPINFORM) {
+            /* RFC2131 Section 4.3.5: The server responds to a DHCPINFORM message by sending a
+             * DHCPACK message directly to the address given in the 'ciaddr' field of the
+             * DHCPINFORM message.
+             */
+            (
+                *reply.ciaddr.with_port(68).as_sockaddr_in().unwrap(),
+                chaddr,
+            )
+        } else {
+            (*ip4, chaddr)
+        };
+
         /* Construct the raw packet from the reply to send */
-        let replybuf = reply.serialise();
         let etherbuf = packet::Fragment::new_udp4(
             *request.serverip.with_port(67).as_sockaddr_in().unwrap(),
             &srcll,
-            *ip4,
-            &chaddr,
+            dst,
+            &dstll,
             packet::Tail::Payload(&replybuf),
         )
         .flatten();
@@ -1040,6 +1474,58 @@ fn test_policy() {
     assert!(apply_policies(&req, policies.as_slice(), &mut resp));
 }
 
+#[test]
+fn test_relayed_policy() {
+    let cfg = config::Policy {
+        match_subnet: Some(erbium_net::Ipv4Subnet::new("192.0.2.0".parse().unwrap(), 24).unwrap()),
+        ..Default::default()
+    };
+    let mut req = DHCPRequest {
+        serverip: "198.51.100.67".parse().unwrap(),
+        ..Default::default()
+    };
+    req.pkt.giaddr = "192.0.2.1".parse().unwrap();
+    let mut resp = Default::default();
+    let policies = vec![cfg];
+
+    /* The relay's address is used to select the network, not the interface it arrived on */
+    assert!(apply_policies(&req, policies.as_slice(), &mut resp));
+    req.pkt.giaddr = net::Ipv4Addr::UNSPECIFIED;
+    assert!(!apply_policies(&req, policies.as_slice(), &mut resp));
+}
+
+#[test]
+fn test_interface_glob() {
+    assert!(config::interface_matches("eth0", "eth0"));
+    assert!(!config::interface_matches("eth0", "eth01"));
+    assert!(config::interface_matches("vlan*", "vlan10"));
+    assert!(config::interface_matches("vlan*", "vlan"));
+    assert!(!config::interface_matches("vlan*", "eth0"));
+    assert!(config::interface_matches("eth?", "eth1"));
+    assert!(!config::interface_matches("eth?", "eth10"));
+    assert!(config::interface_matches("*.10", "eth0.10"));
+}
+
+#[test]
+fn test_interface_policy() {
+    let cfg = config::Policy {
+        match_interface: Some(Some("vlan*".into())),
+        ..Default::default()
+    };
+    let mut req = DHCPRequest {
+        if_name: Some("vlan10".into()),
+        ..Default::default()
+    };
+    let mut resp = Default::default();
+    let policies = vec![cfg];
+
+    assert!(apply_policies(&req, policies.as_slice(), &mut resp));
+    req.if_name = Some("eth0".into());
+    assert!(!apply_policies(&req, policies.as_slice(), &mut resp));
+    req.if_name = None;
+    assert!(!apply_policies(&req, policies.as_slice(), &mut resp));
+}
+
 #[tokio::test]
 async fn test_config_parse() -> Result<(), Box<dyn std::error::Error>> {
     let cfg = crate::config::load_config_from_string_for_test(
@@ -1107,6 +1593,7 @@ dhcp-policies:
             },
             serverip: "192.168.0.67".parse().unwrap(),
             ifindex: 1,
+            if_name: None,
             if_mtu: None,
             if_router: None,
         },
@@ -1236,6 +1723,7 @@ async fn test_base() {
                 apply_address: Some(apply_address),
                 ..Default::default()
             }],
+            ..Default::default()
         },
         ..Default::default()
     };
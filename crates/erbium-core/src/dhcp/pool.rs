@@ -24,10 +24,171 @@ use std::hash::Hasher;
 
 pub const DEFAULT_MIN_LEASE: std::time::Duration = std::time::Duration::from_secs(300);
 pub const DEFAULT_MAX_LEASE: std::time::Duration = std::time::Duration::from_secs(86400);
+pub const DEFAULT_DECLINE_QUARANTINE: std::time::Duration = std::time::Duration::from_secs(86400);
+
+/// A set of IPv4 addresses that can be handed out to clients.
+///
+/// Addresses are stored as a sorted list of disjoint, inclusive ranges, so that even very large
+/// subnets are cheap to build, copy, and subtract reserved addresses from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolAddresses {
+    /* Sorted, non-overlapping, non-adjacent (start, end) pairs. */
+    ranges: Vec<(u32, u32)>,
+}
 
-pub type PoolAddresses = std::collections::HashSet<std::net::Ipv4Addr>;
+impl PoolAddresses {
+    pub fn new() -> Self {
+        Default::default()
+    }
 
-#[derive(Debug)]
+    fn add_range(&mut self, start: u32, end: u32) {
+        if start > end {
+            return;
+        }
+        /* Find all the ranges that overlap or are adjacent to this one, and merge them. */
+        let lo = self
+            .ranges
+            .partition_point(|&(_, e)| e.saturating_add(1) < start);
+        let hi = self
+            .ranges
+            .partition_point(|&(s, _)| s <= end.saturating_add(1));
+        if lo < hi {
+            let merged = (
+                std::cmp::min(start, self.ranges[lo].0),
+                std::cmp::max(end, self.ranges[hi - 1].1),
+            );
+            self.ranges.splice(lo..hi, [merged]);
+        } else {
+            self.ranges.insert(lo, (start, end));
+        }
+    }
+
+    fn remove_range(&mut self, start: u32, end: u32) {
+        if start > end {
+            return;
+        }
+        let lo = self.ranges.partition_point(|&(_, e)| e < start);
+        let hi = self.ranges.partition_point(|&(s, _)| s <= end);
+        if lo >= hi {
+            return;
+        }
+        /* Keep whatever is left over at either end of the removed range. */
+        let mut remaining = Vec::with_capacity(2);
+        if self.ranges[lo].0 < start {
+            remaining.push((self.ranges[lo].0, start - 1));
+        }
+        if self.ranges[hi - 1].1 > end {
+            remaining.push((end + 1, self.ranges[hi - 1].1));
+        }
+        self.ranges.splice(lo..hi, remaining);
+    }
+
+    pub fn insert(&mut self, addr: std::net::Ipv4Addr) {
+        self.add_range(addr.into(), addr.into());
+    }
+
+    /// Adds all the addresses from start to end (inclusive).
+    pub fn insert_range(&mut self, start: std::net::Ipv4Addr, end: std::net::Ipv4Addr) {
+        self.add_range(start.into(), end.into());
+    }
+
+    pub fn remove(&mut self, addr: std::net::Ipv4Addr) {
+        self.remove_range(addr.into(), addr.into());
+    }
+
+    pub fn contains(&self, addr: &std::net::Ipv4Addr) -> bool {
+        let addr = u32::from(*addr);
+        let idx = self.ranges.partition_point(|&(_, e)| e < addr);
+        self.ranges
+            .get(idx)
+            .map(|&(s, _)| s <= addr)
+            .unwrap_or(false)
+    }
+
+    /// The number of addresses in the pool.
+    pub fn len(&self) -> u64 {
+        self.ranges.iter().map(|&(s, e)| u64::from(e - s) + 1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = std::net::Ipv4Addr> + '_ {
+        self.iter_from(0)
+    }
+
+    /// Iterates over every address in the pool, starting with the n'th address and wrapping around
+    /// to the start of the pool.
+    pub fn iter_from(&self, n: u64) -> impl Iterator<Item = std::net::Ipv4Addr> + '_ {
+        let mut idx = 0;
+        let mut skip = if self.is_empty() { 0 } else { n % self.len() };
+        while let Some(&(s, e)) = self.ranges.get(idx) {
+            let len = u64::from(e - s) + 1;
+            if skip < len {
+                break;
+            }
+            skip -= len;
+            idx += 1;
+        }
+        /* skip is now the offset into the range at idx, which is less than 2**32. */
+        let split = self.ranges.get(idx).map(|&(s, e)| (s, s + skip as u32, e));
+        split
+            .into_iter()
+            .map(|(_, mid, e)| (mid, e))
+            .chain(self.ranges.iter().skip(idx + 1).copied())
+            .chain(self.ranges.iter().take(idx).copied())
+            .chain(
+                split
+                    .into_iter()
+                    .filter(|&(s, mid, _)| s < mid)
+                    .map(|(s, mid, _)| (s, mid - 1)),
+            )
+            .flat_map(|(s, e)| (s..=e).map(std::net::Ipv4Addr::from))
+    }
+}
+
+impl std::ops::Sub<&PoolAddresses> for &PoolAddresses {
+    type Output = PoolAddresses;
+
+    fn sub(self, rhs: &PoolAddresses) -> PoolAddresses {
+        let mut ret = self.clone();
+        for &(s, e) in &rhs.ranges {
+            ret.remove_range(s, e);
+        }
+        ret
+    }
+}
+
+impl std::ops::BitOr<&PoolAddresses> for &PoolAddresses {
+    type Output = PoolAddresses;
+
+    fn bitor(self, rhs: &PoolAddresses) -> PoolAddresses {
+        let mut ret = self.clone();
+        for &(s, e) in &rhs.ranges {
+            ret.add_range(s, e);
+        }
+        ret
+    }
+}
+
+impl Extend<std::net::Ipv4Addr> for PoolAddresses {
+    fn extend<I: IntoIterator<Item = std::net::Ipv4Addr>>(&mut self, iter: I) {
+        for addr in iter {
+            self.insert(addr);
+        }
+    }
+}
+
+impl FromIterator<std::net::Ipv4Addr> for PoolAddresses {
+    fn from_iter<I: IntoIterator<Item = std::net::Ipv4Addr>>(iter: I) -> Self {
+        let mut ret = Self::new();
+        ret.extend(iter);
+        ret
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LeaseType {
     NewAddress,
     ReusingLease,
@@ -61,6 +222,7 @@ pub enum Error {
     CorruptDatabase(String),
     NoAssignableAddress,
     RequestedAddressInUse,
+    LeaseNotFound,
 }
 
 impl std::fmt::Display for Error {
@@ -70,6 +232,7 @@ impl std::fmt::Display for Error {
             Error::CorruptDatabase(s) => write!(f, "Corrupt Database: {}", s),
             Error::NoAssignableAddress => write!(f, "No Assignable Address"),
             Error::RequestedAddressInUse => write!(f, "Requested address is in use"),
+            Error::LeaseNotFound => write!(f, "No matching lease found"),
         }
     }
 }
@@ -243,7 +406,8 @@ impl Pool {
                         .get::<_, String>(0)?
                         .parse::<std::net::Ipv4Addr>()
                         .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
-                    client_id: row.get(1)?,
+                    /* Quarantined addresses have no client */
+                    client_id: row.get::<usize, Option<Vec<u8>>>(1)?.unwrap_or_default(),
                     start: row.get(2)?,
                     expire: row.get(3)?,
                     options: row.get::<usize, Option<Vec<u8>>>(4)?.unwrap_or_default(),
@@ -293,45 +457,38 @@ impl Pool {
         addresses: &PoolAddresses,
         clientid: &[u8],
     ) -> Result<Lease, Error> {
-        /* This performs a consistent hash of the clientid and the IP addresses
-         * then orders by the distance from the hash of the clientid
+        /* Collect the addresses that are currently in use.  This is bounded by the number of
+         * leases, rather than the size of the pool.
+         */
+        let in_use = self
+            .conn
+            .prepare_cached(
+                "SELECT
+                  address
+                 FROM
+                  leases
+                 WHERE expiry >= ?1",
+            )
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .query_map(rusqlite::params![ts], |row| row.get::<_, String>(0))
+            .map_err(|e| Error::DbError(e.to_string()))?
+            .filter_map(|addr| addr.ok()?.parse::<std::net::Ipv4Addr>().ok())
+            .collect::<std::collections::HashSet<_>>();
+
+        /* Start at an offset into the pool based on a hash of the clientid, so clients tend to
+         * get the same address each time, then take the first address that isn't in use.  At most
+         * in_use.len() + 1 addresses are considered.
          */
         let clienthash = calculate_hash(&0, &clientid);
-        let mut addresses = addresses
-            .iter()
-            .map(|ip| (calculate_hash(&clienthash, ip), ip))
-            .collect::<Vec<_>>();
-        addresses.sort_unstable();
-        let addresses = addresses
-            .iter()
-            .map(|(_dist, ip)| ip)
-            .copied()
-            .collect::<Vec<_>>();
-        /* Now for each address, see if it's in use, and if so, return it */
-        for i in addresses {
-            if self
-                .conn
-                .query_row(
-                    "SELECT
-                      true
-                     FROM
-                      leases
-                     WHERE expiry >= ?1
-                     AND address = ?2",
-                    rusqlite::params![ts, i.to_string()],
-                    |_row| Ok(Some(())),
-                )
-                .or_else(map_no_row_to_none)?
-                .is_none()
-            {
-                return Ok(Lease {
-                    ip: *i,
-                    expire: std::time::Duration::from_secs(0), /* We rely on the min_lease_time below */
-                    lease_type: LeaseType::NewAddress,
-                });
-            }
-        }
-        Err(Error::NoAssignableAddress)
+        addresses
+            .iter_from(clienthash)
+            .find(|ip| !in_use.contains(ip))
+            .map(|ip| Lease {
+                ip,
+                expire: std::time::Duration::from_secs(0), /* We rely on the min_lease_time below */
+                lease_type: LeaseType::NewAddress,
+            })
+            .ok_or(Error::NoAssignableAddress)
     }
 
     fn select_address(
@@ -510,6 +667,105 @@ impl Pool {
         Ok(lease)
     }
 
+    /// Marks a clients lease as expired (eg on a DHCPRELEASE), so that the address can be handed
+    /// out to other clients.  The lease is kept, so if the client comes back it can be given the
+    /// same address again if it is still free.
+    pub fn release_address(
+        &mut self,
+        clientid: &[u8],
+        addr: std::net::Ipv4Addr,
+    ) -> Result<(), Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs() as u32;
+
+        /* Addresses are considered in use while expiry >= now, so expire it in the past.  The
+         * lease must not end before it started.
+         */
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE leases
+                 SET start = MIN(start, ?3), expiry = ?3
+                 WHERE address = ?1
+                 AND clientid = ?2
+                 AND expiry >= ?4",
+                rusqlite::params![addr.to_string(), clientid, ts.saturating_sub(1), ts],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to release lease: {}", e)))?;
+
+        if updated == 0 {
+            Err(Error::LeaseNotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Prevents an address from being allocated to any client for the duration given.
+    /// The address is recorded as a lease with no client.
+    pub fn quarantine_address(
+        &mut self,
+        addr: std::net::Ipv4Addr,
+        duration: std::time::Duration,
+    ) -> Result<(), Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs();
+
+        self.conn
+            .execute(
+                "INSERT OR REPLACE
+                 INTO leases (address, clientid, start, expiry)
+                 VALUES (?1, NULL, ?2, ?3)",
+                rusqlite::params![
+                    addr.to_string(),
+                    ts as u32,
+                    (ts + duration.as_secs()) as u32,
+                ],
+            )
+            .map_err(|e| Error::DbError(format!("Failed to quarantine address: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Handles a client telling us that an address is already in use on the network (eg on a
+    /// DHCPDECLINE).  A client cannot decline an address that is currently leased to a different
+    /// client.
+    pub fn decline_address(
+        &mut self,
+        clientid: &[u8],
+        addr: std::net::Ipv4Addr,
+        quarantine: std::time::Duration,
+    ) -> Result<(), Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs() as u32;
+
+        if let Some(Some(owner)) = self
+            .conn
+            .query_row(
+                "SELECT
+                  clientid
+                 FROM
+                  leases
+                 WHERE expiry >= ?1
+                 AND address = ?2",
+                rusqlite::params![ts, addr.to_string()],
+                |row| Ok(Some(row.get::<usize, Option<Vec<u8>>>(0)?)),
+            )
+            .or_else(map_no_row_to_none)?
+        {
+            if owner != clientid {
+                return Err(Error::RequestedAddressInUse);
+            }
+        }
+
+        self.quarantine_address(addr, quarantine)
+    }
+
     #[cfg(test)]
     fn reserve_address_internal(
         &mut self,
@@ -763,3 +1019,145 @@ fn dont_hand_out_old_stale_lease() {
     /* Do not assigned the old_reserved address! */
     assert_ne!(lease.ip, old_reserved);
 }
+
+#[test]
+fn release_lease() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert("192.168.0.100".parse().unwrap());
+    let lease = p
+        .allocate_address(
+            b"client",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+
+    /* Another client can't release our lease */
+    assert_eq!(
+        p.release_address(b"other-client", lease.ip),
+        Err(Error::LeaseNotFound)
+    );
+    p.release_address(b"client", lease.ip)
+        .expect("Failed to release lease");
+
+    /* Now the address is free, another client should be able to get it */
+    let other = p
+        .allocate_address(
+            b"other-client",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate released address");
+    assert_eq!(other.ip, lease.ip);
+
+    /* And releasing an already released lease fails */
+    assert_eq!(
+        p.release_address(b"client", lease.ip),
+        Err(Error::LeaseNotFound)
+    );
+}
+
+#[test]
+fn decline_quarantines_address() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let declined = "192.168.0.100".parse().unwrap();
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert(declined);
+    addrpool.insert("192.168.0.101".parse().unwrap());
+    p.reserve_address(b"client", declined);
+
+    /* Clients can't decline addresses leased to other clients */
+    assert_eq!(
+        p.decline_address(b"other-client", declined, DEFAULT_DECLINE_QUARANTINE),
+        Err(Error::RequestedAddressInUse)
+    );
+
+    p.decline_address(b"client", declined, DEFAULT_DECLINE_QUARANTINE)
+        .expect("Failed to decline address");
+
+    /* Neither the declining client, nor any other client should get the declined address */
+    for client in [&b"client"[..], &b"other-client"[..]] {
+        let lease = p
+            .select_address(client, Some(declined), &addrpool)
+            .expect("Failed to allocate address");
+        assert_ne!(lease.ip, declined);
+    }
+
+    /* The quarantined address still shows up in the list of leases, without a client */
+    let leases = p.get_leases().expect("error calling get_leases()");
+    assert!(leases
+        .iter()
+        .any(|l| l.ip == declined && l.client_id.is_empty()));
+}
+
+#[test]
+fn pool_addresses_ranges() {
+    let ip = |s: &str| s.parse::<std::net::Ipv4Addr>().unwrap();
+    let mut addrs = PoolAddresses::new();
+    addrs.insert_range(ip("192.0.2.10"), ip("192.0.2.19"));
+    addrs.insert_range(ip("192.0.2.30"), ip("192.0.2.39"));
+    /* Adjacent and overlapping ranges are merged */
+    addrs.insert_range(ip("192.0.2.20"), ip("192.0.2.32"));
+    addrs.insert(ip("192.0.2.40"));
+    assert_eq!(addrs.ranges.len(), 1);
+    assert_eq!(addrs.len(), 31);
+
+    let mut reserved = PoolAddresses::new();
+    reserved.insert(ip("192.0.2.10"));
+    reserved.insert_range(ip("192.0.2.15"), ip("192.0.2.16"));
+    reserved.insert(ip("192.0.2.99"));
+    let addrs = &addrs - &reserved;
+    assert_eq!(addrs.len(), 28);
+    assert!(!addrs.contains(&ip("192.0.2.10")));
+    assert!(addrs.contains(&ip("192.0.2.11")));
+    assert!(!addrs.contains(&ip("192.0.2.16")));
+    assert!(addrs.contains(&ip("192.0.2.40")));
+    assert!(!addrs.contains(&ip("192.0.2.41")));
+
+    /* Iterating from an offset wraps around and visits every address exactly once */
+    let all = addrs.iter().collect::<Vec<_>>();
+    let mut wrapped = addrs.iter_from(5).collect::<Vec<_>>();
+    assert_eq!(wrapped[0], all[5]);
+    wrapped.sort();
+    assert_eq!(wrapped, all);
+}
+
+#[test]
+fn allocate_from_large_pool() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let mut addrpool = PoolAddresses::new();
+    addrpool.insert_range(
+        "10.0.0.1".parse().unwrap(),
+        "10.255.255.254".parse().unwrap(),
+    );
+    let first = p
+        .allocate_address(
+            b"client",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+    let second = p
+        .allocate_address(
+            b"other-client",
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+            b"",
+        )
+        .expect("Failed to allocate address");
+    assert!(addrpool.contains(&first.ip));
+    assert!(addrpool.contains(&second.ip));
+    assert_ne!(first.ip, second.ip);
+}
@@ -0,0 +1,159 @@
+/*   Copyright 2024 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Address conflict detection.
+ *
+ *  Before offering an address that has never been handed out to a client, we check that nothing
+ *  else is already using it (eg a device with a forgotten static IP).  On the local segment we
+ *  send an ARP probe (RFC5227), for relayed subnets the best we can do is an ICMP echo request
+ *  (RFC2131 Section 3.1).
+ */
+
+use erbium_net::addr::{ToNetAddr as _, WithPort as _};
+use erbium_net::packet;
+use erbium_net::raw;
+use rand::Rng as _;
+
+/// How long to wait for something to answer a probe.
+pub const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// Something answered an ARP probe, with this hardware address.
+    Arp([u8; 6]),
+    /// Something answered an ICMP echo request.
+    Icmp,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Conflict::Arp(mac) => write!(f, "answered ARP from {}", super::format_mac(mac)),
+            Conflict::Icmp => write!(f, "answered ICMP echo request"),
+        }
+    }
+}
+
+/// If this ethernet frame is an ARP packet sent from addr, returns the sender's hardware address.
+fn parse_arp(frame: &[u8], addr: std::net::Ipv4Addr) -> Option<[u8; 6]> {
+    /* Ethernet header (14 bytes) followed by a 28 byte IPv4 over Ethernet ARP packet */
+    let arp = frame.get(14..42)?;
+    if frame[12..14] != [0x08, 0x06] || arp[0..6] != [0x00, 0x01, 0x08, 0x00, 6, 4] {
+        return None;
+    }
+    /* Both replies and requests count, if something is claiming the address it's in use */
+    if arp[14..18] != addr.octets() {
+        return None;
+    }
+    arp[8..14].try_into().ok()
+}
+
+/// Is this IPv4 packet an ICMP echo reply from addr to our echo request?
+fn parse_icmp(pkt: &[u8], addr: std::net::Ipv4Addr, ident: u16) -> bool {
+    let ihl = (*pkt.first().unwrap_or(&0) & 0x0f) as usize * 4;
+    match (pkt.get(12..16), pkt.get(ihl..ihl + 8)) {
+        (Some(src), Some(icmp)) => {
+            src == addr.octets() && icmp[0] == 0 /* Echo Reply */ && icmp[4..6] == ident.to_be_bytes()
+        }
+        _ => false,
+    }
+}
+
+/// Sends an ARP probe for addr out of ifindex, and waits to see if anything answers.
+pub async fn probe_arp(
+    ifindex: u32,
+    srcmac: &[u8; 6],
+    addr: std::net::Ipv4Addr,
+    timeout: std::time::Duration,
+) -> Result<Option<Conflict>, std::io::Error> {
+    let sock = raw::RawSocket::new(raw::EthProto::ARP)?;
+    sock.send_msg(
+        &packet::Fragment::new_arp_probe(srcmac, &addr).flatten(),
+        &raw::ControlMessage::new(),
+        raw::MsgFlags::empty(),
+        Some(&erbium_net::addr::linkaddr_for_ifindex(ifindex as usize).to_net_addr()),
+    )
+    .await?;
+    let answer = async {
+        loop {
+            let msg = sock.recv_msg(1500, raw::MsgFlags::empty()).await?;
+            if let Some(mac) = parse_arp(&msg.buffer, addr) {
+                return Ok(Conflict::Arp(mac));
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, answer).await {
+        Ok(result) => result.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Sends an ICMP echo request to addr, and waits to see if anything answers.
+pub async fn probe_icmp(
+    addr: std::net::Ipv4Addr,
+    timeout: std::time::Duration,
+) -> Result<Option<Conflict>, std::io::Error> {
+    let sock = raw::Raw4Socket::new(raw::IpProto::ICMP)?;
+    let ident: u16 = rand::thread_rng().gen();
+    sock.send_msg(
+        &packet::Fragment::new_icmp4_echo(ident, 1, packet::Tail::Payload(b"erbium")).flatten(),
+        &raw::ControlMessage::new(),
+        raw::MsgFlags::empty(),
+        Some(&addr.with_port(0)),
+    )
+    .await?;
+    let answer = async {
+        loop {
+            let msg = sock.recv_msg(1500, raw::MsgFlags::empty()).await?;
+            if parse_icmp(&msg.buffer, addr, ident) {
+                return Ok(Conflict::Icmp);
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, answer).await {
+        Ok(result) => result.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+#[test]
+fn test_parse_arp() {
+    let addr = "192.0.2.1".parse().unwrap();
+    let mut reply = vec![0xff; 6];
+    reply.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]); /* Ethernet source */
+    reply.extend_from_slice(&[0x08, 0x06]);
+    reply.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x02]);
+    reply.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01, 192, 0, 2, 1]); /* Sender */
+    reply.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02, 0, 0, 0, 0]); /* Target */
+    assert_eq!(parse_arp(&reply, addr), Some([0x02, 0, 0, 0, 0, 0x01]));
+    assert_eq!(parse_arp(&reply, "192.0.2.2".parse().unwrap()), None);
+    /* Our own probe has a sender address of 0.0.0.0, so it's never a conflict */
+    let probe = packet::Fragment::new_arp_probe(&[0x02, 0, 0, 0, 0, 0x02], &addr).flatten();
+    assert_eq!(parse_arp(&probe, addr), None);
+    assert_eq!(parse_arp(&reply[..20], addr), None);
+}
+
+#[test]
+fn test_parse_icmp() {
+    let addr = "192.0.2.1".parse().unwrap();
+    let mut reply = vec![0x45, 0, 0, 36, 0, 0, 0, 0, 64, 1, 0, 0];
+    reply.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2]);
+    reply.extend_from_slice(&[0, 0, 0, 0, 0x12, 0x34, 0, 1]);
+    assert!(parse_icmp(&reply, addr, 0x1234));
+    assert!(!parse_icmp(&reply, addr, 0x4321));
+    assert!(!parse_icmp(&reply, "192.0.2.2".parse().unwrap(), 0x1234));
+    assert!(!parse_icmp(&reply[..24], addr, 0x1234));
+}
@@ -70,6 +70,7 @@ pub fn mk_dhcp_request() -> dhcp::DHCPRequest {
         pkt: mk_dhcp_request_pkt(),
         serverip: SERVER_IP,
         ifindex: 1,
+        if_name: Some("eth0".into()),
         if_mtu: None,
         if_router: None,
     }
@@ -90,6 +91,7 @@ pub fn mk_default_config() -> crate::config::Config {
                 apply_address: Some(apply_address),
                 ..Default::default()
             }],
+            ..Default::default()
         },
         ..Default::default()
     }
@@ -136,7 +138,9 @@ async fn test_handle_pkt() {
     let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
     serverids.insert(SERVER_IP);
     let conf = mk_default_config();
-    dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle request");
+    dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
 }
 
 #[test]
@@ -243,8 +247,123 @@ async fn confirm_yiaddr_set() {
 
 /* rfc2131 Section 3.2 Step 1: The server MUST broadcast the DHCPNAK message to the 0xffffffff broadcast address because the client may not have a correct network address or subnet mask, and the client may not be answering ARP requests.  Otherwise, the server MUST send the DHCPNAK message to the IP address of the BOOTP relay agent, as recorded in 'giaddr'.
  */
-fn broadcast_failed_renew() {
-    /* TODO */
+#[tokio::test]
+async fn broadcast_failed_renew() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+
+    /* A client in INIT-REBOOT that has moved from some other network */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(
+            &dhcppkt::OPTION_ADDRESSREQUEST,
+            &net::Ipv4Addr::new(198, 51, 100, 1),
+        );
+    let nak = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(nak.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(nak.options.get_serverid(), Some(SERVER_IP));
+    assert_eq!(nak.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(nak.ciaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(nak.giaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(
+        nak.options.get_option::<u32>(&dhcppkt::OPTION_LEASETIME),
+        None
+    );
+    assert!(p.get_leases().expect("Failed to get leases").is_empty());
+
+    /* When relayed, the broadcast bit must be set so the relay broadcasts the NAK */
+    request.pkt.giaddr = EXAMPLE_IP3;
+    let nak = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(nak.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(nak.giaddr, EXAMPLE_IP3);
+    assert_eq!(nak.flags & dhcppkt::FLAG_BROADCAST, dhcppkt::FLAG_BROADCAST);
+}
+
+/* rfc2131 Section 4.3.2: If the DHCP server determines that the client's notion of its address is
+ * incorrect, or the client is on the wrong network, the server SHOULD respond with a DHCPNAK.
+ */
+#[tokio::test]
+async fn nak_requested_address_in_use() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+
+    /* Some other client is using the address */
+    let other_client: &[u8] = b"Other Client";
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &other_client)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+
+    /* So requesting it must be refused, rather than acking some other address */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let nak = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(nak.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(
+        nak.options.get_raw_option(&dhcppkt::OPTION_CLIENTID),
+        Some(CLIENTID)
+    );
+    assert_eq!(p.get_leases().expect("Failed to get leases").len(), 1);
+
+    /* Renewing an address someone else holds is also refused */
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = EXAMPLE_IP4;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST);
+    let nak = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(nak.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+}
+
+#[tokio::test]
+async fn no_nak_for_own_lease() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    for _ in 0..2 {
+        /* INIT-REBOOT for an address this client already holds is acked again */
+        let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+            .expect("Failed to handle request")
+            .expect("No reply sent");
+        assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+        assert_eq!(ack.yiaddr, EXAMPLE_IP4);
+    }
 }
 
 /* rfc2131 Section 3.2 Step 3: If the client detects that the IP address in the DHCPACK message is
@@ -257,8 +376,106 @@ fn broadcast_failed_renew() {
 /* rfc2131 Section 3.4: The server SHOULD check the network address in a DHCPINFORM message for
  * consistency, but MUST NOT check for an existing lease.
  */
-fn dhcpinform_dont_check_existing_lease() {
-    /* TODO */
+#[tokio::test]
+async fn dhcpinform_dont_check_existing_lease() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+
+    /* Some other client holds a lease on the address */
+    let other_client: &[u8] = b"Other Client";
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &other_client)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(ack.yiaddr, EXAMPLE_IP4);
+    let leases = p.get_leases().expect("Failed to get leases");
+
+    /* A statically configured host using the same address still gets its configuration */
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = EXAMPLE_IP4;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPINFORM);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle inform")
+        .expect("No reply sent");
+    assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    assert_eq!(ack.ciaddr, EXAMPLE_IP4);
+    assert_eq!(ack.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(ack.options.get_serverid(), Some(SERVER_IP));
+    assert_eq!(
+        ack.options.get_option::<u32>(&dhcppkt::OPTION_LEASETIME),
+        None
+    );
+
+    /* And the pool was left untouched */
+    let after = p.get_leases().expect("Failed to get leases");
+    assert_eq!(after.len(), leases.len());
+    assert_eq!(after[0].client_id, leases[0].client_id);
+    assert_eq!(after[0].expire, leases[0].expire);
+}
+
+#[tokio::test]
+async fn dhcpinform_includes_options() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = crate::config::Config {
+        dns_servers: vec!["192.0.2.53".parse().unwrap()],
+        dns_search: vec!["example.org".into()],
+        ..mk_default_config()
+    };
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = EXAMPLE_IP4;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPINFORM)
+        .set_option(&dhcppkt::OPTION_PARAMLIST, &vec![6u8, 51, 119]);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle inform")
+        .expect("No reply sent");
+    assert_eq!(
+        ack.options
+            .get_option::<Vec<std::net::Ipv4Addr>>(&dhcppkt::OPTION_DOMAINSERVER),
+        Some(vec!["192.0.2.53".parse::<std::net::Ipv4Addr>().unwrap()])
+    );
+    assert_eq!(
+        ack.options
+            .get_option::<Vec<String>>(&dhcppkt::OPTION_DOMAINSEARCH),
+        Some(vec![String::from("example.org")])
+    );
+    assert_eq!(
+        ack.options.get_option::<u32>(&dhcppkt::OPTION_LEASETIME),
+        None
+    );
+    assert!(p.get_leases().expect("Failed to get leases").is_empty());
+}
+
+#[tokio::test]
+async fn dhcpinform_requires_ciaddr() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPINFORM);
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids, &conf),
+        Err(dhcp::DhcpError::ParseError(
+            dhcppkt::ParseError::InvalidPacket
+        ))
+    );
 }
 
 /* rfc2131 Section 3.5: If the client includes a list of parameters in a DHCPDISCOVER message, it
@@ -466,8 +683,9 @@ async fn offer_required() {
     let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
     serverids.insert(SERVER_IP);
     let conf = mk_default_config();
-    let reply =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle request");
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
     assert_eq!(reply.op, dhcppkt::OP_BOOTREPLY);
     assert_eq!(reply.htype, dhcppkt::HWTYPE_ETHERNET);
     assert_eq!(reply.hlen, 6);
@@ -518,8 +736,9 @@ async fn ack_required() {
     let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
     serverids.insert(SERVER_IP);
     let conf = mk_default_config();
-    let reply =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle request");
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
     assert_eq!(reply.op, dhcppkt::OP_BOOTREPLY);
     assert_eq!(reply.htype, dhcppkt::HWTYPE_ETHERNET);
     assert_eq!(reply.hlen, 6);
@@ -574,8 +793,9 @@ async fn test_renew_unknown() {
     let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
     serverids.insert(SERVER_IP);
     let conf = mk_default_config();
-    let reply =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle request");
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
     assert_eq!(reply.yiaddr, EXAMPLE_IP2);
 }
 
@@ -601,7 +821,8 @@ async fn test_full() {
         .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
 
     let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("No reply sent");
 
     serverids.insert(offer.options.get_serverid().unwrap());
 
@@ -619,7 +840,8 @@ async fn test_full() {
         .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &offer.yiaddr);
 
     let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("No reply sent");
 
     assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
     assert_eq!(ack.yiaddr, offer.yiaddr); /* make sure we don't needlessly change our mind */
@@ -636,7 +858,8 @@ async fn test_full() {
         .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST);
     /* no server id */
     let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .expect("No reply sent");
     assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
     assert_eq!(ack.yiaddr, offer.yiaddr); /* Did we get back the same address? */
 
@@ -644,15 +867,101 @@ async fn test_full() {
     let mut request = mk_dhcp_request();
     /* xid and seconds are not copied from the previous requests */
     request.pkt.secs = 0;
+    request.pkt.ciaddr = offer.yiaddr;
     request.pkt.options = request
         .pkt
         .options
         .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
-        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPRELEASE);
-    /* no server id */
-    /* release is not supported, so we expect an error here.  But we shouldn't crash */
-    let _ack =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect_err("Failed to handle request");
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPRELEASE)
+        .set_option(
+            &dhcppkt::OPTION_SERVERID,
+            &offer.options.get_serverid().unwrap(),
+        );
+    /* RELEASE is never replied to */
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle release"),
+        None
+    );
+
+    /* And the lease is now expired */
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let leases = p.get_leases().expect("Failed to get leases");
+    assert_eq!(leases.len(), 1);
+    assert!(leases[0].expire < now);
+}
+
+/* rfc2131 Section 4.3.3: If the server receives a DHCPDECLINE message, the client has discovered
+ * through some other means that the suggested network address is already in use.  The server MUST
+ * mark the network address as not available and SHOULD notify the local system administrator of a
+ * possible configuration problem.
+ */
+#[tokio::test]
+async fn decline_marks_address_unavailable() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_default_config();
+
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(ack.yiaddr, EXAMPLE_IP4);
+
+    /* The client discovers someone else is using the address, and declines it */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDECLINE)
+        .set_option(&dhcppkt::OPTION_SERVERID, &SERVER_IP)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+            .expect("Failed to handle decline"),
+        None
+    );
+
+    /* Restarting the configuration process should not hand out the declined address again */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle discover")
+        .expect("No reply sent");
+    assert_ne!(offer.yiaddr, EXAMPLE_IP4);
+}
+
+#[tokio::test]
+async fn decline_requires_address() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    let conf = mk_default_config();
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDECLINE);
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids, &conf),
+        Err(dhcp::DhcpError::ParseError(
+            dhcppkt::ParseError::InvalidPacket
+        ))
+    );
 }
 
 #[tokio::test]
@@ -688,6 +997,169 @@ dhcp-policies:
     );
 }
 
+/* rfc2131 Section 4.3.1: the address is selected based on the subnet from which the message was
+ * received (if 'giaddr' is 0) or on the address of the relay agent that forwarded the message
+ * ('giaddr' when not 0).
+ */
+#[tokio::test]
+async fn relayed_discover_uses_giaddr() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+addresses: [192.0.2.0/24, 198.51.100.0/24]
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+    let relay: net::Ipv4Addr = "198.51.100.1".parse().unwrap();
+    let relayed_net = erbium_net::Ipv4Subnet::new("198.51.100.0".parse().unwrap(), 24).unwrap();
+
+    let mut request = mk_dhcp_request();
+    request.if_router = Some(EXAMPLE_IP4);
+    request.pkt.giaddr = relay;
+    request.pkt.hops = 1;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    let offer = dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &lockedconf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert!(relayed_net.contains(offer.yiaddr));
+    /* Never hand out the relay's own address */
+    assert_ne!(offer.yiaddr, relay);
+    assert_eq!(offer.giaddr, relay);
+    assert_eq!(offer.options.get_serverid(), Some(SERVER_IP));
+    /* Our router isn't reachable from the relayed network */
+    assert_ne!(
+        offer
+            .options
+            .get_option::<std::net::Ipv4Addr>(&dhcppkt::OPTION_ROUTERADDR),
+        Some(EXAMPLE_IP4)
+    );
+}
+
+const RELAY_INFO: &[u8] = &[
+    1, 13, b's', b'w', b'i', b't', b'c', b'h', b'1', b'/', b'p', b'o', b'r', b't',
+    b'7', /* Circuit ID */
+    2, 4, 0xc0, 0x00, 0x02, 0xfe, /* Remote ID */
+];
+
+/* RFC3046 Section 2.2: DHCP servers claiming to support the Relay Agent Information option SHALL
+ * echo the entire contents of the Relay Agent Information option in all replies.
+ */
+#[tokio::test]
+async fn echo_relay_agent_info() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = mk_default_config();
+    let mut request = mk_dhcp_request();
+    request.pkt.giaddr = EXAMPLE_IP3;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER)
+        .set_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO, RELAY_INFO);
+    let offer = dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(
+        offer
+            .options
+            .get_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO),
+        Some(RELAY_INFO)
+    );
+
+    /* Even when refusing the request */
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST)
+        .set_option(
+            &dhcppkt::OPTION_ADDRESSREQUEST,
+            &net::Ipv4Addr::new(198, 51, 100, 1),
+        );
+    let nak = dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &conf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(nak.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(
+        nak.options.get_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO),
+        Some(RELAY_INFO)
+    );
+}
+
+#[tokio::test]
+async fn match_relay_agent_info() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = crate::config::load_config_from_string_for_test(
+        "
+dhcp-policies:
+  - match-subnet: 192.0.2.0/24
+    apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+    policies:
+      - match-circuit-id: switch1/port7
+        apply-address: 192.0.2.7
+      - match-circuit-id: switch1/port7
+        match-remote-id: wrong-switch
+        apply-address: 192.0.2.8
+",
+    )
+    .expect("Failed to parse test config");
+    let lockedconf = conf.read().await;
+
+    let mut request = mk_dhcp_request();
+    request.pkt.giaddr = EXAMPLE_IP3;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER)
+        .set_raw_option(&dhcppkt::OPTION_RELAYAGENTINFO, RELAY_INFO);
+    let offer = dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &lockedconf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, "192.0.2.7".parse::<net::Ipv4Addr>().unwrap());
+
+    /* Without the relay agent information, the port specific policy doesn't apply */
+    request.pkt.options = request
+        .pkt
+        .options
+        .remove_option(&dhcppkt::OPTION_RELAYAGENTINFO);
+    let offer = dhcp::handle_pkt(&mut p, &request, dhcp::ServerIds::new(), &lockedconf)
+        .expect("Failed to handle request")
+        .expect("No reply sent");
+    assert_ne!(offer.yiaddr, "192.0.2.7".parse::<net::Ipv4Addr>().unwrap());
+    assert_ne!(offer.yiaddr, "192.0.2.8".parse::<net::Ipv4Addr>().unwrap());
+}
+
+/* rfc2131 Section 4.4.1: When the server allocates a new address, the server SHOULD check that the
+ * offered network address is not already in use; e.g., the server may probe the offered address
+ * with an ICMP Echo Request.
+ *
+ * Only addresses that are new to the client need probing.
+ */
+#[test]
+fn only_probe_new_addresses() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let conf = mk_default_config();
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+
+    let (offer, lease_type) = dhcp::process_pkt(&mut p, &request, dhcp::ServerIds::new(), &conf)
+        .expect("Failed to handle request");
+    assert!(offer.is_some());
+    assert_eq!(lease_type, Some(pool::LeaseType::NewAddress));
+
+    /* The client already has this address, so there is no need to check it again */
+    let (offer, lease_type) = dhcp::process_pkt(&mut p, &request, dhcp::ServerIds::new(), &conf)
+        .expect("Failed to handle request");
+    assert!(offer.is_some());
+    assert_eq!(lease_type, Some(pool::LeaseType::ReusingLease));
+}
+
 /* TODO:
  * 4. The servers receive the DHCPREQUEST broadcast from the client.  Those servers not selected by
  *    the DHCPREQUEST message use the message as notification that the client has declined that
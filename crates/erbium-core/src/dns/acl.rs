@@ -18,7 +18,7 @@
  */
 
 use super::dnspkt;
-use super::router;
+use super::rpz;
 use crate::acl;
 use crate::config;
 use erbium_net::addr::NetAddrExt as _;
@@ -28,23 +28,25 @@ use super::Error;
 
 pub(super) struct DnsAclHandler {
     config: config::SharedConfig,
-    next: router::DnsRouteHandler,
+    next: rpz::DnsRpzHandler,
 }
 
 impl DnsAclHandler {
     pub async fn new(config: config::SharedConfig) -> Self {
         Self {
             config: config.clone(),
-            next: router::DnsRouteHandler::new(config).await,
+            next: rpz::DnsRpzHandler::new(config).await,
         }
     }
 
+    pub async fn shutdown(&self) {
+        self.next.shutdown().await;
+    }
+
     pub async fn handle_query(&self, msg: &DnsMessage) -> Result<dnspkt::DNSPkt, Error> {
         acl::require_permission(
             &self.config.read().await.acls,
-            &acl::Attributes {
-                addr: msg.remote_addr,
-            },
+            &msg.client_attributes(),
             acl::PermissionType::DnsRecursion,
         )
         .map_err(Error::RefusedByAcl)?;
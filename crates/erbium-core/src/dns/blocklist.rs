@@ -0,0 +1,470 @@
+/*   Copyright 2024 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Domain blocklists.
+ *
+ *  Blocklists are loaded from files in hosts format ("0.0.0.0 ads.example.com"), as a plain list
+ *  of domains, or in adblock syntax ("||ads.example.com^").  Blocking a domain also blocks all of
+ *  its subdomains.  Lists can have hundreds of thousands of entries, so rather than keeping them in
+ *  a hash of strings, they are stored in a trie keyed by label starting from the TLD, which shares
+ *  the common suffixes.
+ *
+ *  Lists are expected to be kept up to date by something else (eg a cron job fetching them), so
+ *  the files are periodically checked for changes, and reloaded in the background if they have
+ *  changed.
+ */
+
+use super::dnspkt;
+use super::Error;
+use std::sync::Arc;
+
+lazy_static::lazy_static! {
+    static ref BLOCKLIST_HITS: prometheus::IntCounterVec =
+        prometheus::register_int_counter_vec!("dns_blocklist_hits",
+            "DNS queries blocked by each blocklist",
+            &["list"])
+        .unwrap();
+}
+
+/// How often to check if the blocklist files have changed, if not configured.
+pub const DEFAULT_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Names that are commonly found in hosts files, but should never be blocked.
+const HOSTS_IGNORED: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlockAction {
+    /// Reply with NXDOMAIN.
+    NxDomain,
+    /// Reply with 0.0.0.0 or :: for A and AAAA queries, and no records for anything else.
+    NullAddress,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    blocked: bool,
+    /* Sorted by label, so they can be binary searched. */
+    children: Vec<(Box<str>, Node)>,
+}
+
+/// A set of domains, each of which also matches all of its subdomains.
+#[derive(Debug, Default)]
+pub struct SuffixTrie {
+    root: Node,
+    len: usize,
+}
+
+impl SuffixTrie {
+    /// Builds the trie from lowercased domains.
+    pub fn new(mut domains: Vec<String>) -> Self {
+        /* Insert in order of reversed labels, so new children always sort after existing ones and
+         * can be appended, and parents are seen before their subdomains.
+         */
+        domains.sort_by(|a, b| a.rsplit('.').cmp(b.rsplit('.')));
+        let mut trie = Self::default();
+        'domain: for domain in &domains {
+            let mut node = &mut trie.root;
+            for label in domain.rsplit('.') {
+                if node.blocked {
+                    /* A parent domain is already blocked, so this is redundant. */
+                    continue 'domain;
+                }
+                if node.children.last().map(|(l, _)| &**l) != Some(label) {
+                    node.children.push((label.into(), Node::default()));
+                }
+                node = &mut node.children.last_mut().unwrap().1;
+            }
+            if !node.blocked {
+                node.blocked = true;
+                node.children = vec![];
+                trie.len += 1;
+            }
+        }
+        trie
+    }
+
+    /// Returns if the (lowercased) domain, or any of its parents are in the set.
+    pub fn contains(&self, domain: &str) -> bool {
+        let mut node = &self.root;
+        for label in domain.rsplit('.') {
+            if node.blocked {
+                return true;
+            }
+            match node.children.binary_search_by(|(l, _)| (**l).cmp(label)) {
+                Ok(idx) => node = &node.children[idx].1,
+                Err(_) => return false,
+            }
+        }
+        node.blocked
+    }
+
+    /// The number of domains in the set, not counting subdomains of other domains in the set.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+fn valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.split('.').all(|l| !l.is_empty() && l.len() < 64)
+        && domain
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+/// Extracts the domains from one line of a blocklist.
+fn parse_line(line: &str) -> Vec<String> {
+    let line = line.trim();
+    /* Adblock comments, and the "[Adblock Plus 2.0]" header. */
+    if line.starts_with('!') || line.starts_with('[') {
+        return vec![];
+    }
+    let domains: Vec<&str> = if let Some(rule) = line.strip_prefix("||") {
+        /* Only whole domain rules are supported, rules with paths, wildcards or options are
+         * ignored, as we can't tell from just the name if they apply.
+         */
+        match rule.strip_suffix('^') {
+            Some(domain) => vec![domain],
+            None => vec![],
+        }
+    } else {
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            /* Hosts format: an address followed by one or more names */
+            Some(addr) if addr.parse::<std::net::IpAddr>().is_ok() => tokens
+                .filter(|name| !HOSTS_IGNORED.contains(name))
+                .collect(),
+            /* A plain list of domains */
+            Some(domain) if tokens.next().is_none() => vec![domain],
+            _ => vec![],
+        }
+    };
+    domains
+        .into_iter()
+        .map(|d| d.trim_end_matches('.').to_ascii_lowercase())
+        .filter(|d| valid_domain(d))
+        .collect()
+}
+
+/// Parses a blocklist in any of the supported formats, which can be mixed.
+pub fn parse_blocklist(text: &str) -> SuffixTrie {
+    SuffixTrie::new(text.lines().flat_map(parse_line).collect())
+}
+
+fn load(path: &std::path::Path) -> Result<SuffixTrie, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(parse_blocklist(&text))
+}
+
+#[derive(Debug)]
+struct ReloadState {
+    next_check: std::time::Instant,
+    modified: Option<std::time::SystemTime>,
+}
+
+/// A single blocklist file.
+#[derive(Debug)]
+pub struct List {
+    path: std::path::PathBuf,
+    name: String,
+    trie: std::sync::RwLock<SuffixTrie>,
+    reload: std::sync::Mutex<ReloadState>,
+}
+
+impl List {
+    /// Loads the list.  If the file doesn't exist yet, the list is empty until it's created.
+    pub fn new(path: &std::path::Path) -> Result<Self, String> {
+        let (trie, modified) = if !path.exists() {
+            log::warn!(
+                "Blocklist {} does not exist, it is empty until it is created",
+                path.display()
+            );
+            (SuffixTrie::default(), None)
+        } else {
+            let modified = crate::file_modified(path);
+            let trie = load(path)?;
+            log::info!("Loaded {} domains from {}", trie.len(), path.display());
+            (trie, modified)
+        };
+        Ok(Self {
+            path: path.into(),
+            name: path.display().to_string(),
+            trie: std::sync::RwLock::new(trie),
+            reload: std::sync::Mutex::new(ReloadState {
+                next_check: std::time::Instant::now(),
+                modified,
+            }),
+        })
+    }
+
+    fn reload(&self) {
+        match load(&self.path) {
+            Ok(trie) => {
+                log::info!("Reloaded {} domains from {}", trie.len(), self.name);
+                *self.trie.write().unwrap() = trie;
+            }
+            Err(err) => log::warn!("Failed to reload blocklist: {}", err),
+        }
+    }
+
+    /// Starts reloading the list in the background if it's time to check, and it has changed.
+    fn check_reload(self: &Arc<Self>, interval: std::time::Duration) {
+        let now = std::time::Instant::now();
+        {
+            let mut state = self.reload.lock().unwrap();
+            if state.next_check > now {
+                return;
+            }
+            state.next_check = now + interval;
+            let modified = crate::file_modified(&self.path);
+            if modified == state.modified {
+                return;
+            }
+            /* If the file is still being written, we'll pick up the rest of it next time. */
+            state.modified = modified;
+        }
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let list = self.clone();
+            handle.spawn_blocking(move || list.reload());
+        } else {
+            self.reload();
+        }
+    }
+
+    fn contains(&self, domain: &str) -> bool {
+        self.trie.read().unwrap().contains(domain)
+    }
+}
+
+#[derive(Debug)]
+pub struct Blocklist {
+    pub lists: Vec<Arc<List>>,
+    pub action: BlockAction,
+    pub reload_interval: std::time::Duration,
+}
+
+impl Blocklist {
+    /// Returns the name of the first list that blocks this domain, if any.
+    fn find(&self, domain: &dnspkt::Domain) -> Option<&str> {
+        let domain = domain.to_string().to_ascii_lowercase();
+        self.lists.iter().find_map(|list| {
+            list.check_reload(self.reload_interval);
+            list.contains(&domain).then_some(&*list.name)
+        })
+    }
+
+    /// Returns None if this query isn't blocked, and should be handled by the next route.
+    pub fn handle_query(&self, msg: &super::DnsMessage) -> Option<Result<dnspkt::DNSPkt, Error>> {
+        let question = &msg.in_query.question;
+        let list = self.find(&question.qdomain)?;
+        log::trace!(
+            "[{:x}] {} is blocked by {}",
+            msg.in_query.qid,
+            question.qdomain,
+            list
+        );
+        BLOCKLIST_HITS.with_label_values(&[list]).inc();
+        if self.action == BlockAction::NxDomain {
+            return Some(Err(Error::Blocked));
+        }
+        let rdata = match question.qtype {
+            dnspkt::RR_A => Some(std::net::Ipv4Addr::UNSPECIFIED.octets().to_vec()),
+            dnspkt::RR_AAAA => Some(std::net::Ipv6Addr::UNSPECIFIED.octets().to_vec()),
+            _ => None,
+        };
+        let mut edns = dnspkt::EdnsData::default();
+        edns.set_extended_dns_error(
+            dnspkt::EDE_BLOCKED,
+            "Server is configured to block these queries",
+        );
+        Some(Ok(dnspkt::DNSPkt {
+            answer: rdata
+                .map(|rdata| dnspkt::RR {
+                    domain: question.qdomain.clone(),
+                    class: dnspkt::CLASS_IN,
+                    rrtype: question.qtype,
+                    ttl: super::zone::DEFAULT_TTL,
+                    rdata: dnspkt::RData::Other(rdata),
+                })
+                .into_iter()
+                .collect(),
+            edns: Some(edns),
+            ..dnspkt::DNSPkt::reply_to(&msg.in_query, dnspkt::NOERROR)
+        }))
+    }
+}
+
+#[test]
+fn test_suffix_trie() {
+    let trie = SuffixTrie::new(
+        [
+            "ads.example.com",
+            "tracker.example.net",
+            "example.org",
+            "deep.sub.example.org",
+            "ads.example.com",
+        ]
+        .iter()
+        .map(|d| d.to_string())
+        .collect(),
+    );
+    /* Duplicates, and subdomains of blocked domains aren't stored */
+    assert_eq!(trie.len(), 3);
+    assert!(trie.contains("ads.example.com"));
+    assert!(trie.contains("x.ads.example.com"));
+    assert!(!trie.contains("example.com"));
+    assert!(!trie.contains("notads.example.com"));
+    assert!(!trie.contains("com"));
+    assert!(trie.contains("example.org"));
+    assert!(trie.contains("www.example.org"));
+    assert!(!trie.contains("example.net"));
+    assert!(!trie.contains(""));
+}
+
+#[test]
+fn test_parse_blocklist() {
+    let trie = parse_blocklist(
+        "# A hosts file
+127.0.0.1 localhost
+0.0.0.0 ads.example.com tracker.example.com # trailing comment
+::1 ip6-localhost
+[Adblock Plus 2.0]
+! An adblock list
+||Adverts.Example.NET^
+||example.net/path^
+||example.org^$third-party
+@@||allowed.example.org^
+plain.example.org
+not a domain
+bad/domain.example
+",
+    );
+    assert!(trie.contains("ads.example.com"));
+    assert!(trie.contains("tracker.example.com"));
+    assert!(trie.contains("adverts.example.net"));
+    assert!(trie.contains("plain.example.org"));
+    assert!(!trie.contains("localhost"));
+    assert!(!trie.contains("ip6-localhost"));
+    assert!(!trie.contains("example.net"));
+    assert!(!trie.contains("example.org"));
+    assert!(!trie.contains("allowed.example.org"));
+    assert_eq!(trie.len(), 4);
+}
+
+#[cfg(test)]
+fn test_blocklist(path: &std::path::Path, action: BlockAction) -> Blocklist {
+    Blocklist {
+        lists: vec![Arc::new(List::new(path).unwrap())],
+        action,
+        reload_interval: DEFAULT_RELOAD_INTERVAL,
+    }
+}
+
+#[test]
+fn test_blocklist_null_address() {
+    let path = crate::test_util::TempPath::new("blocklist");
+    std::fs::write(&path, "0.0.0.0 ads.example.com\n").unwrap();
+    let blocklist = test_blocklist(&path, BlockAction::NullAddress);
+    let hits = || {
+        BLOCKLIST_HITS
+            .with_label_values(&[&path.display().to_string()])
+            .get()
+    };
+
+    for (qtype, rdata) in [
+        (dnspkt::RR_A, Some(vec![0; 4])),
+        (dnspkt::RR_AAAA, Some(vec![0; 16])),
+        (dnspkt::RR_TXT, None),
+    ] {
+        let msg = super::DnsMessage::new_for_test("x.ads.example.com", qtype);
+        let reply = blocklist.handle_query(&msg).unwrap().unwrap();
+        assert_eq!(reply.rcode, dnspkt::NOERROR);
+        assert_eq!(
+            reply.answer.first().map(|rr| rr.rdata.clone()),
+            rdata.map(dnspkt::RData::Other)
+        );
+        assert_eq!(
+            reply.edns.unwrap().get_extended_dns_error().unwrap().0,
+            dnspkt::EDE_BLOCKED
+        );
+    }
+    assert_eq!(hits(), 3);
+
+    /* Names that aren't blocked are left for the next route, and aren't counted. */
+    let msg = super::DnsMessage::new_for_test("example.com", dnspkt::RR_A);
+    assert!(blocklist.handle_query(&msg).is_none());
+    assert_eq!(hits(), 3);
+}
+
+#[test]
+fn test_blocklist_nxdomain() {
+    let path = crate::test_util::TempPath::new("blocklist");
+    std::fs::write(&path, "||ads.example.com^\n").unwrap();
+    let blocklist = test_blocklist(&path, BlockAction::NxDomain);
+    let msg = super::DnsMessage::new_for_test("ads.example.com", dnspkt::RR_A);
+    assert!(matches!(
+        blocklist.handle_query(&msg),
+        Some(Err(Error::Blocked))
+    ));
+    assert_eq!(
+        BLOCKLIST_HITS
+            .with_label_values(&[&path.display().to_string()])
+            .get(),
+        1
+    );
+}
+
+#[test]
+fn test_blocklist_reload() {
+    let path = crate::test_util::TempPath::new("blocklist");
+    /* A list that doesn't exist yet starts empty. */
+    let list = Arc::new(List::new(&path).unwrap());
+    assert!(!list.contains("ads.example.com"));
+
+    let write = |text: &str, secs: u64| {
+        std::fs::write(&path, text).unwrap();
+        crate::test_util::set_modified(&path, secs);
+    };
+
+    /* Outside of a runtime, reloads happen immediately. */
+    write("ads.example.com\n", 1);
+    list.check_reload(std::time::Duration::ZERO);
+    assert!(list.contains("ads.example.com"));
+
+    write("tracker.example.com\n", 2);
+    list.check_reload(std::time::Duration::ZERO);
+    assert!(!list.contains("ads.example.com"));
+    assert!(list.contains("tracker.example.com"));
+
+    /* Nothing is reloaded if the modification time hasn't changed. */
+    write("ads.example.com\n", 2);
+    list.check_reload(std::time::Duration::ZERO);
+    assert!(list.contains("tracker.example.com"));
+}
@@ -20,6 +20,7 @@
 
 use super::Error;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::time::{Duration, Instant};
@@ -27,9 +28,26 @@ use tokio::time::{Duration, Instant};
 use crate::dns::dnspkt;
 use crate::dns::outquery;
 
+mod snapshot;
 #[cfg(test)]
 mod test;
 
+/* RFC8767 Section 5: Stale answers are given a TTL of 30 seconds, so clients come back and get a
+ * fresh answer soon after the upstream recovers.
+ */
+const STALE_TTL: u32 = 30;
+
+/* RFC8767 Section 5: After the upstream fails to refresh a stale entry, keep answering from the
+ * stale entry without waiting on the upstream, and only try to refresh it in the background this
+ * often.
+ */
+const STALE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/* Entries that have been used at least this many times are refreshed in the background when they
+ * are about to expire, so the next client doesn't have to wait for the upstream.
+ */
+const PREFETCH_MIN_HITS: u64 = 3;
+
 lazy_static::lazy_static! {
     static ref DNS_CACHE: prometheus::IntCounterVec =
         prometheus::register_int_counter_vec!("dns_cache",
@@ -41,13 +59,30 @@ lazy_static::lazy_static! {
         prometheus::register_int_gauge!("dns_cache_size",
             "Number of entries in the cache")
         .unwrap();
+
+    static ref DNS_CACHE_BYTES: prometheus::IntGauge =
+        prometheus::register_int_gauge!("dns_cache_bytes",
+            "Approximate memory used by entries in the cache")
+        .unwrap();
+
+    static ref DNS_CACHE_COALESCED: prometheus::IntCounter =
+        prometheus::register_int_counter!("dns_cache_coalesced",
+            "Number of queries that waited for the same query already sent upstream")
+        .unwrap();
+
+    static ref DNS_CACHE_EVICTIONS: prometheus::IntCounter =
+        prometheus::register_int_counter!("dns_cache_evictions",
+            "Number of entries evicted from the cache to make room for new entries")
+        .unwrap();
 }
 
-#[derive(Eq, PartialEq, Hash)]
-#[cfg_attr(test, derive(Clone))]
+#[derive(Eq, PartialEq, Hash, Clone)]
 struct CacheKey {
+    /* Views can forward the same name to different servers, so they don't share entries */
+    view: Option<String>,
     qname: dnspkt::Domain,
-    qtype: dnspkt::Type,
+    /* NXDOMAIN means the name doesn't exist for any type, so is cached with no type */
+    qtype: Option<dnspkt::Type>,
     edns_do: bool,
     cd: bool,
 }
@@ -56,20 +91,180 @@ struct CacheValue {
     reply: Result<dnspkt::DNSPkt, Error>,
     birth: Instant,
     lifetime: Duration,
+    /* When the upstream last failed to refresh this entry after it expired */
+    refresh_failed: Option<Instant>,
+    /* The cache's clock when this entry was last used, for LRU eviction */
+    last_used: AtomicU64,
+    /* The approximate memory used by this entry, filled in when it's added to the cache */
+    size: usize,
+    /* How many times this entry has been used to answer a query */
+    hits: AtomicU64,
+    /* Set when a refresh has been started, so only one is started */
+    prefetching: AtomicBool,
 }
 
 impl CacheValue {
+    fn new(reply: Result<dnspkt::DNSPkt, Error>, birth: Instant, lifetime: Duration) -> Self {
+        CacheValue {
+            reply,
+            birth,
+            lifetime,
+            refresh_failed: None,
+            last_used: AtomicU64::new(0),
+            size: 0,
+            hits: AtomicU64::new(0),
+            prefetching: AtomicBool::new(false),
+        }
+    }
+
     fn expiry(&self) -> Instant {
         self.birth + self.lifetime
     }
+
+    /* This doesn't need to be exact, it just has to grow with the size of the reply, so that
+     * large replies can't be used to make the cache use much more memory than configured.
+     */
+    fn estimate_size(&self, ck: &CacheKey) -> usize {
+        let reply = match &self.reply {
+            Ok(reply) => {
+                reply.serialise().len()
+                    + (reply.answer.len() + reply.nameserver.len() + reply.additional.len())
+                        * std::mem::size_of::<dnspkt::RR>()
+            }
+            Err(_) => 0,
+        };
+        std::mem::size_of::<CacheKey>()
+            + std::mem::size_of::<CacheValue>()
+            + ck.view.as_ref().map_or(0, String::len)
+            + ck.qname.to_wire().len()
+            + reply
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<CacheKey, CacheValue>,
+    /* The approximate memory used by all the entries */
+    bytes: usize,
+    /* Ticks every time an entry is used, so entries can be ordered by how recently they were
+     * used.  This is atomic so that lookups can update it with only a read lock on the cache.
+     */
+    clock: AtomicU64,
+}
+
+impl Cache {
+    fn new() -> Self {
+        Default::default()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn get(&self, ck: &CacheKey) -> Option<&CacheValue> {
+        self.entries.get(ck)
+    }
+
+    fn get_mut(&mut self, ck: &CacheKey) -> Option<&mut CacheValue> {
+        self.entries.get_mut(ck)
+    }
+
+    /* Marks an entry as recently used, so it is evicted last. */
+    fn touch(&self, value: &CacheValue) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        value.last_used.store(now, Ordering::Relaxed);
+    }
+
+    fn insert(&mut self, ck: CacheKey, mut value: CacheValue) {
+        self.touch(&value);
+        value.size = value.estimate_size(&ck);
+        self.bytes += value.size;
+        if let Some(old) = self.entries.insert(ck, value) {
+            self.bytes -= old.size;
+        }
+    }
+
+    fn remove(&mut self, ck: &CacheKey) -> Option<CacheValue> {
+        let value = self.entries.remove(ck)?;
+        self.bytes -= value.size;
+        Some(value)
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&CacheKey, &CacheValue) -> bool) {
+        let mut bytes = self.bytes;
+        self.entries.retain(|k, v| {
+            let keep = f(k, v);
+            if !keep {
+                bytes -= v.size;
+            }
+            keep
+        });
+        self.bytes = bytes;
+    }
+
+    /* Evicts the least recently used entries until the cache is within its limits, returning how
+     * many were evicted.
+     */
+    fn evict(&mut self, max_entries: usize, max_bytes: usize) -> usize {
+        if self.entries.len() <= max_entries && self.bytes <= max_bytes {
+            return 0;
+        }
+
+        /* Finding the least recently used entries means sorting the entire cache, so evict an
+         * extra 10% each time, so this isn't needed again for a while.
+         */
+        let target_entries = max_entries - max_entries / 10;
+        let target_bytes = max_bytes - max_bytes / 10;
+        let mut lru = self
+            .entries
+            .iter()
+            .map(|(k, v)| (v.last_used.load(Ordering::Relaxed), k.clone()))
+            .collect::<Vec<_>>();
+        lru.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+        let mut evicted = 0;
+        for (_, ck) in lru {
+            if self.entries.len() <= target_entries && self.bytes <= target_bytes {
+                break;
+            }
+            self.remove(&ck);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn update_metrics(&self) {
+        use std::convert::TryInto as _;
+        DNS_CACHE_SIZE.set(self.len().try_into().unwrap_or(i64::MAX));
+        DNS_CACHE_BYTES.set(self.bytes.try_into().unwrap_or(i64::MAX));
+    }
+}
+
+/* Queries that have been sent upstream, and not answered yet.  The result is sent on the channel
+ * when the upstream answers.
+ */
+type InFlight = std::sync::Mutex<
+    HashMap<CacheKey, tokio::sync::watch::Receiver<Option<Result<dnspkt::DNSPkt, Error>>>>,
+>;
+
+/* Removes a query from the in flight table when it's finished, or abandoned. */
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    ck: &'a CacheKey,
 }
 
-type Cache = HashMap<CacheKey, CacheValue>;
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(self.ck);
+    }
+}
 
 #[derive(Clone)]
 pub struct CacheHandler {
     next: outquery::OutQuery,
     cache: Arc<RwLock<Cache>>,
+    in_flight: Arc<InFlight>,
+    config: super::config::CacheConfig,
 }
 
 /* std::io::Error is not clonable (for good reason), but we want to clone it.
@@ -97,6 +292,7 @@ fn clone_out_reply(reply: &Result<dnspkt::DNSPkt, Error>) -> Result<dnspkt::DNSP
         Err(OutReply(OutReplyError::TcpConnection(msg))) => {
             Err(OutReply(OutReplyError::TcpConnection(msg.clone())))
         }
+        Err(OutReply(OutReplyError::Http(msg))) => Err(OutReply(OutReplyError::Http(msg.clone()))),
         Err(OutReply(OutReplyError::Parse(msg))) => {
             Err(OutReply(OutReplyError::Parse(msg.clone())))
         }
@@ -106,18 +302,122 @@ fn clone_out_reply(reply: &Result<dnspkt::DNSPkt, Error>) -> Result<dnspkt::DNSP
         Err(Denied(x)) => Err(Denied(x.clone())),
         Err(Blocked) => Err(Blocked),
         Err(NoRouteConfigured) => Err(NoRouteConfigured),
+        Err(LeasesUnavailable(msg)) => Err(LeasesUnavailable(msg.clone())),
         /* These errors cannot occur */
         Err(ListenError(..)) => unreachable!(),
         Err(AcceptError(..)) => unreachable!(),
+        Err(TlsError(_)) => unreachable!(),
         Err(RecvError(_)) => unreachable!(),
         Err(ParseError(_)) => unreachable!(),
         Err(RefusedByAcl(_)) => unreachable!(),
+        Err(Dropped) => unreachable!(),
     }
 }
 
-/* std::io::Error is not clonable (for good reason), but we want to clone it.
- * So instead, we do some mappings to remove the std::io::Error
+/* RFC2308: A NXDOMAIN, or a NOERROR without any records of the type asked for, is a negative
+ * answer.
+ */
+fn is_negative(reply: &dnspkt::DNSPkt) -> bool {
+    reply.rcode == dnspkt::NXDOMAIN
+        || (reply.rcode == dnspkt::NOERROR
+            && !reply
+                .answer
+                .iter()
+                .any(|rr| rr.rrtype == reply.question.qtype))
+}
+
+/* RFC6604: The rcode of a reply that follows a CNAME or DNAME chain is about the end of the chain, so
+ * a NXDOMAIN only says the name asked for doesn't exist if there is no chain.
+ */
+fn is_name_error(reply: &dnspkt::DNSPkt) -> bool {
+    reply.rcode == dnspkt::NXDOMAIN
+        && !reply
+            .answer
+            .iter()
+            .any(|rr| rr.rrtype == dnspkt::RR_CNAME || rr.rrtype == dnspkt::RR_DNAME)
+}
+
+/* RFC2308 Section 5: Negative answers are cached for the lesser of the SOA's TTL and its minimum
+ * field.  Without a SOA we don't know how long the answer is good for, so it isn't cached.
+ */
+fn negative_expiry(reply: &dnspkt::DNSPkt, max: Duration) -> Duration {
+    let soa_ttl = reply
+        .nameserver
+        .iter()
+        .filter_map(|rr| match &rr.rdata {
+            dnspkt::RData::Soa(soa) => Some(std::cmp::min(rr.ttl, soa.minimum)),
+            _ => None,
+        })
+        .min();
+    match soa_ttl {
+        Some(ttl) => reply
+            .answer
+            .iter()
+            .map(|rr| rr.ttl)
+            .chain(std::iter::once(ttl))
+            .min()
+            .map(|ttl| std::cmp::min(Duration::from_secs(ttl.into()), max))
+            .unwrap(),
+        None => Duration::from_secs(0),
+    }
+}
+
+/* Records in the cache shouldn't outlive the cache entry, so the TTLs count down to zero when the
+ * entry expires.  This matters for negative answers, where the SOA's TTL can be longer than we
+ * cache the answer for.
  */
+fn clamp_ttls(reply: &mut Result<dnspkt::DNSPkt, Error>, lifetime: Duration) {
+    if let Ok(reply) = reply {
+        let max = lifetime.as_secs().try_into().unwrap_or(u32::MAX);
+        for rr in reply
+            .answer
+            .iter_mut()
+            .chain(reply.nameserver.iter_mut())
+            .chain(reply.additional.iter_mut())
+        {
+            rr.ttl = std::cmp::min(rr.ttl, max);
+        }
+    }
+}
+
+/* Did the upstream fail to give us an answer? */
+fn is_upstream_failure(reply: &Result<dnspkt::DNSPkt, Error>) -> bool {
+    match reply {
+        Ok(reply) => reply.rcode == dnspkt::SERVFAIL,
+        Err(Error::OutReply(_)) => true,
+        Err(_) => false,
+    }
+}
+
+/* RFC8767: Stale answers are marked as such with an extended DNS error (RFC8914). */
+fn stale_reply(reply: &dnspkt::DNSPkt) -> dnspkt::DNSPkt {
+    let mut reply = reply.clone();
+    for rr in reply
+        .answer
+        .iter_mut()
+        .chain(reply.nameserver.iter_mut())
+        .chain(reply.additional.iter_mut())
+    {
+        rr.ttl = STALE_TTL;
+    }
+    let (code, msg) = if reply.rcode == dnspkt::NXDOMAIN {
+        (
+            dnspkt::EDE_STALE_NXDOMAIN,
+            "Upstream failed, answering NXDOMAIN from expired cache",
+        )
+    } else {
+        (
+            dnspkt::EDE_STALE_ANSWER,
+            "Upstream failed, answering from expired cache",
+        )
+    };
+    reply
+        .edns
+        .get_or_insert_with(Default::default)
+        .set_extended_dns_error(code, msg);
+    reply
+}
+
 fn clone_with_ttl_decrement_out_reply(
     reply: &Result<dnspkt::DNSPkt, Error>,
     decrement: std::time::Duration,
@@ -129,30 +429,112 @@ fn clone_with_ttl_decrement_out_reply(
 }
 
 impl CacheHandler {
-    pub async fn new() -> Self {
-        let cache = Arc::new(RwLock::new(Cache::new()));
+    pub async fn new(config: super::config::CacheConfig) -> Self {
+        let mut cache = Cache::new();
+        if let Some(path) = &config.snapshot_file {
+            Self::load_snapshot(&mut cache, path, &config).await;
+        }
+        let cache = Arc::new(RwLock::new(cache));
         let cache_copy = cache.clone();
+        let serve_stale = config.serve_stale;
         tokio::spawn(async move {
-            Self::expire_thread(cache_copy).await;
+            Self::expire_thread(cache_copy, serve_stale).await;
         });
-        CacheHandler {
+        let handler = CacheHandler {
             next: outquery::OutQuery::new(),
             cache,
+            in_flight: Default::default(),
+            config,
+        };
+        if handler.config.snapshot_file.is_some() {
+            let handler_copy = handler.clone();
+            tokio::spawn(async move {
+                handler_copy.snapshot_thread().await;
+            });
         }
+        handler
     }
 
-    /* Expires entries, returns the time for the next expiration run. */
-    fn expire(cache: &mut Cache, now: Instant) -> Instant {
-        use std::convert::TryInto as _;
+    /// Saves the cache, so it can be reloaded when erbium restarts.
+    pub async fn shutdown(&self) {
+        self.save_snapshot().await;
+    }
 
+    async fn load_snapshot(
+        cache: &mut Cache,
+        path: &std::path::Path,
+        config: &super::config::CacheConfig,
+    ) {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            /* There won't be a snapshot the first time erbium is run */
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                log::warn!("Failed to read DNS cache from {}: {}", path.display(), e);
+                return;
+            }
+        };
+        match snapshot::deserialise(
+            &data,
+            Instant::now(),
+            std::time::SystemTime::now(),
+            config.serve_stale,
+        ) {
+            Ok(entries) => {
+                log::info!(
+                    "Loaded {} DNS cache entries from {}",
+                    entries.len(),
+                    path.display()
+                );
+                for (ck, value) in entries {
+                    cache.insert(ck, value);
+                }
+                cache.evict(config.max_entries, config.max_size.unwrap_or(usize::MAX));
+                cache.update_metrics();
+            }
+            Err(e) => log::warn!("Failed to load DNS cache from {}: {}", path.display(), e),
+        }
+    }
+
+    async fn save_snapshot(&self) {
+        let Some(path) = &self.config.snapshot_file else {
+            return;
+        };
+        let data = snapshot::serialise(
+            &*self.cache.read().await,
+            Instant::now(),
+            std::time::SystemTime::now(),
+        );
+        match snapshot::save(path, &data).await {
+            Ok(()) => log::debug!("Saved DNS cache to {}", path.display()),
+            Err(e) => log::warn!("Failed to save DNS cache to {}: {}", path.display(), e),
+        }
+    }
+
+    async fn snapshot_thread(&self) {
+        loop {
+            tokio::time::sleep(self.config.snapshot_interval).await;
+            self.save_snapshot().await;
+        }
+    }
+
+    /* Expires entries that are too old to be served stale, returns the time for the next
+     * expiration run.
+     */
+    fn expire(cache: &mut Cache, now: Instant, serve_stale: Duration) -> Instant {
         /* We don't have any notification from the resolvers if this time needs to go down.
          * So if we get a spike of resolutions we might have to start doing expiries, so poll
          * at least every this time.
          */
         let mut next_cycle = now + Duration::from_secs(1800);
         cache.retain(|_k, v| {
-            if v.expiry() >= now {
-                next_cycle = std::cmp::min(next_cycle, v.expiry());
+            /* Only answers can be served stale, there is no point keeping errors around */
+            let expiry = match v.reply {
+                Ok(_) => v.expiry() + serve_stale,
+                Err(_) => v.expiry(),
+            };
+            if expiry >= now {
+                next_cycle = std::cmp::min(next_cycle, expiry);
                 true
             } else {
                 false
@@ -160,7 +542,7 @@ impl CacheHandler {
         });
 
         /* Update the new cache size. */
-        DNS_CACHE_SIZE.set(cache.len().try_into().unwrap_or(i64::MAX));
+        cache.update_metrics();
 
         /* Don't waste cpu cycling too often.  If we have a lot of entries expiring at about
          * the same time, cap this to poll a bit more infrequently, it's more efficient to do
@@ -170,14 +552,14 @@ impl CacheHandler {
         std::cmp::max(next_cycle, Instant::now() + Duration::from_secs(30))
     }
 
-    async fn expire_thread(cache: Arc<RwLock<Cache>>) {
+    async fn expire_thread(cache: Arc<RwLock<Cache>>, serve_stale: Duration) {
         loop {
             let next_cycle;
 
             /* Expire all the old entries */
             {
                 let mut rwcache = cache.write().await;
-                next_cycle = Self::expire(&mut rwcache, Instant::now());
+                next_cycle = Self::expire(&mut rwcache, Instant::now(), serve_stale);
             }
 
             /* Now wait until then. */
@@ -189,26 +571,63 @@ impl CacheHandler {
         }
     }
 
+    #[cfg(test)]
     fn get_entry(
         cache: &Cache,
         ck: &CacheKey,
         now: Instant,
     ) -> Option<Result<dnspkt::DNSPkt, Error>> {
-        /* Check to see if we have a cache hit that is still valid, if so, return it */
-        if let Some(entry) = cache.get(ck) {
-            if entry.expiry() >= now {
-                let remaining = (entry.birth + entry.lifetime) - now;
-                log::trace!("Cache hit ({:?} remaining)", remaining);
-                DNS_CACHE.with_label_values(&["HIT"]).inc();
-                Some(clone_with_ttl_decrement_out_reply(
-                    &entry.reply,
-                    now - entry.birth,
-                ))
-            } else {
-                log::trace!("Cache miss: Cache expired");
-                DNS_CACHE.with_label_values(&["EXPIRED"]).inc();
-                None
+        Self::get_first_entry(cache, std::slice::from_ref(ck), now)
+    }
+
+    /* The keys for the entries that could answer this query, best first. */
+    fn candidate_keys(&self, ck: &CacheKey) -> Vec<CacheKey> {
+        let mut keys = vec![
+            ck.clone(),
+            CacheKey {
+                qtype: None,
+                ..ck.clone()
+            },
+        ];
+        /* RFC8020: If a name doesn't exist, then nothing below it exists either */
+        if self.config.nxdomain_cut {
+            let mut name = ck.qname.parent();
+            while let Some(parent) = name {
+                name = parent.parent();
+                keys.push(CacheKey {
+                    qname: parent,
+                    qtype: None,
+                    ..ck.clone()
+                });
             }
+        }
+        keys
+    }
+
+    fn get_first_entry(
+        cache: &Cache,
+        cks: &[CacheKey],
+        now: Instant,
+    ) -> Option<Result<dnspkt::DNSPkt, Error>> {
+        /* Check to see if we have a cache hit that is still valid, if so, return it */
+        let entries = cks
+            .iter()
+            .filter_map(|ck| cache.get(ck))
+            .collect::<Vec<_>>();
+        if let Some(entry) = entries.iter().find(|entry| entry.expiry() >= now) {
+            let remaining = (entry.birth + entry.lifetime) - now;
+            log::trace!("Cache hit ({:?} remaining)", remaining);
+            DNS_CACHE.with_label_values(&["HIT"]).inc();
+            cache.touch(entry);
+            entry.hits.fetch_add(1, Ordering::Relaxed);
+            Some(clone_with_ttl_decrement_out_reply(
+                &entry.reply,
+                now - entry.birth,
+            ))
+        } else if !entries.is_empty() {
+            log::trace!("Cache miss: Cache expired");
+            DNS_CACHE.with_label_values(&["EXPIRED"]).inc();
+            None
         } else {
             log::trace!("Cache miss: Entry not present");
             DNS_CACHE.with_label_values(&["MISS"]).inc();
@@ -216,8 +635,65 @@ impl CacheHandler {
         }
     }
 
+    /* Popular entries that are in the last 10% of their lifetime are refreshed before they expire.
+     * Returns the key of the entry to refresh, if it needs refreshing and nothing else has started
+     * refreshing it.
+     */
+    fn claim_prefetch(&self, cache: &Cache, ck: &CacheKey, now: Instant) -> Option<CacheKey> {
+        if !self.config.prefetch {
+            return None;
+        }
+        /* Only entries for this name, refreshing a parent's NXDOMAIN would need a different query */
+        let (ck, entry) = [
+            ck.clone(),
+            CacheKey {
+                qtype: None,
+                ..ck.clone()
+            },
+        ]
+        .into_iter()
+        .find_map(|ck| {
+            cache
+                .get(&ck)
+                .filter(|entry| entry.expiry() >= now)
+                .map(|entry| (ck, entry))
+        })?;
+        if entry.reply.is_ok()
+            && entry.hits.load(Ordering::Relaxed) >= PREFETCH_MIN_HITS
+            && (entry.expiry() - now) * 10 <= entry.lifetime
+            && !entry.prefetching.swap(true, Ordering::Relaxed)
+        {
+            Some(ck)
+        } else {
+            None
+        }
+    }
+
+    /* RFC8767: Finds an expired answer that can still be served if the upstream fails. */
+    fn get_stale_entry(
+        &self,
+        cache: &Cache,
+        cks: &[CacheKey],
+        now: Instant,
+    ) -> Option<(CacheKey, dnspkt::DNSPkt, Option<Instant>)> {
+        cks.iter().find_map(|ck| match cache.get(ck) {
+            Some(
+                entry @ CacheValue {
+                    reply: Ok(reply), ..
+                },
+            ) if entry.expiry() + self.config.serve_stale >= now => {
+                cache.touch(entry);
+                Some((ck.clone(), reply.clone(), entry.refresh_failed))
+            }
+            _ => None,
+        })
+    }
+
     fn calculate_expiry(&self, out_result: &Result<crate::dns::dnspkt::DNSPkt, Error>) -> Duration {
         match &out_result {
+            Ok(out_reply) if is_negative(out_reply) => {
+                negative_expiry(out_reply, self.config.max_negative_ttl)
+            }
             /* If we got a packet, then use the expiry from the packet. */
             Ok(out_reply) => out_reply.get_expiry(),
             /* If there was a problem sending the reply, then wait for at least as long
@@ -240,62 +716,239 @@ impl CacheHandler {
         out_result: &Result<dnspkt::DNSPkt, Error>,
         expiry: Duration,
     ) {
-        use std::convert::TryInto as _;
+        let mut reply = clone_out_reply(out_result);
+        clamp_ttls(&mut reply, expiry);
+        cache.insert(ck, CacheValue::new(reply, Instant::now(), expiry));
 
-        cache.insert(
-            ck,
-            CacheValue {
-                reply: clone_out_reply(out_result),
-                birth: Instant::now(),
-                lifetime: expiry,
-            },
+        let evicted = cache.evict(
+            self.config.max_entries,
+            self.config.max_size.unwrap_or(usize::MAX),
         );
+        if evicted > 0 {
+            log::trace!("Evicted {} entries from the cache", evicted);
+            DNS_CACHE_EVICTIONS.inc_by(evicted as u64);
+        }
 
-        DNS_CACHE_SIZE.set(cache.len().try_into().unwrap_or(i64::MAX));
+        cache.update_metrics();
     }
 
     pub async fn handle_query(
         &self,
         msg: &super::DnsMessage,
-        addr: std::net::SocketAddr,
+        view: Option<&str>,
+        servers: &[outquery::Nameserver],
     ) -> Result<dnspkt::DNSPkt, Error> {
         /* Only do caching for IN queries */
         if msg.in_query.question.qclass != dnspkt::CLASS_IN {
             log::trace!("[{:x}] Not caching non-IN query", msg.in_query.qid);
             DNS_CACHE.with_label_values(&["UNCACHABLE_CLASS"]).inc();
-            return self.next.handle_query(msg, addr).await;
+            return self.next.handle_query(msg, servers).await;
         }
 
         let ck = CacheKey {
+            view: view.map(str::to_string),
             qname: msg.in_query.question.qdomain.clone(),
-            qtype: msg.in_query.question.qtype,
+            qtype: Some(msg.in_query.question.qtype),
             edns_do: msg.in_query.edns_do,
             cd: msg.in_query.cd,
         };
 
-        {
+        let stale = {
             let rocache = self.cache.read().await;
-            if let Some(result) = Self::get_entry(&rocache, &ck, Instant::now()) {
+            let cks = self.candidate_keys(&ck);
+            let now = Instant::now();
+            if let Some(result) = Self::get_first_entry(&rocache, &cks, now) {
+                if let Some(prefetch_ck) = self.claim_prefetch(&rocache, &ck, now) {
+                    log::trace!(
+                        "[{:x}] Refreshing popular entry before it expires",
+                        msg.in_query.qid
+                    );
+                    DNS_CACHE.with_label_values(&["PREFETCH"]).inc();
+                    let handler = self.clone();
+                    let msg = msg.clone();
+                    let servers = servers.to_vec();
+                    tokio::spawn(async move {
+                        handler.prefetch(&msg, ck, prefetch_ck, &servers).await;
+                    });
+                }
                 return result;
             }
+            self.get_stale_entry(&rocache, &cks, now)
+        };
+
+        /* If the upstream recently failed to refresh this entry, don't make the client wait for
+         * it to fail again.  Answer from the stale entry, and occasionally retry in the background.
+         */
+        if let Some((stale_ck, reply, Some(failed))) = stale {
+            DNS_CACHE.with_label_values(&["STALE"]).inc();
+            if failed.elapsed() >= STALE_REFRESH_INTERVAL {
+                self.mark_refresh_failed(&stale_ck).await;
+                let handler = self.clone();
+                let msg = msg.clone();
+                let servers = servers.to_vec();
+                let stale = Some((stale_ck, reply.clone()));
+                tokio::spawn(async move {
+                    let _ = handler.refresh(&msg, ck, stale, &servers).await;
+                });
+            }
+            return Ok(stale_reply(&reply));
         }
 
-        /* Cache miss: Go attempt the resolve, and return the result */
-        let out_result = self.next.handle_query(msg, addr).await;
+        self.coalesced_refresh(
+            msg,
+            ck,
+            stale.map(|(stale_ck, reply, _)| (stale_ck, reply)),
+            servers,
+        )
+        .await
+    }
 
-        let expiry = self.calculate_expiry(&out_result);
+    /* If the same query has already been sent upstream, wait for its answer instead of sending
+     * another one.
+     */
+    async fn coalesced_refresh(
+        &self,
+        msg: &super::DnsMessage,
+        ck: CacheKey,
+        stale: Option<(CacheKey, dnspkt::DNSPkt)>,
+        servers: &[outquery::Nameserver],
+    ) -> Result<dnspkt::DNSPkt, Error> {
+        use std::collections::hash_map::Entry;
+
+        let tx = loop {
+            let (tx, rx) = tokio::sync::watch::channel(None);
+            let mut rx = match self.in_flight.lock().unwrap().entry(ck.clone()) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => {
+                    entry.insert(rx);
+                    break tx;
+                }
+            };
 
-        /* Only insert into the cache if the duration is reasonable */
-        if expiry > Duration::from_secs(0) {
-            let mut rwcache = self.cache.write().await;
-            self.insert_cache_entry(&mut rwcache, ck, &out_result, expiry);
+            log::trace!(
+                "[{:x}] Waiting for the same query already sent upstream",
+                msg.in_query.qid
+            );
+            DNS_CACHE_COALESCED.inc();
+            loop {
+                if let Some(result) = &*rx.borrow() {
+                    return clone_out_reply(result);
+                }
+                if rx.changed().await.is_err() {
+                    break;
+                }
+            }
+            /* The query was abandoned before it was answered, so the first waiter to get here
+             * sends it again, and the rest wait for that.
+             */
+        };
+
+        /* This is dropped before tx, so waiters that see tx has gone won't find its entry */
+        let _guard = InFlightGuard {
+            in_flight: &self.in_flight,
+            ck: &ck,
+        };
+        let result = self.refresh(msg, ck.clone(), stale, servers).await;
+        /* Nobody may be waiting, which is fine */
+        let _ = tx.send(Some(clone_out_reply(&result)));
+        result
+    }
+
+    async fn mark_refresh_failed(&self, ck: &CacheKey) {
+        if let Some(entry) = self.cache.write().await.get_mut(ck) {
+            entry.refresh_failed = Some(Instant::now());
         }
+    }
+
+    /* Asks the upstream, and caches the result.  If the upstream fails, and we have a stale entry,
+     * then the stale entry is used instead.
+     */
+    async fn refresh(
+        &self,
+        msg: &super::DnsMessage,
+        ck: CacheKey,
+        stale: Option<(CacheKey, dnspkt::DNSPkt)>,
+        servers: &[outquery::Nameserver],
+    ) -> Result<dnspkt::DNSPkt, Error> {
+        let out_result = self.next.handle_query(msg, servers).await;
 
         match &out_result {
             Ok(x) => log::trace!("[{:x}] OutReply: {:?}", msg.in_query.qid, x),
             Err(e) => log::trace!("[{:x}] OutReply: {}", msg.in_query.qid, e),
         };
 
+        if let Some((stale_ck, reply)) = &stale {
+            if is_upstream_failure(&out_result) {
+                log::debug!(
+                    "[{:x}] Upstream failed, answering from expired cache",
+                    msg.in_query.qid
+                );
+                DNS_CACHE.with_label_values(&["STALE"]).inc();
+                self.mark_refresh_failed(stale_ck).await;
+                return Ok(stale_reply(reply));
+            }
+        }
+
+        self.cache_result(
+            ck,
+            &out_result,
+            stale.as_ref().map(|(stale_ck, _)| stale_ck),
+        )
+        .await;
+
         out_result
     }
+
+    /* Refreshes an entry that hasn't expired yet. */
+    async fn prefetch(
+        &self,
+        msg: &super::DnsMessage,
+        ck: CacheKey,
+        old_ck: CacheKey,
+        servers: &[outquery::Nameserver],
+    ) {
+        let out_result = self.next.handle_query(msg, servers).await;
+
+        /* The old entry is still good, so keep using it until it expires, and let a later query
+         * try refreshing it again.
+         */
+        if is_upstream_failure(&out_result) {
+            log::debug!(
+                "[{:x}] Upstream failed, not refreshing cache entry",
+                msg.in_query.qid
+            );
+            if let Some(entry) = self.cache.read().await.get(&old_ck) {
+                entry.prefetching.store(false, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        self.cache_result(ck, &out_result, Some(&old_ck)).await;
+    }
+
+    async fn cache_result(
+        &self,
+        ck: CacheKey,
+        out_result: &Result<dnspkt::DNSPkt, Error>,
+        replaced: Option<&CacheKey>,
+    ) {
+        let expiry = self.calculate_expiry(out_result);
+        let mut rwcache = self.cache.write().await;
+
+        /* The upstream has answered, so the old entry has been replaced, even if the new answer
+         * is for a different key (eg the name used to be NXDOMAIN).
+         */
+        if let Some(replaced) = replaced {
+            rwcache.remove(replaced);
+        }
+
+        /* Only insert into the cache if the duration is reasonable */
+        if expiry > Duration::from_secs(0) {
+            let ck = match out_result {
+                Ok(reply) if is_name_error(reply) => CacheKey { qtype: None, ..ck },
+                _ => ck,
+            };
+            self.insert_cache_entry(&mut rwcache, ck, out_result, expiry);
+        }
+    }
 }
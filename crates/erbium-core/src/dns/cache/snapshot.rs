@@ -0,0 +1,173 @@
+/*   Copyright 2024 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Saving the cache to disk, so it survives restarts.
+ *
+ *  The snapshot is a header, followed by one record for each cached answer:
+ *    u8 flags (see below)
+ *    u16 length of the view's name, followed by the name (only if FLAG_VIEW is set)
+ *    u64 when the answer was received, in seconds since the unix epoch
+ *    u64 how long the answer is cached for, in seconds
+ *    u32 length of the answer, followed by the answer in wire format
+ *  All integers are in network byte order.  The query name and type come from the question in the
+ *  answer.
+ *
+ *  Instants can't be saved, as they are meaningless after a reboot, so the times are converted to
+ *  wall clock time.
+ */
+
+use super::{Cache, CacheKey, CacheValue};
+use crate::dns::dnspkt;
+use crate::dns::parse;
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+
+const HEADER: &[u8] = b"erbium dns cache 1\n";
+
+const FLAG_EDNS_DO: u8 = 0b0000_0001;
+const FLAG_CD: u8 = 0b0000_0010;
+/* NXDOMAIN answers are cached for all types */
+const FLAG_QTYPE: u8 = 0b0000_0100;
+const FLAG_VIEW: u8 = 0b0000_1000;
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub(super) fn serialise(cache: &Cache, now: Instant, wall_now: SystemTime) -> Vec<u8> {
+    let mut ret = HEADER.to_vec();
+    for (ck, v) in &cache.entries {
+        /* Errors are only cached briefly, and are probably different after a restart anyway */
+        let Ok(reply) = &v.reply else {
+            continue;
+        };
+        let birth = unix_time(wall_now).saturating_sub((now - v.birth).as_secs());
+        let pkt = reply.serialise();
+
+        let mut flags = 0;
+        if ck.edns_do {
+            flags |= FLAG_EDNS_DO;
+        }
+        if ck.cd {
+            flags |= FLAG_CD;
+        }
+        if ck.qtype.is_some() {
+            flags |= FLAG_QTYPE;
+        }
+        if ck.view.is_some() {
+            flags |= FLAG_VIEW;
+        }
+        ret.push(flags);
+        if let Some(view) = &ck.view {
+            ret.extend((view.len() as u16).to_be_bytes());
+            ret.extend(view.as_bytes());
+        }
+        ret.extend(birth.to_be_bytes());
+        ret.extend(v.lifetime.as_secs().to_be_bytes());
+        ret.extend((pkt.len() as u32).to_be_bytes());
+        ret.extend(pkt);
+    }
+    ret
+}
+
+struct Reader<'l> {
+    buffer: &'l [u8],
+}
+
+impl<'l> Reader<'l> {
+    fn get_bytes(&mut self, len: usize) -> Result<&'l [u8], String> {
+        if self.buffer.len() < len {
+            return Err("Truncated snapshot".into());
+        }
+        let (bytes, rest) = self.buffer.split_at(len);
+        self.buffer = rest;
+        Ok(bytes)
+    }
+
+    fn get_u8(&mut self) -> Result<u8, String> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.get_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn get_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.get_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn get_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.get_bytes(8)?.try_into().unwrap()))
+    }
+}
+
+/* Returns the entries that haven't expired (or can still be served stale). */
+pub(super) fn deserialise(
+    data: &[u8],
+    now: Instant,
+    wall_now: SystemTime,
+    serve_stale: Duration,
+) -> Result<Vec<(CacheKey, CacheValue)>, String> {
+    let Some(data) = data.strip_prefix(HEADER) else {
+        return Err("Not a DNS cache snapshot".into());
+    };
+    let mut reader = Reader { buffer: data };
+    let mut ret = vec![];
+    while !reader.buffer.is_empty() {
+        let flags = reader.get_u8()?;
+        let view = if flags & FLAG_VIEW != 0 {
+            let len = reader.get_u16()?.into();
+            Some(
+                String::from_utf8(reader.get_bytes(len)?.to_vec())
+                    .map_err(|e| format!("Bad view name: {}", e))?,
+            )
+        } else {
+            None
+        };
+        let birth = reader.get_u64()?;
+        let lifetime = Duration::from_secs(reader.get_u64()?);
+        let len = reader.get_u32()? as usize;
+        let reply: dnspkt::DNSPkt = parse::PktParser::new(reader.get_bytes(len)?).get_dns()?;
+
+        /* If the clock has gone backwards, treat the answer as brand new */
+        let age = Duration::from_secs(unix_time(wall_now).saturating_sub(birth));
+        if age > lifetime + serve_stale {
+            continue;
+        }
+        let Some(birth) = now.checked_sub(age) else {
+            continue;
+        };
+        let ck = CacheKey {
+            view,
+            qname: reply.question.qdomain.clone(),
+            qtype: (flags & FLAG_QTYPE != 0).then_some(reply.question.qtype),
+            edns_do: flags & FLAG_EDNS_DO != 0,
+            cd: flags & FLAG_CD != 0,
+        };
+        ret.push((ck, CacheValue::new(Ok(reply), birth, lifetime)));
+    }
+    Ok(ret)
+}
+
+/* Writes to a temporary file first, so a crash while saving doesn't lose the old snapshot. */
+pub(super) async fn save(path: &std::path::Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
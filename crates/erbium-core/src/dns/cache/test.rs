@@ -19,19 +19,27 @@
 
 use super::*;
 use crate::dns::dnspkt::*;
+use crate::dns::DnsMessage;
 
-#[tokio::test]
-async fn test_expiry() {
-    let handler = CacheHandler {
+fn test_handler(config: super::super::config::CacheConfig) -> CacheHandler {
+    CacheHandler {
         next: outquery::OutQuery::new(),
         cache: Arc::new(RwLock::new(Cache::new())),
-    };
+        in_flight: Default::default(),
+        config,
+    }
+}
+
+#[tokio::test]
+async fn test_expiry() {
+    let handler = test_handler(Default::default());
 
     let example_net: dnspkt::Domain = "example.net".parse().unwrap();
 
     let ck = CacheKey {
+        view: None,
         qname: example_net.clone(),
-        qtype: RR_A,
+        qtype: Some(RR_A),
         edns_do: false,
         cd: false,
     };
@@ -96,7 +104,7 @@ async fn test_expiry() {
     now += Duration::from_secs(60);
     {
         let mut rwcache = handler.cache.write().await;
-        let next = CacheHandler::expire(&mut rwcache, now);
+        let next = CacheHandler::expire(&mut rwcache, now, Duration::ZERO);
         assert_eq!(rwcache.len(), 1);
         assert!(CacheHandler::get_entry(&rwcache, &ck, now).is_some());
         assert!(next < now + Duration::from_secs(1800)); // We have an entry that is newer than that.
@@ -114,9 +122,820 @@ async fn test_expiry() {
     now += Duration::from_secs(3600);
     {
         let mut rwcache = handler.cache.write().await;
-        let next = CacheHandler::expire(&mut rwcache, now);
+        let next = CacheHandler::expire(&mut rwcache, now, Duration::ZERO);
         assert!(CacheHandler::get_entry(&rwcache, &ck, now).is_none());
         assert_eq!(rwcache.len(), 0);
         assert!(next >= now + Duration::from_secs(1800)); // There are no entries left, so re-run infrequently.
     }
 }
+
+fn negative_reply(qname: &str, rcode: dnspkt::RCode, soa_ttl: u32, minimum: u32) -> dnspkt::DNSPkt {
+    let origin: dnspkt::Domain = "example.net".parse().unwrap();
+    dnspkt::DNSPkt {
+        qid: 1,
+        rd: true,
+        tc: false,
+        aa: false,
+        qr: true,
+        opcode: dnspkt::OPCODE_QUERY,
+        cd: false,
+        ad: false,
+        ra: false,
+        rcode,
+        bufsize: 512,
+        edns_ver: Some(0),
+        edns_do: false,
+        question: dnspkt::Question {
+            qdomain: qname.parse().unwrap(),
+            qtype: RR_A,
+            qclass: CLASS_IN,
+        },
+        answer: vec![],
+        nameserver: vec![dnspkt::RR {
+            domain: origin.clone(),
+            class: CLASS_IN,
+            rrtype: RR_SOA,
+            ttl: soa_ttl,
+            rdata: dnspkt::RData::Soa(dnspkt::SoaData {
+                mname: origin.clone(),
+                rname: origin,
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum,
+            }),
+        }],
+        additional: vec![],
+        edns: None,
+    }
+}
+
+#[tokio::test]
+async fn test_negative_expiry() {
+    let handler = test_handler(Default::default());
+
+    /* The lesser of the SOA's TTL and minimum is used */
+    let nxdomain = negative_reply("nx.example.net", NXDOMAIN, 900, 300);
+    assert_eq!(
+        handler.calculate_expiry(&Ok(nxdomain.clone())),
+        Duration::from_secs(300)
+    );
+    let nodata = negative_reply("example.net", NOERROR, 60, 300);
+    assert_eq!(
+        handler.calculate_expiry(&Ok(nodata.clone())),
+        Duration::from_secs(60)
+    );
+
+    /* A CNAME to a name that doesn't exist can't be cached for longer than the CNAME */
+    let mut cname = nxdomain.clone();
+    cname.answer.push(dnspkt::RR {
+        domain: "nx.example.net".parse().unwrap(),
+        class: CLASS_IN,
+        rrtype: RR_CNAME,
+        ttl: 30,
+        rdata: dnspkt::RData::CName("nx2.example.net".parse().unwrap()),
+    });
+    assert_eq!(
+        handler.calculate_expiry(&Ok(cname)),
+        Duration::from_secs(30)
+    );
+
+    /* Negative answers without a SOA aren't cached */
+    let mut no_soa = nxdomain.clone();
+    no_soa.nameserver.clear();
+    assert_eq!(
+        handler.calculate_expiry(&Ok(no_soa)),
+        Duration::from_secs(0)
+    );
+
+    /* And are capped to max-negative-ttl */
+    let handler = CacheHandler {
+        config: super::super::config::CacheConfig {
+            max_negative_ttl: Duration::from_secs(10),
+            ..Default::default()
+        },
+        ..handler
+    };
+    assert_eq!(
+        handler.calculate_expiry(&Ok(nxdomain.clone())),
+        Duration::from_secs(10)
+    );
+
+    /* The SOA's TTL is reduced to match how long it's cached for */
+    {
+        let mut rwcache = handler.cache.write().await;
+        let ck = CacheKey {
+            view: None,
+            qname: "nx.example.net".parse().unwrap(),
+            qtype: None,
+            edns_do: false,
+            cd: false,
+        };
+        handler.insert_cache_entry(
+            &mut rwcache,
+            ck.clone(),
+            &Ok(nxdomain),
+            Duration::from_secs(10),
+        );
+        let cached = CacheHandler::get_entry(&rwcache, &ck, Instant::now())
+            .unwrap()
+            .unwrap();
+        assert!(cached.nameserver[0].ttl <= 10);
+    }
+}
+
+#[tokio::test]
+async fn test_nxdomain_covers_all_types() {
+    let mut handler = test_handler(Default::default());
+    let key = |name: &str, qtype| CacheKey {
+        view: None,
+        qname: name.parse().unwrap(),
+        qtype,
+        edns_do: false,
+        cd: false,
+    };
+
+    {
+        let mut rwcache = handler.cache.write().await;
+        handler.insert_cache_entry(
+            &mut rwcache,
+            key("nx.example.net", None),
+            &Ok(negative_reply("nx.example.net", NXDOMAIN, 300, 300)),
+            Duration::from_secs(300),
+        );
+    }
+
+    let now = Instant::now();
+    let rocache = handler.cache.read().await;
+    /* The NXDOMAIN is used for every type */
+    for qtype in [RR_A, RR_AAAA, RR_MX] {
+        let cks = handler.candidate_keys(&key("nx.example.net", Some(qtype)));
+        let reply = CacheHandler::get_first_entry(&rocache, &cks, now).unwrap();
+        assert_eq!(reply.unwrap().rcode, NXDOMAIN);
+    }
+    /* But not for names below it, unless nxdomain-cut is enabled */
+    let cks = handler.candidate_keys(&key("www.nx.example.net", Some(RR_A)));
+    assert!(CacheHandler::get_first_entry(&rocache, &cks, now).is_none());
+    drop(rocache);
+
+    handler.config.nxdomain_cut = true;
+    let rocache = handler.cache.read().await;
+    let cks = handler.candidate_keys(&key("www.nx.example.net", Some(RR_A)));
+    let reply = CacheHandler::get_first_entry(&rocache, &cks, now).unwrap();
+    assert_eq!(reply.unwrap().rcode, NXDOMAIN);
+    /* Other names in the parent zone aren't affected */
+    let cks = handler.candidate_keys(&key("www.example.net", Some(RR_A)));
+    assert!(CacheHandler::get_first_entry(&rocache, &cks, now).is_none());
+}
+
+#[tokio::test]
+async fn test_nxdomain_after_cname() {
+    let handler = test_handler(super::super::config::CacheConfig {
+        nxdomain_cut: true,
+        ..Default::default()
+    });
+    let key = |name: &str, qtype| CacheKey {
+        view: None,
+        qname: name.parse().unwrap(),
+        qtype,
+        edns_do: false,
+        cd: false,
+    };
+
+    /* alias.example.net exists, it's the target of the CNAME that doesn't */
+    let mut reply = negative_reply("alias.example.net", NXDOMAIN, 300, 300);
+    reply.answer.push(dnspkt::RR {
+        domain: "alias.example.net".parse().unwrap(),
+        class: CLASS_IN,
+        rrtype: RR_CNAME,
+        ttl: 300,
+        rdata: dnspkt::RData::CName("nx.example.net".parse().unwrap()),
+    });
+    handler
+        .cache_result(key("alias.example.net", Some(RR_A)), &Ok(reply), None)
+        .await;
+
+    let now = Instant::now();
+    let rocache = handler.cache.read().await;
+    let cks = handler.candidate_keys(&key("alias.example.net", Some(RR_A)));
+    let cached = CacheHandler::get_first_entry(&rocache, &cks, now).unwrap();
+    assert_eq!(cached.unwrap().rcode, NXDOMAIN);
+    /* So the NXDOMAIN doesn't answer other types, or names below it */
+    let cks = handler.candidate_keys(&key("alias.example.net", Some(RR_CNAME)));
+    assert!(CacheHandler::get_first_entry(&rocache, &cks, now).is_none());
+    let cks = handler.candidate_keys(&key("www.alias.example.net", Some(RR_A)));
+    assert!(CacheHandler::get_first_entry(&rocache, &cks, now).is_none());
+}
+
+#[tokio::test]
+async fn test_serve_stale() {
+    let handler = test_handler(super::super::config::CacheConfig {
+        serve_stale: Duration::from_secs(3600),
+        ..Default::default()
+    });
+    let key = |name: &str, qtype| CacheKey {
+        view: None,
+        qname: name.parse().unwrap(),
+        qtype,
+        edns_do: false,
+        cd: false,
+    };
+    let mut answer = negative_reply("example.net", NOERROR, 600, 600);
+    answer.nameserver.clear();
+    answer.answer.push(dnspkt::RR {
+        domain: "example.net".parse().unwrap(),
+        class: CLASS_IN,
+        rrtype: RR_A,
+        ttl: 10,
+        rdata: dnspkt::RData::Other(vec![192, 0, 2, 1]),
+    });
+
+    /* Add entries that expired 10s ago */
+    let birth = Instant::now() - Duration::from_secs(20);
+    {
+        let mut rwcache = handler.cache.write().await;
+        rwcache.insert(
+            key("example.net", Some(RR_A)),
+            CacheValue::new(Ok(answer), birth, Duration::from_secs(10)),
+        );
+        rwcache.insert(
+            key("nx.example.net", None),
+            CacheValue::new(
+                Ok(negative_reply("nx.example.net", NXDOMAIN, 10, 10)),
+                birth,
+                Duration::from_secs(10),
+            ),
+        );
+    }
+
+    /* There are no nameservers, so the upstream fails, and the stale entry is used */
+    let reply = handler
+        .handle_query(&DnsMessage::new_for_test("example.net", RR_A), None, &[])
+        .await
+        .unwrap();
+    assert_eq!(reply.answer[0].ttl, STALE_TTL);
+    assert_eq!(
+        reply.edns.unwrap().get_extended_dns_error().unwrap().0,
+        EDE_STALE_ANSWER
+    );
+    assert!(handler
+        .cache
+        .read()
+        .await
+        .get(&key("example.net", Some(RR_A)))
+        .unwrap()
+        .refresh_failed
+        .is_some());
+
+    /* Now the upstream has failed, the stale entry keeps being used */
+    let reply = handler
+        .handle_query(&DnsMessage::new_for_test("example.net", RR_A), None, &[])
+        .await
+        .unwrap();
+    assert_eq!(reply.answer.len(), 1);
+
+    let reply = handler
+        .handle_query(
+            &DnsMessage::new_for_test("nx.example.net", RR_AAAA),
+            None,
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(reply.rcode, NXDOMAIN);
+    assert_eq!(
+        reply.edns.unwrap().get_extended_dns_error().unwrap().0,
+        EDE_STALE_NXDOMAIN
+    );
+
+    /* Stale entries are kept until the stale window has passed */
+    let now = Instant::now();
+    {
+        let mut rwcache = handler.cache.write().await;
+        CacheHandler::expire(&mut rwcache, now, handler.config.serve_stale);
+        assert_eq!(rwcache.len(), 2);
+        CacheHandler::expire(
+            &mut rwcache,
+            now + Duration::from_secs(3600),
+            handler.config.serve_stale,
+        );
+        assert_eq!(rwcache.len(), 0);
+    }
+
+    /* Without serve-stale, the failure is returned */
+    let handler = CacheHandler {
+        config: Default::default(),
+        ..handler
+    };
+    {
+        let mut rwcache = handler.cache.write().await;
+        rwcache.insert(
+            key("example.net", Some(RR_A)),
+            CacheValue::new(
+                Ok(negative_reply("example.net", NOERROR, 10, 10)),
+                birth,
+                Duration::from_secs(10),
+            ),
+        );
+    }
+    assert!(handler
+        .handle_query(&DnsMessage::new_for_test("example.net", RR_A), None, &[])
+        .await
+        .is_err());
+}
+
+fn a_reply(qname: &str, records: u8) -> Result<dnspkt::DNSPkt, Error> {
+    let mut reply = negative_reply(qname, NOERROR, 600, 600);
+    reply.nameserver.clear();
+    for i in 0..records {
+        reply.answer.push(dnspkt::RR {
+            domain: qname.parse().unwrap(),
+            class: CLASS_IN,
+            rrtype: RR_A,
+            ttl: 600,
+            rdata: dnspkt::RData::Other(vec![192, 0, 2, i]),
+        });
+    }
+    Ok(reply)
+}
+
+fn a_key(qname: &str) -> CacheKey {
+    CacheKey {
+        view: None,
+        qname: qname.parse().unwrap(),
+        qtype: Some(RR_A),
+        edns_do: false,
+        cd: false,
+    }
+}
+
+#[tokio::test]
+async fn test_max_entries() {
+    let handler = test_handler(super::super::config::CacheConfig {
+        max_entries: 100,
+        ..Default::default()
+    });
+    let hot = a_key("hot.example.net");
+    let mut rwcache = handler.cache.write().await;
+    handler.insert_cache_entry(
+        &mut rwcache,
+        hot.clone(),
+        &a_reply("hot.example.net", 1),
+        Duration::from_secs(600),
+    );
+
+    /* A client walking random subdomains can't grow the cache past the limit, or push out names
+     * that are being used.
+     */
+    for i in 0..10000 {
+        let name = format!("{}.random.example.net", i);
+        handler.insert_cache_entry(
+            &mut rwcache,
+            a_key(&name),
+            &a_reply(&name, 1),
+            Duration::from_secs(600),
+        );
+        assert!(rwcache.len() <= 100);
+        if i % 50 == 0 {
+            assert!(CacheHandler::get_entry(&rwcache, &hot, Instant::now()).is_some());
+        }
+    }
+    assert!(CacheHandler::get_entry(&rwcache, &hot, Instant::now()).is_some());
+
+    /* The least recently added names were evicted, the most recent ones are still there */
+    assert!(
+        CacheHandler::get_entry(&rwcache, &a_key("0.random.example.net"), Instant::now()).is_none()
+    );
+    assert!(
+        CacheHandler::get_entry(&rwcache, &a_key("9999.random.example.net"), Instant::now())
+            .is_some()
+    );
+}
+
+#[tokio::test]
+async fn test_max_size() {
+    const MAX_SIZE: usize = 64 * 1024;
+    let handler = test_handler(super::super::config::CacheConfig {
+        max_size: Some(MAX_SIZE),
+        ..Default::default()
+    });
+    let mut rwcache = handler.cache.write().await;
+
+    /* Large replies are limited by size, well before the entry limit is reached */
+    for i in 0..1000 {
+        let name = format!("{}.large.example.net", i);
+        handler.insert_cache_entry(
+            &mut rwcache,
+            a_key(&name),
+            &a_reply(&name, 100),
+            Duration::from_secs(600),
+        );
+        assert!(rwcache.bytes <= MAX_SIZE);
+    }
+    assert!(rwcache.len() < 1000);
+
+    /* Replacing, removing and expiring entries keeps the accounting correct */
+    handler.insert_cache_entry(
+        &mut rwcache,
+        a_key("999.large.example.net"),
+        &a_reply("999.large.example.net", 1),
+        Duration::from_secs(1),
+    );
+    rwcache.remove(&a_key("998.large.example.net"));
+    CacheHandler::expire(
+        &mut rwcache,
+        Instant::now() + Duration::from_secs(60),
+        Duration::ZERO,
+    );
+    assert_eq!(
+        rwcache.bytes,
+        rwcache.entries.values().map(|v| v.size).sum::<usize>()
+    );
+    assert!(rwcache.get(&a_key("999.large.example.net")).is_none());
+
+    /* Everything expires eventually, and then the cache is empty */
+    CacheHandler::expire(
+        &mut rwcache,
+        Instant::now() + Duration::from_secs(3600),
+        Duration::ZERO,
+    );
+    assert_eq!(rwcache.len(), 0);
+    assert_eq!(rwcache.bytes, 0);
+}
+
+#[tokio::test]
+async fn test_prefetch() {
+    let handler = test_handler(Default::default());
+    let popular = a_key("popular.example.net");
+    let fresh = a_key("fresh.example.net");
+    {
+        let mut rwcache = handler.cache.write().await;
+        /* This entry is in the last 10% of its lifetime */
+        rwcache.insert(
+            popular.clone(),
+            CacheValue::new(
+                a_reply("popular.example.net", 1),
+                Instant::now() - Duration::from_secs(95),
+                Duration::from_secs(100),
+            ),
+        );
+        rwcache.insert(
+            fresh.clone(),
+            CacheValue::new(
+                a_reply("fresh.example.net", 1),
+                Instant::now(),
+                Duration::from_secs(100),
+            ),
+        );
+    }
+
+    /* Entries that haven't been used much aren't refreshed */
+    for _ in 0..PREFETCH_MIN_HITS - 1 {
+        handler
+            .handle_query(
+                &DnsMessage::new_for_test("popular.example.net", RR_A),
+                None,
+                &[],
+            )
+            .await
+            .unwrap();
+    }
+    assert!(!handler
+        .cache
+        .read()
+        .await
+        .get(&popular)
+        .unwrap()
+        .prefetching
+        .load(Ordering::Relaxed));
+
+    /* Once it's popular, it's refreshed, but only once */
+    handler
+        .handle_query(
+            &DnsMessage::new_for_test("popular.example.net", RR_A),
+            None,
+            &[],
+        )
+        .await
+        .unwrap();
+    {
+        let rocache = handler.cache.read().await;
+        assert!(rocache
+            .get(&popular)
+            .unwrap()
+            .prefetching
+            .load(Ordering::Relaxed));
+        assert!(handler
+            .claim_prefetch(&rocache, &popular, Instant::now())
+            .is_none());
+    }
+
+    /* There are no nameservers so the refresh fails, but the entry can still be used, and can
+     * be refreshed again.
+     */
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!handler
+        .cache
+        .read()
+        .await
+        .get(&popular)
+        .unwrap()
+        .prefetching
+        .load(Ordering::Relaxed));
+    let reply = handler
+        .handle_query(
+            &DnsMessage::new_for_test("popular.example.net", RR_A),
+            None,
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(reply.answer.len(), 1);
+
+    /* Entries that aren't about to expire aren't refreshed, however popular they are */
+    for _ in 0..10 {
+        handler
+            .handle_query(
+                &DnsMessage::new_for_test("fresh.example.net", RR_A),
+                None,
+                &[],
+            )
+            .await
+            .unwrap();
+    }
+    assert!(!handler
+        .cache
+        .read()
+        .await
+        .get(&fresh)
+        .unwrap()
+        .prefetching
+        .load(Ordering::Relaxed));
+
+    /* Nothing is refreshed when prefetching is disabled */
+    let handler = CacheHandler {
+        config: super::super::config::CacheConfig {
+            prefetch: false,
+            ..Default::default()
+        },
+        ..handler
+    };
+    {
+        let mut rwcache = handler.cache.write().await;
+        rwcache.insert(
+            popular.clone(),
+            CacheValue::new(
+                a_reply("popular.example.net", 1),
+                Instant::now() - Duration::from_secs(95),
+                Duration::from_secs(100),
+            ),
+        );
+    }
+    for _ in 0..10 {
+        handler
+            .handle_query(
+                &DnsMessage::new_for_test("popular.example.net", RR_A),
+                None,
+                &[],
+            )
+            .await
+            .unwrap();
+    }
+    assert!(!handler
+        .cache
+        .read()
+        .await
+        .get(&popular)
+        .unwrap()
+        .prefetching
+        .load(Ordering::Relaxed));
+}
+
+/// Starts a nameserver on 127.0.0.1 that answers each query once a permit is added to the returned
+/// semaphore.  Returns the address it's listening on, the semaphore, and a count of the queries it
+/// has received.
+async fn start_held_nameserver() -> (
+    std::net::SocketAddr,
+    Arc<tokio::sync::Semaphore>,
+    Arc<std::sync::atomic::AtomicUsize>,
+) {
+    let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = sock.local_addr().unwrap();
+    let release = Arc::new(tokio::sync::Semaphore::new(0));
+    let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let (permits, count) = (release.clone(), queries.clone());
+    tokio::spawn(async move {
+        let mut buf = [0u8; 65536];
+        while let Ok((len, from)) = sock.recv_from(&mut buf).await {
+            count.fetch_add(1, Ordering::SeqCst);
+            let mut reply = crate::dns::parse::PktParser::new(&buf[..len])
+                .get_dns()
+                .unwrap();
+            permits.acquire().await.unwrap().forget();
+            reply.qr = true;
+            /* A TTL of 0 means the reply isn't cached */
+            reply.answer.push(dnspkt::RR {
+                domain: reply.question.qdomain.clone(),
+                class: CLASS_IN,
+                rrtype: RR_A,
+                ttl: 0,
+                rdata: dnspkt::RData::Other(vec![192, 0, 2, 1]),
+            });
+            sock.send_to(&reply.serialise(), from).await.unwrap();
+        }
+    });
+    (addr, release, queries)
+}
+
+/// Runs fut until done() is true, without letting it finish.
+async fn run_until<F: std::future::Future + Unpin>(fut: &mut F, done: impl Fn() -> bool) {
+    while !done() {
+        tokio::select! {
+            _ = &mut *fut => panic!("Finished early"),
+            _ = tokio::task::yield_now() => (),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_coalesce() {
+    let (addr, release, queries) = start_held_nameserver().await;
+    let servers = [outquery::Nameserver::Plain(addr)];
+    let handler = test_handler(Default::default());
+    let msg = DnsMessage::new_for_test("example.net", RR_A);
+    let sent = || queries.load(Ordering::SeqCst);
+    let coalesced = DNS_CACHE_COALESCED.get();
+    let waiting = || DNS_CACHE_COALESCED.get() - coalesced;
+
+    /* Lots of clients asking the same question at once only send one query upstream */
+    let mut replies = Box::pin(futures::future::join_all(
+        (0..10).map(|_| handler.handle_query(&msg, None, &servers)),
+    ));
+    run_until(&mut replies, || sent() == 1 && waiting() == 9).await;
+    release.add_permits(1);
+    for reply in replies.await {
+        assert_eq!(reply.unwrap().answer.len(), 1);
+    }
+    assert_eq!(sent(), 1);
+    assert!(handler.in_flight.lock().unwrap().is_empty());
+
+    /* Once it's answered, the next query is sent upstream again */
+    release.add_permits(1);
+    handler.handle_query(&msg, None, &servers).await.unwrap();
+    assert_eq!(sent(), 2);
+
+    /* If the query that was sent is abandoned, only one of the clients waiting for it sends it
+     * again.
+     */
+    let mut first = Box::pin(handler.handle_query(&msg, None, &servers));
+    run_until(&mut first, || sent() == 3).await;
+    let mut replies = Box::pin(futures::future::join_all(
+        (0..5).map(|_| handler.handle_query(&msg, None, &servers)),
+    ));
+    run_until(&mut replies, || waiting() == 9 + 5).await;
+    drop(first);
+    release.add_permits(1); /* For the abandoned query */
+    run_until(&mut replies, || sent() == 4).await;
+    release.add_permits(10);
+    for reply in replies.await {
+        assert_eq!(reply.unwrap().answer.len(), 1);
+    }
+    assert_eq!(sent(), 4);
+}
+
+#[test]
+fn test_snapshot_serialisation() {
+    let now = Instant::now();
+    let wall_now = std::time::SystemTime::now();
+    let guests_key = CacheKey {
+        view: Some("guests".into()),
+        ..a_key("example.net")
+    };
+    let nx_key = CacheKey {
+        view: None,
+        qname: "nx.example.net".parse().unwrap(),
+        qtype: None,
+        edns_do: true,
+        cd: true,
+    };
+    let expired_key = a_key("expired.example.net");
+    let mut cache = Cache::new();
+    cache.insert(
+        guests_key.clone(),
+        CacheValue::new(
+            a_reply("example.net", 2),
+            now - Duration::from_secs(100),
+            Duration::from_secs(600),
+        ),
+    );
+    cache.insert(
+        nx_key.clone(),
+        CacheValue::new(
+            Ok(negative_reply("nx.example.net", NXDOMAIN, 300, 300)),
+            now,
+            Duration::from_secs(300),
+        ),
+    );
+    cache.insert(
+        expired_key.clone(),
+        CacheValue::new(
+            a_reply("expired.example.net", 1),
+            now - Duration::from_secs(20),
+            Duration::from_secs(10),
+        ),
+    );
+    /* Errors aren't saved */
+    cache.insert(
+        a_key("error.example.net"),
+        CacheValue::new(
+            Err(Error::OutReply(outquery::Error::Timeout)),
+            now,
+            Duration::from_secs(8),
+        ),
+    );
+    let data = snapshot::serialise(&cache, now, wall_now);
+
+    /* Reload it a minute later, eg after a reboot */
+    let later = Instant::now();
+    let entries = snapshot::deserialise(
+        &data,
+        later,
+        wall_now + Duration::from_secs(60),
+        Duration::ZERO,
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 2);
+    let mut reloaded = Cache::new();
+    for (ck, value) in entries {
+        reloaded.insert(ck, value);
+    }
+    let reply = CacheHandler::get_entry(&reloaded, &guests_key, later)
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply.answer.len(), 2);
+    /* It's 160s older than when it was received */
+    assert_eq!(reply.answer[0].ttl, 440);
+    let reply = CacheHandler::get_entry(&reloaded, &nx_key, later)
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply.rcode, NXDOMAIN);
+    assert!(reloaded.get(&expired_key).is_none());
+
+    /* Expired entries are kept if they can be served stale */
+    let entries = snapshot::deserialise(
+        &data,
+        later,
+        wall_now + Duration::from_secs(60),
+        Duration::from_secs(3600),
+    )
+    .unwrap();
+    assert!(entries.iter().any(|(ck, _)| *ck == expired_key));
+
+    /* Everything has expired after a long time */
+    assert!(snapshot::deserialise(
+        &data,
+        later,
+        wall_now + Duration::from_secs(86400),
+        Duration::ZERO
+    )
+    .unwrap()
+    .is_empty());
+
+    /* Damaged snapshots are rejected */
+    assert!(
+        snapshot::deserialise(&data[..data.len() - 1], later, wall_now, Duration::ZERO).is_err()
+    );
+    assert!(snapshot::deserialise(b"not a snapshot", later, wall_now, Duration::ZERO).is_err());
+}
+
+#[tokio::test]
+async fn test_snapshot_file() {
+    let path = crate::test_util::TempPath::new("dns-cache");
+    let config = super::super::config::CacheConfig {
+        snapshot_file: Some(path.to_path_buf()),
+        ..Default::default()
+    };
+
+    /* There's no snapshot the first time */
+    let handler = CacheHandler::new(config.clone()).await;
+    assert_eq!(handler.cache.read().await.len(), 0);
+    {
+        let mut rwcache = handler.cache.write().await;
+        handler.insert_cache_entry(
+            &mut rwcache,
+            a_key("example.net"),
+            &a_reply("example.net", 1),
+            Duration::from_secs(600),
+        );
+    }
+    handler.shutdown().await;
+
+    /* After a restart, the entries are loaded from the snapshot */
+    let handler = CacheHandler::new(config).await;
+    let reply = handler
+        .handle_query(&DnsMessage::new_for_test("example.net", RR_A), None, &[])
+        .await
+        .unwrap();
+    assert_eq!(reply.answer.len(), 1);
+}
@@ -22,13 +22,22 @@ use yaml_rust::yaml;
 
 #[derive(Debug)]
 pub enum Handler {
-    Forward(Vec<std::net::SocketAddr>),
+    Forward(Vec<super::outquery::Nameserver>),
     ForgeNxDomain,
+    #[cfg(feature = "dhcp")]
+    DhcpLeases,
+    Zones(Vec<super::zone::Zone>),
+    Blocklist(super::blocklist::Blocklist),
 }
 
 enum HandlerType {
     Forward,
     ForgeNxDomain,
+    #[cfg(feature = "dhcp")]
+    DhcpLeases,
+    Static,
+    Zone,
+    Blocklist,
 }
 
 #[derive(Debug)]
@@ -37,20 +46,122 @@ pub struct Route {
     pub dest: Handler,
 }
 
+fn parse_nameserver(
+    name: &str,
+    fragment: &yaml::Yaml,
+) -> Result<Option<super::outquery::Nameserver>, Error> {
+    parse_string(name, fragment)?
+        .map(|s| {
+            s.parse()
+                .map_err(|e| Error::InvalidConfig(format!("{}: {}", name, e)))
+        })
+        .transpose()
+}
+
+fn parse_dns_record(name: &str, fragment: &yaml::Yaml) -> Result<Option<super::dnspkt::RR>, Error> {
+    use super::{dnspkt, zone};
+    if let Some(h) = fragment.as_hash() {
+        let mut rrname = None;
+        let mut rrtype = None;
+        let mut value = None;
+        let mut ttl = None;
+        for (k, v) in h {
+            match k.as_str() {
+                Some("name") => rrname = parse_string("name", v)?,
+                Some("type") => rrtype = parse_string("type", v)?,
+                Some("value") => value = parse_string("value", v)?,
+                Some("ttl") => ttl = parse_num("ttl", v)?,
+                Some(opt) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Unknown {} keyword {}",
+                        name, opt
+                    )))
+                }
+                None => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Expected string in {}, not {:?}",
+                        name, k
+                    )))
+                }
+            }
+        }
+        let rrname =
+            rrname.ok_or_else(|| Error::InvalidConfig(format!("{} is missing a name", name)))?;
+        let rrtype = rrtype.ok_or_else(|| {
+            Error::InvalidConfig(format!("{} {} is missing a type", name, rrname))
+        })?;
+        let value = value.ok_or_else(|| {
+            Error::InvalidConfig(format!("{} {} is missing a value", name, rrname))
+        })?;
+        let rrtype = zone::parse_type(&rrtype).ok_or_else(|| {
+            Error::InvalidConfig(format!("{} {}: unknown type {}", name, rrname, rrtype))
+        })?;
+        /* A TXT value is a single string, rather than space separated strings */
+        let rdata = if rrtype == dnspkt::RR_TXT {
+            zone::txt_rdata(&value.as_bytes().chunks(255).collect::<Vec<_>>())
+        } else {
+            zone::parse_rdata(rrtype, &value.split_whitespace().collect::<Vec<_>>(), None)
+        }
+        .map_err(|e| Error::InvalidConfig(format!("{} {}: {}", name, rrname, e)))?;
+        return Ok(Some(dnspkt::RR {
+            domain: zone::parse_name(&rrname, None).map_err(Error::InvalidConfig)?,
+            class: dnspkt::CLASS_IN,
+            rrtype,
+            ttl: ttl.unwrap_or(zone::DEFAULT_TTL),
+            rdata,
+        }));
+    }
+    Err(Error::InvalidConfig(format!(
+        "{} should be a hash, not {:?}",
+        name, fragment
+    )))
+}
+
 pub fn parse_dns_route(name: &str, fragment: &yaml::Yaml) -> Result<Option<Route>, Error> {
     if let Some(h) = fragment.as_hash() {
         let mut suffixes = None;
         let mut servers = None;
         let mut handler = None;
+        let mut records = None;
+        let mut zone_file = None;
+        let mut blocklists = None;
+        let mut block_action = None;
+        let mut reload_interval = None;
         for (k, v) in h {
             match k.as_str() {
                 Some("domain-suffixes") => {
                     suffixes = parse_array("domain-suffixes", v, parse_string)?
                 }
-                Some("dns-servers") => servers = parse_array("domain-servers", v, parse_string_ip)?,
+                Some("dns-servers") => {
+                    servers = parse_array("domain-servers", v, parse_nameserver)?
+                }
+                Some("records") => records = parse_array("records", v, parse_dns_record)?,
+                Some("zone-file") => zone_file = parse_string("zone-file", v)?,
+                Some("blocklists") => blocklists = parse_array("blocklists", v, parse_string)?,
+                Some("block-action") => match parse_string("block-action", v)? {
+                    Some(a) if a == "nxdomain" => {
+                        block_action = Some(super::blocklist::BlockAction::NxDomain)
+                    }
+                    Some(a) if a == "null-address" => {
+                        block_action = Some(super::blocklist::BlockAction::NullAddress)
+                    }
+                    Some(a) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "{} block-action {} not supported, expected nxdomain or null-address",
+                            name, a,
+                        )))
+                    }
+                    None => block_action = None,
+                },
+                Some("reload-interval") => reload_interval = parse_duration("reload-interval", v)?,
                 Some("type") => match parse_string("type", v)? {
                     Some(t) if t == "forward" => handler = Some(HandlerType::Forward),
                     Some(t) if t == "forge-nxdomain" => handler = Some(HandlerType::ForgeNxDomain),
+                    #[cfg(feature = "dhcp")]
+                    Some(t) if t == "dhcp-leases" => handler = Some(HandlerType::DhcpLeases),
+                    Some(t) if t == "static" => handler = Some(HandlerType::Static),
+                    Some(t) if t == "zone" => handler = Some(HandlerType::Zone),
+                    Some(t) if t == "blocklist" => handler = Some(HandlerType::Blocklist),
                     Some(kw) => {
                         return Err(Error::InvalidConfig(format!(
                             "{} type {} not supported",
@@ -80,21 +191,11 @@ pub fn parse_dns_route(name: &str, fragment: &yaml::Yaml) -> Result<Option<Route
             .collect::<Result<_, &'static str>>()
             .map_err(|m| Error::InvalidConfig(m.into()))?;
         let servers = servers.unwrap_or_default();
-        if servers.len() > 1 {
-            return Err(Error::InvalidConfig(
-                "Multiple DNS servers for a prefix not yet implemented.".into(), // TODO
-            ));
-        }
         match handler {
             Some(HandlerType::Forward) | None => {
                 return Ok(Some(Route {
                     suffixes: suffix_domains,
-                    dest: Handler::Forward(
-                        servers
-                            .iter()
-                            .map(|ip| std::net::SocketAddr::new(*ip, 53))
-                            .collect(),
-                    ),
+                    dest: Handler::Forward(servers),
                 }));
             }
             Some(HandlerType::ForgeNxDomain) => {
@@ -103,6 +204,87 @@ pub fn parse_dns_route(name: &str, fragment: &yaml::Yaml) -> Result<Option<Route
                     dest: Handler::ForgeNxDomain,
                 }))
             }
+            #[cfg(feature = "dhcp")]
+            Some(HandlerType::DhcpLeases) => {
+                return Ok(Some(Route {
+                    suffixes: suffix_domains,
+                    dest: Handler::DhcpLeases,
+                }))
+            }
+            Some(HandlerType::Static) => {
+                let mut zones = suffix_domains
+                    .iter()
+                    .cloned()
+                    .map(super::zone::Zone::new)
+                    .collect::<Vec<_>>();
+                for rr in records.unwrap_or_default() {
+                    /* Each record belongs to the most specific suffix that contains it */
+                    let zone = zones
+                        .iter_mut()
+                        .filter(|z| rr.domain.ends_with(&z.origin))
+                        .min_by(|a, b| super::dnspkt::compare_longest_suffix(&a.origin, &b.origin))
+                        .ok_or_else(|| {
+                            Error::InvalidConfig(format!(
+                                "{} is not within any of the domain-suffixes of {}",
+                                rr.domain, name
+                            ))
+                        })?;
+                    zone.insert(rr).map_err(Error::InvalidConfig)?;
+                }
+                return Ok(Some(Route {
+                    suffixes: suffix_domains,
+                    dest: Handler::Zones(zones),
+                }));
+            }
+            Some(HandlerType::Zone) => {
+                let zone_file = zone_file.ok_or_else(|| {
+                    Error::InvalidConfig(format!("{} type zone requires a zone-file", name))
+                })?;
+                let origin = match suffix_domains.as_slice() {
+                    [origin] => origin.clone(),
+                    _ => {
+                        return Err(Error::InvalidConfig(format!(
+                            "{} type zone requires exactly one domain-suffix",
+                            name
+                        )))
+                    }
+                };
+                let text = std::fs::read_to_string(&zone_file)
+                    .map_err(|e| Error::InvalidConfig(format!("{}: {}", zone_file, e)))?;
+                let zone = super::zone::parse_master_file(&text, origin).map_err(|(line, e)| {
+                    Error::InvalidConfig(format!("{}:{}: {}", zone_file, line, e))
+                })?;
+                return Ok(Some(Route {
+                    suffixes: suffix_domains,
+                    dest: Handler::Zones(vec![zone]),
+                }));
+            }
+            Some(HandlerType::Blocklist) => {
+                let lists = blocklists
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|path| {
+                        super::blocklist::List::new(std::path::Path::new(path))
+                            .map(std::sync::Arc::new)
+                            .map_err(Error::InvalidConfig)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if lists.is_empty() {
+                    return Err(Error::InvalidConfig(format!(
+                        "{} type blocklist requires at least one of blocklists",
+                        name
+                    )));
+                }
+                return Ok(Some(Route {
+                    suffixes: suffix_domains,
+                    dest: Handler::Blocklist(super::blocklist::Blocklist {
+                        lists,
+                        action: block_action.unwrap_or(super::blocklist::BlockAction::NxDomain),
+                        reload_interval: reload_interval
+                            .unwrap_or(super::blocklist::DEFAULT_RELOAD_INTERVAL),
+                    }),
+                }));
+            }
         }
     }
     Ok(None)
@@ -112,6 +294,254 @@ pub fn parse_dns_routes(name: &str, fragment: &yaml::Yaml) -> Result<Option<Vec<
     parse_array(name, fragment, parse_dns_route)
 }
 
+/// The longest we cache negative answers for by default.  RFC2308 suggests one to three hours.
+pub const DEFAULT_MAX_NEGATIVE_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// The most entries the cache holds by default.
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 10000;
+
+/// How often the cache is saved to the snapshot file by default.
+pub const DEFAULT_SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(900);
+
+/// Saving the cache rewrites the whole file, so don't do it more often than this.
+pub const MIN_SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// The longest time to cache NXDOMAIN and NODATA answers for.
+    pub max_negative_ttl: std::time::Duration,
+    /// Treat NXDOMAIN as meaning no names below it exist either (RFC8020).
+    pub nxdomain_cut: bool,
+    /// How long after expiring an answer can still be used if the upstream fails (RFC8767).
+    pub serve_stale: std::time::Duration,
+    /// The most entries to hold, the least recently used are evicted first.
+    pub max_entries: usize,
+    /// The most memory (approximately, in bytes) the entries can use.
+    pub max_size: Option<usize>,
+    /// Refresh popular entries before they expire.
+    pub prefetch: bool,
+    /// Where to save the cache, so it can be reloaded after a restart.
+    pub snapshot_file: Option<std::path::PathBuf>,
+    /// How often to save the cache to the snapshot file.
+    pub snapshot_interval: std::time::Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_negative_ttl: DEFAULT_MAX_NEGATIVE_TTL,
+            nxdomain_cut: false,
+            serve_stale: std::time::Duration::ZERO,
+            max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+            max_size: None,
+            prefetch: true,
+            snapshot_file: None,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        }
+    }
+}
+
+pub fn parse_cache_config(name: &str, fragment: &yaml::Yaml) -> Result<Option<CacheConfig>, Error> {
+    if let Some(h) = fragment.as_hash() {
+        let mut config = CacheConfig::default();
+        for (k, v) in h {
+            match k.as_str() {
+                Some("max-negative-ttl") => {
+                    config.max_negative_ttl =
+                        parse_duration("max-negative-ttl", v)?.unwrap_or(DEFAULT_MAX_NEGATIVE_TTL)
+                }
+                Some("nxdomain-cut") => {
+                    config.nxdomain_cut = parse_boolean("nxdomain-cut", v)?.unwrap_or(false)
+                }
+                Some("serve-stale") => {
+                    config.serve_stale =
+                        parse_duration("serve-stale", v)?.unwrap_or(std::time::Duration::ZERO)
+                }
+                Some("max-entries") => {
+                    config.max_entries =
+                        parse_num("max-entries", v)?.unwrap_or(DEFAULT_CACHE_MAX_ENTRIES)
+                }
+                Some("max-size") => config.max_size = parse_num("max-size", v)?,
+                Some("prefetch") => config.prefetch = parse_boolean("prefetch", v)?.unwrap_or(true),
+                Some("snapshot-file") => {
+                    config.snapshot_file = parse_string("snapshot-file", v)?.map(Into::into)
+                }
+                Some("snapshot-interval") => {
+                    config.snapshot_interval = parse_duration("snapshot-interval", v)?
+                        .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL);
+                    if config.snapshot_interval < MIN_SNAPSHOT_INTERVAL {
+                        return Err(Error::InvalidConfig(format!(
+                            "snapshot-interval must be at least {}s",
+                            MIN_SNAPSHOT_INTERVAL.as_secs()
+                        )));
+                    }
+                }
+                Some(opt) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Unknown {} keyword {}",
+                        name, opt
+                    )))
+                }
+                None => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Expected string in {}, not {:?}",
+                        name, k
+                    )))
+                }
+            }
+        }
+        return Ok(Some(config));
+    }
+    if fragment.is_null() {
+        return Ok(None);
+    }
+    Err(Error::InvalidConfig(format!(
+        "{} should be a hash, not {:?}",
+        name, fragment
+    )))
+}
+
+/// A set of routes used for clients that match.
+#[derive(Debug)]
+pub struct View {
+    pub name: String,
+    pub client: crate::acl::ClientMatch,
+    pub routes: Vec<Route>,
+}
+
+pub fn parse_dns_view(name: &str, fragment: &yaml::Yaml) -> Result<Option<View>, Error> {
+    if let Some(h) = fragment.as_hash() {
+        let mut view_name = None;
+        let mut client = crate::acl::ClientMatch::default();
+        let mut routes = None;
+        for (k, v) in h {
+            match k.as_str() {
+                Some(m) if client.parse_key(m, v)? => (),
+                Some("name") => view_name = parse_string("name", v)?,
+                Some("dns-routes") => routes = parse_dns_routes("dns-routes", v)?,
+                Some(opt) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Unknown {} keyword {}",
+                        name, opt
+                    )))
+                }
+                None => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Expected string in {}, not {:?}",
+                        name, k
+                    )))
+                }
+            }
+        }
+        let view_name =
+            view_name.ok_or_else(|| Error::InvalidConfig(format!("{} requires a name", name)))?;
+        return Ok(Some(View {
+            name: view_name,
+            client,
+            routes: routes.unwrap_or_default(),
+        }));
+    }
+    Err(Error::InvalidConfig(format!(
+        "{} should be a hash, not {:?}",
+        name, fragment
+    )))
+}
+
+pub fn parse_dns_views(name: &str, fragment: &yaml::Yaml) -> Result<Option<Vec<View>>, Error> {
+    let views = parse_array(name, fragment, parse_dns_view)?;
+    for (i, view) in views.iter().flatten().enumerate() {
+        if views.iter().flatten().take(i).any(|v| v.name == view.name) {
+            return Err(Error::InvalidConfig(format!(
+                "{} has more than one view named {}",
+                name, view.name
+            )));
+        }
+    }
+    Ok(views)
+}
+
+pub use super::rpz::PolicyZone;
+
+fn parse_primary(name: &str, fragment: &yaml::Yaml) -> Result<Option<std::net::SocketAddr>, Error> {
+    parse_string(name, fragment)?
+        .map(|s| {
+            s.parse()
+                .or_else(|_| s.parse().map(|ip| std::net::SocketAddr::new(ip, 53)))
+                .map_err(|e| Error::InvalidConfig(format!("{}: {} ({})", name, e, s)))
+        })
+        .transpose()
+}
+
+pub fn parse_policy_zone(
+    name: &str,
+    fragment: &yaml::Yaml,
+) -> Result<Option<std::sync::Arc<PolicyZone>>, Error> {
+    if let Some(h) = fragment.as_hash() {
+        let mut zone = None;
+        let mut zone_file = None;
+        let mut primary = None;
+        let mut ede = None;
+        for (k, v) in h {
+            match k.as_str() {
+                Some("zone") => zone = parse_string("zone", v)?,
+                Some("zone-file") => zone_file = parse_string("zone-file", v)?,
+                Some("primary") => primary = parse_primary("primary", v)?,
+                Some("extended-error") => match parse_string("extended-error", v)? {
+                    Some(e) if e == "blocked" => ede = Some(super::dnspkt::EDE_BLOCKED),
+                    Some(e) if e == "filtered" => ede = Some(super::dnspkt::EDE_FILTERED),
+                    Some(e) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "{} extended-error {} not supported, expected blocked or filtered",
+                            name, e,
+                        )))
+                    }
+                    None => ede = None,
+                },
+                Some(opt) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Unknown {} keyword {}",
+                        name, opt
+                    )))
+                }
+                None => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Expected string in {}, not {:?}",
+                        name, k
+                    )))
+                }
+            }
+        }
+        let zone = zone.ok_or_else(|| Error::InvalidConfig(format!("{} requires a zone", name)))?;
+        let origin = zone
+            .parse()
+            .map_err(|e| Error::InvalidConfig(format!("{}: {} ({})", name, e, zone)))?;
+        let source = match (zone_file, primary) {
+            (Some(path), None) => super::rpz::Source::File(path.into()),
+            (None, Some(primary)) => super::rpz::Source::Axfr(primary),
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "{} {} requires exactly one of zone-file or primary",
+                    name, zone
+                )))
+            }
+        };
+        return PolicyZone::new(origin, source, ede.unwrap_or(super::dnspkt::EDE_BLOCKED))
+            .map(|zone| Some(zone.into()))
+            .map_err(Error::InvalidConfig);
+    }
+    Err(Error::InvalidConfig(format!(
+        "{} should be a hash, not {:?}",
+        name, fragment
+    )))
+}
+
+pub fn parse_policy_zones(
+    name: &str,
+    fragment: &yaml::Yaml,
+) -> Result<Option<Vec<std::sync::Arc<PolicyZone>>>, Error> {
+    parse_array(name, fragment, parse_policy_zone)
+}
+
 #[test]
 fn test_dns_config() -> Result<(), Error> {
     use crate::config;
@@ -120,10 +550,279 @@ fn test_dns_config() -> Result<(), Error> {
 dns-routes:
   - domain-suffixes: ['invalid']
     type: forge-nxdomain
+  - domain-suffixes: ['lan', '2.0.192.in-addr.arpa']
+    type: dhcp-leases
+  - domain-suffixes: ['home.arpa']
+    type: static
+    records:
+      - name: router.home.arpa
+        type: A
+        value: 192.0.2.1
+      - name: home.arpa
+        type: TXT
+        value: v=spf1 -all
+        ttl: 3600
+      - name: _http._tcp.home.arpa
+        type: SRV
+        value: 0 5 80 router.home.arpa
   - domain-suffixes: ['']
     type: forward
-    dns-servers: [2001:4860:4860::8888]
+    dns-servers:
+      - 2001:4860:4860::8888
+      - 8.8.8.8
+      - tls://dns.google@8.8.4.4
+      - https://dns.google/dns-query
 ",
     )?;
     Ok(())
 }
+
+#[test]
+fn test_dns_static_record_outside_suffix() {
+    use crate::config;
+    assert!(config::load_config_from_string_for_test(
+        "---
+dns-routes:
+  - domain-suffixes: ['home.arpa']
+    type: static
+    records:
+      - name: router.example.com
+        type: A
+        value: 192.0.2.1
+",
+    )
+    .is_err());
+}
+
+#[test]
+fn test_dns_zone_config() {
+    use crate::config;
+    for cfg in [
+        "---
+dns-routes:
+  - domain-suffixes: ['home.arpa']
+    type: zone
+",
+        "---
+dns-routes:
+  - domain-suffixes: ['home.arpa']
+    type: zone
+    zone-file: /nonexistent/home.arpa.zone
+",
+        "---
+dns-routes:
+  - domain-suffixes: ['home.arpa', 'lan']
+    type: zone
+    zone-file: /nonexistent/home.arpa.zone
+",
+    ] {
+        assert!(config::load_config_from_string_for_test(cfg).is_err());
+    }
+}
+
+#[test]
+fn test_dns_cache_config() {
+    use crate::config;
+    let conf = config::load_config_from_string_for_test(
+        "---
+dns-cache:
+  max-negative-ttl: 5m
+  nxdomain-cut: true
+  serve-stale: 1d
+  max-entries: 500
+  max-size: 1048576
+  prefetch: false
+  snapshot-file: /var/lib/erbium/dns-cache
+  snapshot-interval: 1h
+",
+    )
+    .unwrap();
+    let conf = conf.try_read().unwrap();
+    assert_eq!(
+        conf.dns_cache.max_negative_ttl,
+        std::time::Duration::from_secs(300)
+    );
+    assert!(conf.dns_cache.nxdomain_cut);
+    assert_eq!(
+        conf.dns_cache.serve_stale,
+        std::time::Duration::from_secs(86400)
+    );
+    assert_eq!(conf.dns_cache.max_entries, 500);
+    assert_eq!(conf.dns_cache.max_size, Some(1048576));
+    assert!(!conf.dns_cache.prefetch);
+    assert_eq!(
+        conf.dns_cache.snapshot_file,
+        Some("/var/lib/erbium/dns-cache".into())
+    );
+    assert_eq!(
+        conf.dns_cache.snapshot_interval,
+        std::time::Duration::from_secs(3600)
+    );
+
+    let conf = config::load_config_from_string_for_test("---\ndns-cache:\n").unwrap();
+    let conf = conf.try_read().unwrap();
+    assert_eq!(conf.dns_cache.max_negative_ttl, DEFAULT_MAX_NEGATIVE_TTL);
+    assert!(!conf.dns_cache.nxdomain_cut);
+    assert_eq!(conf.dns_cache.serve_stale, std::time::Duration::ZERO);
+    assert_eq!(conf.dns_cache.max_entries, DEFAULT_CACHE_MAX_ENTRIES);
+    assert_eq!(conf.dns_cache.max_size, None);
+    assert!(conf.dns_cache.prefetch);
+    assert_eq!(conf.dns_cache.snapshot_file, None);
+
+    for cfg in [
+        "---
+dns-cache:
+  max-positive-ttl: 5m
+",
+        "---
+dns-cache:
+  snapshot-interval: 0s
+",
+    ] {
+        assert!(config::load_config_from_string_for_test(cfg).is_err());
+    }
+}
+
+#[test]
+fn test_dns_views_config() {
+    use crate::config;
+    let conf = config::load_config_from_string_for_test(
+        "---
+dns-views:
+  - name: guests
+    match-subnets: [192.0.2.128/25]
+    match-interfaces: [guest*]
+    dns-routes:
+      - domain-suffixes: [internal.example]
+        type: forge-nxdomain
+  - name: empty
+dns-routes:
+  - domain-suffixes: ['']
+    type: forward
+    dns-servers: [192.0.2.53]
+",
+    )
+    .unwrap();
+    let conf = conf.try_read().unwrap();
+    assert_eq!(conf.dns_views.len(), 2);
+    assert_eq!(conf.dns_views[0].name, "guests");
+    assert_eq!(conf.dns_views[0].routes.len(), 1);
+    assert_eq!(
+        conf.dns_views[0].client.interfaces,
+        Some(vec!["guest*".to_string()])
+    );
+    assert!(conf.dns_views[1].routes.is_empty());
+
+    for cfg in [
+        "---
+dns-views:
+  - match-subnets: [192.0.2.128/25]
+",
+        "---
+dns-views:
+  - name: guests
+  - name: guests
+",
+        "---
+dns-views:
+  - name: guests
+    apply-access: [dns-recursion]
+",
+    ] {
+        assert!(config::load_config_from_string_for_test(cfg).is_err());
+    }
+}
+
+#[test]
+fn test_dns_rpz_config() {
+    use crate::config;
+    let path = crate::test_util::TempPath::new("rpz");
+    std::fs::write(
+        &path,
+        "@ SOA ns hostmaster 1 3600 600 86400 60\nads.example.com CNAME .\n",
+    )
+    .unwrap();
+    let ok = config::load_config_from_string_for_test(&format!(
+        "---
+dns-rpz:
+  - zone: rpz.example
+    zone-file: {}
+    extended-error: filtered
+  - zone: rpz2.example
+    primary: 192.0.2.53
+",
+        path.display()
+    ));
+    let conf = ok.unwrap();
+    let conf = conf.try_read().unwrap();
+    assert_eq!(conf.dns_rpz.len(), 2);
+    assert_eq!(conf.dns_rpz[0].ede, super::dnspkt::EDE_FILTERED);
+    assert!(matches!(
+        conf.dns_rpz[1].source,
+        super::rpz::Source::Axfr(addr) if addr == "192.0.2.53:53".parse().unwrap()
+    ));
+
+    // A zone file that doesn't exist yet is loaded once it's created.
+    assert!(config::load_config_from_string_for_test(
+        "---
+dns-rpz:
+  - zone: rpz.example
+    zone-file: /nonexistent/rpz
+"
+    )
+    .is_ok());
+
+    for cfg in [
+        "---
+dns-rpz:
+  - zone: rpz.example
+",
+        "---
+dns-rpz:
+  - zone: rpz.example
+    primary: 192.0.2.53
+    extended-error: censored
+",
+    ] {
+        assert!(config::load_config_from_string_for_test(cfg).is_err());
+    }
+}
+
+#[test]
+fn test_dns_blocklist_config() {
+    use crate::config;
+    let path = crate::test_util::TempPath::new("blocklist");
+    std::fs::write(&path, "0.0.0.0 ads.example.com\n").unwrap();
+    let ok = config::load_config_from_string_for_test(&format!(
+        "---
+dns-routes:
+  - domain-suffixes: ['']
+    type: blocklist
+    blocklists: [{}]
+    block-action: null-address
+    reload-interval: 1h
+",
+        path.display()
+    ));
+    ok.unwrap();
+
+    // A blocklist that doesn't exist yet is loaded once it's created.
+    assert!(config::load_config_from_string_for_test(
+        "---
+dns-routes:
+  - domain-suffixes: ['']
+    type: blocklist
+    blocklists: [/nonexistent/blocklist]
+"
+    )
+    .is_ok());
+
+    assert!(config::load_config_from_string_for_test(
+        "---
+dns-routes:
+  - domain-suffixes: ['']
+    type: blocklist
+"
+    )
+    .is_err());
+}
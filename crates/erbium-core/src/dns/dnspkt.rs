@@ -54,13 +54,18 @@ pub const RR_CNAME: Type = Type(5);
 pub const RR_SOA: Type = Type(6);
 pub const RR_PTR: Type = Type(12);
 pub const RR_MX: Type = Type(15);
+pub const RR_TXT: Type = Type(16);
 pub const RR_RP: Type = Type(17);
 pub const RR_AFSDB: Type = Type(18);
 pub const RR_RT: Type = Type(21);
+pub const RR_AAAA: Type = Type(28);
+pub const RR_SRV: Type = Type(33);
 pub const RR_NAPTR: Type = Type(35);
+pub const RR_DNAME: Type = Type(39);
 pub const RR_OPT: Type = Type(41);
 pub const RR_NSEC: Type = Type(47);
 pub const RR_NSEC3: Type = Type(50);
+pub const RR_AXFR: Type = Type(252);
 pub const RR_ANY: Type = Type(255);
 
 impl fmt::Display for Type {
@@ -71,10 +76,16 @@ impl fmt::Display for Type {
             &RR_CNAME => write!(f, "CNAME"),
             &RR_SOA => write!(f, "SOA"),
             &RR_PTR => write!(f, "PTR"),
+            &RR_MX => write!(f, "MX"),
+            &RR_TXT => write!(f, "TXT"),
+            &RR_AAAA => write!(f, "AAAA"),
+            &RR_SRV => write!(f, "SRV"),
             &RR_NAPTR => write!(f, "NAPTR"),
+            &RR_DNAME => write!(f, "DNAME"),
             &RR_OPT => write!(f, "OPT"),
             &RR_NSEC => write!(f, "NSEC"),
             &RR_NSEC3 => write!(f, "NSEC3"),
+            &RR_AXFR => write!(f, "AXFR"),
             Type(x) => write!(f, "Type#{}", x),
         }
     }
@@ -193,6 +204,21 @@ impl Domain {
     pub fn ends_with(&self, other: &Self) -> bool {
         self.0.ends_with(&other.0)
     }
+
+    /// The domain with the first label removed, or None for the root.
+    pub fn parent(&self) -> Option<Domain> {
+        self.0.split_first().map(|(_, rest)| Domain(rest.to_vec()))
+    }
+
+    /// The uncompressed wire format of this domain, for building RData::Other.
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut v = vec![];
+        for l in &self.0 {
+            push_label(&mut v, l);
+        }
+        v.push(0);
+        v
+    }
 }
 
 impl From<Vec<Label>> for Domain {
@@ -996,6 +1022,29 @@ fn push_rr(v: &mut Vec<u8>, rr: &RR, offsets: &mut DomainOffsets) {
 }
 
 impl DNSPkt {
+    /// Starts a reply that we generate ourselves to a query, with no records in it yet.
+    pub fn reply_to(query: &DNSPkt, rcode: RCode) -> Self {
+        DNSPkt {
+            qid: query.qid,
+            rd: false,
+            tc: false,
+            aa: false,
+            qr: true,
+            opcode: OPCODE_QUERY,
+            cd: false,
+            ad: false,
+            ra: true,
+            rcode,
+            bufsize: 4096,
+            edns_ver: None,
+            edns_do: false,
+            question: query.question.clone(),
+            answer: vec![],
+            nameserver: vec![],
+            additional: vec![],
+            edns: None,
+        }
+    }
     pub fn status(&self) -> String {
         match self
             .edns
@@ -0,0 +1,429 @@
+/*   Copyright 2024 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Answers DNS queries for DHCP clients from the lease database.
+ *
+ *  Clients are given an A record of <host-name>.<domain> where host-name is the DHCP host-name
+ *  option they sent, and domain is the first (non reverse) domain suffix of the route.  PTR records
+ *  are provided for any in-addr.arpa suffixes on the route.
+ */
+
+use super::dnspkt;
+use super::Error;
+use crate::dhcp::pool;
+
+/// Leases can change at any time, so don't let resolvers cache the answers for long.
+const LEASE_TTL: u32 = 60;
+
+/// How long the names are reused for, rather than reading the lease database again.  This is much
+/// shorter than LEASE_TTL, so new leases still show up quickly.
+const NAMES_CACHE_TIME: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Which address owns each hostname.
+type Names = std::collections::HashMap<String, std::net::Ipv4Addr>;
+
+pub struct DhcpLeaseHandler {
+    /* The lease database is opened on first use, so erbium-dns doesn't need DHCP to be running
+     * until someone actually asks for a lease.
+     */
+    pool: std::sync::Arc<std::sync::Mutex<Option<pool::Pool>>>,
+    /* The names from the last time the lease database was read, and when it was read. */
+    names: std::sync::Mutex<Option<(std::time::Instant, std::sync::Arc<Names>)>>,
+}
+
+impl DhcpLeaseHandler {
+    pub fn new() -> Self {
+        Self {
+            pool: Default::default(),
+            names: Default::default(),
+        }
+    }
+
+    /* Reading the lease database blocks, so is done off the async runtime. */
+    async fn load_names(&self) -> Result<Names, Error> {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut locked_pool = pool.lock().unwrap();
+            let pool = match &mut *locked_pool {
+                Some(pool) => pool,
+                None => locked_pool.insert(
+                    pool::Pool::new().map_err(|e| Error::LeasesUnavailable(e.to_string()))?,
+                ),
+            };
+            let leases = pool
+                .get_leases()
+                .map_err(|e| Error::LeasesUnavailable(e.to_string()))?;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .expect("clock failure")
+                .as_secs() as u32;
+            Ok(lease_names(&leases, now))
+        })
+        .await
+        .map_err(|e| Error::LeasesUnavailable(e.to_string()))?
+    }
+
+    /* Every query would otherwise read the whole lease database, so the names are reused for a
+     * few seconds.
+     */
+    async fn get_names(&self) -> Result<std::sync::Arc<Names>, Error> {
+        if let Some((loaded, names)) = &*self.names.lock().unwrap() {
+            if loaded.elapsed() < NAMES_CACHE_TIME {
+                return Ok(names.clone());
+            }
+        }
+        let names = std::sync::Arc::new(self.load_names().await?);
+        *self.names.lock().unwrap() = Some((std::time::Instant::now(), names.clone()));
+        Ok(names)
+    }
+
+    pub async fn handle_query(
+        &self,
+        msg: &super::DnsMessage,
+        suffixes: &[dnspkt::Domain],
+    ) -> Result<dnspkt::DNSPkt, Error> {
+        let names = self.get_names().await?;
+        let (rcode, answer) = lookup(&names, &msg.in_query.question, suffixes);
+        /* Negative answers need a SOA so resolvers know how long they can cache them for */
+        let nameserver = if answer.is_empty() {
+            lease_soa(&msg.in_query.question, suffixes)
+                .into_iter()
+                .collect()
+        } else {
+            vec![]
+        };
+        Ok(dnspkt::DNSPkt {
+            aa: true,
+            answer,
+            nameserver,
+            ..dnspkt::DNSPkt::reply_to(&msg.in_query, rcode)
+        })
+    }
+}
+
+/// Returns a SOA for the most specific suffix the question is under, with a TTL short enough that
+/// new leases are seen quickly.
+fn lease_soa(question: &dnspkt::Question, suffixes: &[dnspkt::Domain]) -> Option<dnspkt::RR> {
+    let qname = question.qdomain.to_string().to_ascii_lowercase();
+    let origin = suffixes
+        .iter()
+        .map(|s| (s, s.to_string().to_ascii_lowercase()))
+        .filter(|(_, s)| s.is_empty() || qname == *s || qname.ends_with(&format!(".{}", s)))
+        .max_by_key(|(_, s)| s.len())?
+        .0;
+    let mut soa = super::zone::default_soa(origin);
+    soa.ttl = LEASE_TTL;
+    if let dnspkt::RData::Soa(data) = &mut soa.rdata {
+        data.minimum = LEASE_TTL;
+    }
+    Some(soa)
+}
+
+/// Converts a DHCP host-name into a DNS label, if it's usable as one.
+fn hostname_to_label(hostname: &str) -> Option<String> {
+    /* Some clients send their fully qualified name, we only want the host part */
+    let label = hostname.split('.').next()?.to_ascii_lowercase();
+    if label.is_empty()
+        || label.len() > 63
+        || label.starts_with('-')
+        || label.ends_with('-')
+        || !label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+/// Returns which address owns each hostname.  If more than one client claims the same name, the
+/// client that has held its lease the longest keeps it.
+fn lease_names(leases: &[pool::LeaseInfo], now: u32) -> Names {
+    let mut active = leases
+        .iter()
+        .filter(|li| li.expire >= now && !li.client_id.is_empty())
+        .collect::<Vec<_>>();
+    active.sort_by_key(|li| (li.start, li.ip));
+    let mut names = std::collections::HashMap::new();
+    for li in active {
+        let name =
+            match crate::dhcp::dhcppkt::parse_options(crate::pktparser::Buffer::new(&li.options))
+                .ok()
+                .and_then(|o| o.get_hostname())
+                .and_then(|h| hostname_to_label(&h))
+            {
+                Some(name) => name,
+                None => continue,
+            };
+        match names.entry(name) {
+            std::collections::hash_map::Entry::Vacant(v) => {
+                v.insert(li.ip);
+            }
+            std::collections::hash_map::Entry::Occupied(o) => log::debug!(
+                "{} wants the name {}, but it is already used by {}",
+                li.ip,
+                o.key(),
+                o.get()
+            ),
+        }
+    }
+    names
+}
+
+/// Converts a name in in-addr.arpa back to an IPv4 address.
+fn reverse_to_ip(name: &str) -> Option<std::net::Ipv4Addr> {
+    let rest = name.strip_suffix(".in-addr.arpa")?;
+    let mut octets = rest
+        .split('.')
+        .map(|o| o.parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if octets.len() != 4 {
+        return None;
+    }
+    octets.reverse();
+    Some(std::net::Ipv4Addr::new(
+        octets[0], octets[1], octets[2], octets[3],
+    ))
+}
+
+/// Answers a question from the names leased to clients, returning the rcode and answers.
+fn lookup(
+    names: &Names,
+    question: &dnspkt::Question,
+    suffixes: &[dnspkt::Domain],
+) -> (dnspkt::RCode, Vec<dnspkt::RR>) {
+    let qname = question.qdomain.to_string().to_ascii_lowercase();
+    let suffixes = suffixes
+        .iter()
+        .map(|s| s.to_string().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let (reverse, forward): (Vec<_>, Vec<_>) = suffixes
+        .iter()
+        .partition(|s| *s == "in-addr.arpa" || s.ends_with(".in-addr.arpa"));
+
+    /* Reverse lookups */
+    if reverse
+        .iter()
+        .any(|s| qname == **s || qname.ends_with(&format!(".{}", s)))
+    {
+        let name = reverse_to_ip(&qname).and_then(|ip| {
+            names
+                .iter()
+                .find(|(_, owner)| **owner == ip)
+                .map(|(name, _)| name)
+        });
+        return match (name, forward.first()) {
+            (Some(name), Some(domain)) => {
+                let rr = dnspkt::RR {
+                    domain: question.qdomain.clone(),
+                    class: dnspkt::CLASS_IN,
+                    rrtype: dnspkt::RR_PTR,
+                    ttl: LEASE_TTL,
+                    rdata: dnspkt::RData::Ptr(
+                        format!("{}.{}", name, domain)
+                            .parse()
+                            .expect("hostnames are always valid domains"),
+                    ),
+                };
+                if question.qtype == dnspkt::RR_PTR && question.qclass == dnspkt::CLASS_IN {
+                    (dnspkt::NOERROR, vec![rr])
+                } else {
+                    (dnspkt::NOERROR, vec![])
+                }
+            }
+            _ if qname.ends_with(".in-addr.arpa") && reverse_to_ip(&qname).is_some() => {
+                (dnspkt::NXDOMAIN, vec![])
+            }
+            /* Intermediate names (eg 2.0.192.in-addr.arpa) exist, but have no records */
+            _ => (dnspkt::NOERROR, vec![]),
+        };
+    }
+
+    /* Forward lookups */
+    for domain in forward {
+        if qname == *domain {
+            return (dnspkt::NOERROR, vec![]);
+        }
+        let host = match qname.strip_suffix(&format!(".{}", domain)) {
+            Some(host) => host,
+            None => continue,
+        };
+        return match names.get(host) {
+            Some(ip) if question.qtype == dnspkt::RR_A && question.qclass == dnspkt::CLASS_IN => (
+                dnspkt::NOERROR,
+                vec![dnspkt::RR {
+                    domain: question.qdomain.clone(),
+                    class: dnspkt::CLASS_IN,
+                    rrtype: dnspkt::RR_A,
+                    ttl: LEASE_TTL,
+                    rdata: dnspkt::RData::Other(ip.octets().to_vec()),
+                }],
+            ),
+            Some(_) => (dnspkt::NOERROR, vec![]),
+            None => (dnspkt::NXDOMAIN, vec![]),
+        };
+    }
+    (dnspkt::NXDOMAIN, vec![])
+}
+
+#[cfg(test)]
+fn mk_lease(ip: &str, client_id: &[u8], start: u32, hostname: &str) -> pool::LeaseInfo {
+    use crate::dhcp::dhcppkt::Serialise as _;
+    let mut options = vec![];
+    crate::dhcp::dhcppkt::DhcpOptions::default()
+        .set_option(
+            &crate::dhcp::dhcppkt::OPTION_HOSTNAME,
+            &hostname.to_string(),
+        )
+        .serialise(&mut options);
+    pool::LeaseInfo {
+        ip: ip.parse().unwrap(),
+        client_id: client_id.to_vec(),
+        start,
+        expire: start + 3600,
+        options,
+    }
+}
+
+#[cfg(test)]
+fn mk_question(name: &str, qtype: dnspkt::Type) -> dnspkt::Question {
+    dnspkt::Question {
+        qdomain: name.parse().unwrap(),
+        qclass: dnspkt::CLASS_IN,
+        qtype,
+    }
+}
+
+#[test]
+fn test_lease_lookup() {
+    let suffixes = vec![
+        "lan".parse().unwrap(),
+        "2.0.192.in-addr.arpa".parse().unwrap(),
+    ];
+    let leases = vec![
+        mk_lease("192.0.2.10", b"laptop", 100, "Laptop"),
+        mk_lease("192.0.2.11", b"phone", 200, "phone.example.com"),
+        pool::LeaseInfo {
+            expire: 500, /* Expired */
+            ..mk_lease("192.0.2.12", b"old", 0, "old")
+        },
+    ];
+    let names = lease_names(&leases, 1000);
+
+    let (rcode, answer) = lookup(&names, &mk_question("laptop.lan", dnspkt::RR_A), &suffixes);
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(answer[0].rdata, dnspkt::RData::Other(vec![192, 0, 2, 10]));
+
+    let (rcode, answer) = lookup(&names, &mk_question("Phone.lan", dnspkt::RR_A), &suffixes);
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(answer[0].rdata, dnspkt::RData::Other(vec![192, 0, 2, 11]));
+
+    let (rcode, answer) = lookup(
+        &names,
+        &mk_question("11.2.0.192.in-addr.arpa", dnspkt::RR_PTR),
+        &suffixes,
+    );
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(
+        answer[0].rdata,
+        dnspkt::RData::Ptr("phone.lan".parse().unwrap())
+    );
+
+    /* Names that exist but don't have the requested type, are NODATA not NXDOMAIN */
+    let (rcode, answer) = lookup(
+        &names,
+        &mk_question("laptop.lan", dnspkt::Type(28)),
+        &suffixes,
+    );
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert!(answer.is_empty());
+
+    for (name, qtype) in [
+        ("old.lan", dnspkt::RR_A),
+        ("missing.lan", dnspkt::RR_A),
+        ("12.2.0.192.in-addr.arpa", dnspkt::RR_PTR),
+    ] {
+        let (rcode, answer) = lookup(&names, &mk_question(name, qtype), &suffixes);
+        assert_eq!(rcode, dnspkt::NXDOMAIN, "{}", name);
+        assert!(answer.is_empty());
+    }
+}
+
+#[test]
+fn test_lease_name_conflict() {
+    let suffixes = vec![
+        "lan".parse().unwrap(),
+        "2.0.192.in-addr.arpa".parse().unwrap(),
+    ];
+    /* Both claim "printer", the one that has had its lease longest keeps the name */
+    let leases = vec![
+        mk_lease("192.0.2.20", b"new-printer", 200, "printer"),
+        mk_lease("192.0.2.21", b"old-printer", 100, "printer"),
+    ];
+    let names = lease_names(&leases, 1000);
+
+    let (_, answer) = lookup(&names, &mk_question("printer.lan", dnspkt::RR_A), &suffixes);
+    assert_eq!(answer[0].rdata, dnspkt::RData::Other(vec![192, 0, 2, 21]));
+    let (rcode, _) = lookup(
+        &names,
+        &mk_question("20.2.0.192.in-addr.arpa", dnspkt::RR_PTR),
+        &suffixes,
+    );
+    assert_eq!(rcode, dnspkt::NXDOMAIN);
+}
+
+#[test]
+fn test_lease_soa() {
+    let suffixes = vec![
+        "lan".parse().unwrap(),
+        "in-addr.arpa".parse().unwrap(),
+        "2.0.192.in-addr.arpa".parse().unwrap(),
+    ];
+    for (name, origin) in [
+        ("missing.lan", "lan"),
+        ("LAN", "lan"),
+        ("12.2.0.192.in-addr.arpa", "2.0.192.in-addr.arpa"),
+        ("12.3.0.192.in-addr.arpa", "in-addr.arpa"),
+    ] {
+        let soa = lease_soa(&mk_question(name, dnspkt::RR_A), &suffixes).unwrap();
+        assert_eq!(soa.domain, origin.parse().unwrap(), "{}", name);
+        assert_eq!(soa.ttl, LEASE_TTL);
+    }
+    assert!(lease_soa(&mk_question("example.com", dnspkt::RR_A), &suffixes).is_none());
+}
+
+#[tokio::test]
+async fn test_lease_names_cached() {
+    let names = lease_names(&[mk_lease("192.0.2.10", b"laptop", 100, "laptop")], 1000);
+    /* Recently read names are used without reading the lease database again */
+    let handler = DhcpLeaseHandler {
+        pool: Default::default(),
+        names: Some((std::time::Instant::now(), names.into())).into(),
+    };
+    let reply = handler
+        .handle_query(
+            &super::DnsMessage::new_for_test("laptop.lan", dnspkt::RR_A),
+            &["lan".parse().unwrap()],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        reply.answer[0].rdata,
+        dnspkt::RData::Other(vec![192, 0, 2, 10])
+    );
+    assert!(handler.pool.lock().unwrap().is_none());
+}
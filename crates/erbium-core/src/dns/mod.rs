@@ -22,22 +22,30 @@ use erbium_net::udp;
 type UdpSocket = udp::UdpSocket;
 
 mod acl;
+mod blocklist;
 mod bucket;
 mod cache;
 pub(crate) mod config;
 pub mod dnspkt;
+#[cfg(feature = "dhcp")]
+mod leases;
 mod outquery;
 #[cfg(fuzzing)]
 pub mod parse;
 #[cfg(not(fuzzing))]
 mod parse;
 mod router;
+mod rpz;
+mod zone;
 
 use bytes::BytesMut;
 use tokio_util::codec::Decoder;
 
 type Key = [u8; 8];
 
+/// How long to wait for the next query on a DNS over TLS connection before closing it.
+const TLS_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
 struct CookieKeys {
     next_refresh: tokio::time::Instant,
     current: Key,
@@ -129,13 +137,16 @@ lazy_static::lazy_static! {
 pub enum Error {
     ListenError(std::io::Error, Box<erbium_net::addr::NetAddr>),
     AcceptError(std::io::Error),
+    TlsError(String),
     RecvError(std::io::Error),
     ParseError(String),
     RefusedByAcl(crate::acl::AclError),
     Denied(String),
     Blocked,
+    Dropped,
     NoRouteConfigured,
     NotAuthoritative,
+    LeasesUnavailable(String),
     OutReply(outquery::Error),
 }
 
@@ -145,12 +156,15 @@ impl std::fmt::Display for Error {
         match self {
             ListenError(io, addr) => write!(f, "Failed to listen for DNS on {}: {}", addr, io),
             AcceptError(io) => write!(f, "Failed to accept new TCP connection for DNS: {}", io),
+            TlsError(msg) => write!(f, "Failed to set up TLS for DNS: {}", msg),
             RecvError(io) => write!(f, "Failed to receive DNS in query: {}", io),
             ParseError(msg) => write!(f, "Failed to parse DNS in query: {}", msg),
             RefusedByAcl(why) => write!(f, "Query refused by policy: {}", why),
             NotAuthoritative => write!(f, "Not Authoritative"),
             Blocked => write!(f, "Blocked by configuration"),
+            Dropped => write!(f, "Dropped by policy"),
             NoRouteConfigured => write!(f, "No route configured"),
+            LeasesUnavailable(msg) => write!(f, "Failed to read DHCP leases: {}", msg),
             Denied(msg) => write!(f, "Denied: {}", msg),
             OutReply(err) => write!(f, "{}", err),
         }
@@ -501,9 +515,12 @@ impl Decoder for DnsCodec {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum Protocol {
     Udp,
     Tcp,
+    Tls,
+    Https,
 }
 
 impl std::fmt::Display for Protocol {
@@ -511,6 +528,8 @@ impl std::fmt::Display for Protocol {
         match &self {
             Protocol::Udp => write!(f, "UDP"),
             Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Tls => write!(f, "TLS"),
+            Protocol::Https => write!(f, "HTTPS"),
         }
     }
 }
@@ -522,17 +541,64 @@ enum CookieStatus {
     Good,
 }
 
+#[derive(Clone)]
 pub struct DnsMessage {
     pub in_query: dnspkt::DNSPkt,
     pub in_size: usize,
     pub local_ip: std::net::IpAddr,
     pub remote_addr: NetAddr,
     pub protocol: Protocol,
+    /// The name of the interface the query arrived on, if known.
+    pub interface: Option<String>,
 }
 
 type CookieDigest = hmac::Hmac<sha2::Sha256>;
 
 impl DnsMessage {
+    /// A query for name and qtype, arriving over UDP from 192.0.2.10.
+    #[cfg(test)]
+    pub fn new_for_test(name: &str, qtype: dnspkt::Type) -> Self {
+        Self {
+            in_query: dnspkt::DNSPkt {
+                qid: 1,
+                rd: true,
+                tc: false,
+                aa: false,
+                qr: false,
+                opcode: dnspkt::OPCODE_QUERY,
+                cd: false,
+                ad: false,
+                ra: false,
+                rcode: dnspkt::NOERROR,
+                bufsize: 4096,
+                edns_ver: None,
+                edns_do: false,
+                question: dnspkt::Question {
+                    qdomain: name.parse().unwrap(),
+                    qclass: dnspkt::CLASS_IN,
+                    qtype,
+                },
+                answer: vec![],
+                nameserver: vec![],
+                additional: vec![],
+                edns: None,
+            },
+            in_size: 0,
+            local_ip: "192.0.2.53".parse().unwrap(),
+            remote_addr: std::net::SocketAddr::from(([192, 0, 2, 10], 12345)).into(),
+            protocol: Protocol::Udp,
+            interface: None,
+        }
+    }
+
+    /// The attributes of the client, as used by ACLs and views.
+    pub fn client_attributes(&self) -> crate::acl::Attributes {
+        crate::acl::Attributes {
+            addr: self.remote_addr,
+            interface: self.interface.clone(),
+        }
+    }
+
     // Calculate the value of the cookie based on a key.
     // This uses the client cookie, the source and dest ip addresses for generating the cookie.
     fn calculate_cookie(&self, client: &[u8], key: &[u8]) -> CookieDigest {
@@ -606,7 +672,11 @@ struct DnsListenerHandler {
     next: acl::DnsAclHandler,
     udp_listeners: Vec<UdpSocket>,
     tcp_listeners: Vec<tokio::net::TcpListener>,
+    tls_listeners: Vec<tokio::net::TcpListener>,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
     rate_limiter: std::sync::Arc<IpRateLimiter>,
+    netinfo: erbium_net::netinfo::SharedNetInfo,
+    conf: crate::config::SharedConfig,
 }
 
 impl DnsListenerHandler {
@@ -664,6 +734,7 @@ impl DnsListenerHandler {
     async fn listen_tcp(
         _conf: &crate::config::SharedConfig,
         addr: &erbium_net::addr::NetAddr,
+        protocol: Protocol,
     ) -> Result<tokio::net::TcpListener, Error> {
         use erbium_net::addr::NetAddrExt as _;
         let tcp = tokio::net::TcpListener::bind(addr.to_std_socket_addr().ok_or_else(|| {
@@ -673,7 +744,8 @@ impl DnsListenerHandler {
         .map_err(|e| Error::ListenError(e, Box::new(*addr)))?;
 
         log::info!(
-            "Listening for DNS on TCP {}",
+            "Listening for DNS on {} {}",
+            protocol,
             tcp.local_addr()
                 .map(|name| format!("{}", name))
                 .unwrap_or_else(|_| "Unknown".into())
@@ -688,6 +760,8 @@ impl DnsListenerHandler {
     ) -> Result<Self, Error> {
         let mut udp_listeners = vec![];
         let mut tcp_listeners = vec![];
+        let mut tls_listeners = vec![];
+        let mut tls_acceptor = None;
         {
             let roconf = conf.read().await;
             for addr in &roconf
@@ -696,16 +770,37 @@ impl DnsListenerHandler {
                 .await
             {
                 udp_listeners.push(Self::listen_udp(&conf, addr).await?);
-                tcp_listeners.push(Self::listen_tcp(&conf, addr).await?);
+                tcp_listeners.push(Self::listen_tcp(&conf, addr, Protocol::Tcp).await?);
+            }
+            /* DNS over TLS is only enabled if there is a certificate to serve. */
+            if let Some(cert) = &roconf.dns_tls_certificate {
+                let resolver = std::sync::Arc::new(
+                    crate::tls::CertResolver::new(&cert.certificate, &cert.private_key)
+                        .map_err(Error::TlsError)?,
+                );
+                tls_acceptor = Some(tokio_rustls::TlsAcceptor::from(
+                    resolver.server_config(&[b"dot"]),
+                ));
+                for addr in &roconf
+                    .dns_tls_listeners
+                    .as_sockaddrs(&roconf.addresses, netinfo, 853)
+                    .await
+                {
+                    tls_listeners.push(Self::listen_tcp(&conf, addr, Protocol::Tls).await?);
+                }
             }
         }
         let rate_limiter = IpRateLimiter::new().into();
 
         Ok(Self {
-            next: acl::DnsAclHandler::new(conf).await,
+            next: acl::DnsAclHandler::new(conf.clone()).await,
             udp_listeners,
             tcp_listeners,
+            tls_listeners,
+            tls_acceptor,
             rate_limiter,
+            netinfo: netinfo.clone(),
+            conf,
         })
     }
 
@@ -739,6 +834,13 @@ impl DnsListenerHandler {
     async fn create_in_reply(msg: &DnsMessage, outr: &dnspkt::DNSPkt) -> dnspkt::DNSPkt {
         let mut edns: dnspkt::EdnsData = Default::default();
         Self::add_edns(&mut edns, msg).await;
+        if let Some((code, text)) = outr
+            .edns
+            .as_ref()
+            .and_then(|edns| edns.get_extended_dns_error())
+        {
+            edns.set_extended_dns_error(code, &text);
+        }
         dnspkt::DNSPkt {
             qid: msg.in_query.qid,
             rd: false,
@@ -760,7 +862,7 @@ impl DnsListenerHandler {
 
             question: msg.in_query.question.clone(),
             answer: outr.answer.clone(),
-            nameserver: outr.answer.clone(),
+            nameserver: outr.nameserver.clone(),
             additional: outr.additional.clone(),
             edns: Some(edns),
         }
@@ -776,8 +878,11 @@ impl DnsListenerHandler {
             /* These errors mean we never get a packet to reply to. */
             ListenError(..) => unreachable!(),
             AcceptError(..) => unreachable!(),
+            TlsError(_) => unreachable!(),
             RecvError(_) => unreachable!(),
             ParseError(_) => unreachable!(),
+            /* Dropped queries are never answered. */
+            Dropped => unreachable!(),
             RefusedByAcl(why) => {
                 rcode = REFUSED;
                 edns.set_extended_dns_error(EDE_PROHIBITED, &why.to_string());
@@ -801,6 +906,10 @@ impl DnsListenerHandler {
                 rcode = SERVFAIL;
                 edns.set_extended_dns_error(EDE_NOT_SUPPORTED, "No route configured for suffix");
             }
+            LeasesUnavailable(msg) => {
+                rcode = SERVFAIL;
+                edns.set_extended_dns_error(EDE_OTHER, &msg);
+            }
             OutReply(outquery::Error::Timeout) => {
                 rcode = SERVFAIL;
                 edns.set_extended_dns_error(
@@ -828,6 +937,10 @@ impl DnsListenerHandler {
                 rcode = SERVFAIL;
                 edns.set_extended_dns_error(EDE_NETWORK_ERROR, &msg);
             }
+            OutReply(outquery::Error::Http(msg)) => {
+                rcode = SERVFAIL;
+                edns.set_extended_dns_error(EDE_NETWORK_ERROR, &msg);
+            }
             OutReply(outquery::Error::Parse(msg)) => {
                 rcode = SERVFAIL;
                 edns.set_extended_dns_error(EDE_NETWORK_ERROR, &msg);
@@ -838,33 +951,59 @@ impl DnsListenerHandler {
             }
         }
         dnspkt::DNSPkt {
-            qid: msg.in_query.qid,
-            rd: false,
-            tc: false,
-            aa: false,
-            qr: true,
-            opcode: dnspkt::OPCODE_QUERY,
-            cd: false,
-            ad: false,
-            ra: true,
-            rcode,
-            bufsize: 4096,
             edns_ver: msg.in_query.edns_ver.map(|_| 0),
-            edns_do: false,
-
-            question: msg.in_query.question.clone(),
-            answer: vec![],
-            additional: vec![],
-            nameserver: vec![],
             edns: Some(edns),
+            ..dnspkt::DNSPkt::reply_to(&msg.in_query, rcode)
         }
     }
 
+    /// Finds the name of the interface a query arrived on.  If we don't know the interface index,
+    /// use the interface that has the address the query was sent to.
+    ///
+    /// This is only needed if an ACL or view matches on interfaces, otherwise it returns None
+    /// without looking.
+    async fn find_interface(
+        s: &std::sync::Arc<tokio::sync::RwLock<Self>>,
+        ifindex: Option<u32>,
+        local_ip: std::net::IpAddr,
+    ) -> Option<String> {
+        let netinfo = {
+            let this = s.read().await;
+            let conf = this.conf.read().await;
+            if !conf.acls.iter().any(|acl| acl.client.interfaces.is_some())
+                && !conf
+                    .dns_views
+                    .iter()
+                    .any(|view| view.client.interfaces.is_some())
+            {
+                return None;
+            }
+            this.netinfo.clone()
+        };
+        if let Some(ifindex) = ifindex.filter(|&ifindex| ifindex != 0) {
+            return netinfo.get_name_by_ifidx(ifindex).await;
+        }
+        let local_ip = local_ip.to_canonical();
+        for ifindex in netinfo.get_ifindexes().await {
+            if netinfo
+                .get_prefixes_by_ifidx(ifindex)
+                .await
+                .unwrap_or_default()
+                .iter()
+                .any(|(addr, _)| *addr == local_ip)
+            {
+                return netinfo.get_name_by_ifidx(ifindex).await;
+            }
+        }
+        None
+    }
+
     fn build_dns_message(
         pkt: &[u8],
         local_ip: std::net::IpAddr,
         remote_addr: NetAddr,
         protocol: Protocol,
+        interface: Option<String>,
     ) -> Result<DnsMessage, Error> {
         let in_query = parse::PktParser::new(pkt)
             .get_dns()
@@ -875,13 +1014,14 @@ impl DnsListenerHandler {
             remote_addr,
             protocol,
             in_size: pkt.len(),
+            interface,
         })
     }
 
     async fn recv_in_query(
         s: &std::sync::Arc<tokio::sync::RwLock<Self>>,
         msg: &DnsMessage,
-    ) -> Result<dnspkt::DNSPkt, std::convert::Infallible> {
+    ) -> Option<dnspkt::DNSPkt> {
         log::trace!(
             "[{:x}] In Query {}: {} ⇐ {}: {:?}",
             msg.in_query.qid,
@@ -899,6 +1039,13 @@ impl DnsListenerHandler {
                     .with_label_values(&[&msg.protocol.to_string(), &in_reply.status()])
                     .inc();
             }
+            Err(Error::Dropped) => {
+                log::trace!("[{:x}] Not Sending Reply: Dropped", msg.in_query.qid);
+                IN_QUERY_RESULT
+                    .with_label_values(&[&msg.protocol.to_string(), "DROPPED"])
+                    .inc();
+                return None;
+            }
             Err(err) => {
                 in_reply = Self::create_in_error(msg, err).await;
                 IN_QUERY_RESULT
@@ -907,7 +1054,7 @@ impl DnsListenerHandler {
             }
         }
         log::trace!("[{:x}] In Reply: {:?}", msg.in_query.qid, in_reply);
-        Ok(in_reply)
+        Some(in_reply)
     }
 
     async fn should_ratelimit(
@@ -980,14 +1127,20 @@ impl DnsListenerHandler {
         );
 
         tokio::spawn(async move {
+            let local_ip = rm.local_ip().unwrap(); /* TODO: Error? */
+            let interface =
+                Self::find_interface(&q, rm.local_intf().map(|i| i as u32), local_ip).await;
             match Self::build_dns_message(
                 &rm.buffer,
-                rm.local_ip().unwrap(), /* TODO: Error? */
-                rm.address.unwrap(),    /* TODO: Error? */
+                local_ip,
+                rm.address.unwrap(), /* TODO: Error? */
                 Protocol::Udp,
+                interface,
             ) {
                 Ok(msg) => {
-                    let in_reply = Self::recv_in_query(&q, &msg).await.unwrap();
+                    let Some(in_reply) = Self::recv_in_query(&q, &msg).await else {
+                        return;
+                    };
                     let in_reply_bytes = in_reply.serialise();
                     if !Self::should_ratelimit(
                         &msg,
@@ -1041,6 +1194,8 @@ impl DnsListenerHandler {
             sock_addr,
             sock.local_addr().unwrap(), /* TODO: Error? */
         );
+        let local_ip = sock.local_addr().ok().map(|addr| addr.ip()).unwrap(); /* TODO: Error? */
+        let interface = Self::find_interface(s, None, local_ip).await;
 
         let mut lbytes = [0u8; 2];
 
@@ -1067,14 +1222,11 @@ impl DnsListenerHandler {
 
         tokio::spawn(async move {
             use tokio::io::AsyncWriteExt as _;
-            match Self::build_dns_message(
-                &buffer,
-                sock.local_addr().ok().map(|addr| addr.ip()).unwrap(), /* TODO: Error? */
-                sock_addr,
-                Protocol::Tcp,
-            ) {
+            match Self::build_dns_message(&buffer, local_ip, sock_addr, Protocol::Tcp, interface) {
                 Ok(msg) => {
-                    let in_reply = Self::recv_in_query(&q, &msg).await.unwrap();
+                    let Some(in_reply) = Self::recv_in_query(&q, &msg).await else {
+                        return;
+                    };
                     let serialised =
                         Self::prepare_to_send(&in_reply, msg.in_query.bufsize as usize);
                     let mut in_reply_bytes = Vec::with_capacity(2 + serialised.len());
@@ -1112,6 +1264,145 @@ impl DnsListenerHandler {
         Ok(())
     }
 
+    async fn run_tls<S>(
+        s: &std::sync::Arc<tokio::sync::RwLock<Self>>,
+        sock: S,
+        local_ip: std::net::IpAddr,
+        sock_addr: NetAddr,
+    ) -> Result<(), Error>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use futures::FutureExt as _;
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        let interface = Self::find_interface(s, None, local_ip).await;
+        let (mut reader, mut writer) = tokio::io::split(sock);
+        let (reply_tx, mut reply_rx) = tokio::sync::mpsc::channel::<(u16, Vec<u8>)>(16);
+
+        /* Unlike plain TCP, TLS connections are expensive to set up, so clients (RFC7858) send
+         * many queries over the same connection.  Each query is answered in its own task, so a
+         * slow lookup doesn't hold up the queries behind it, which means replies may be sent out
+         * of order (RFC7858 §3.3).  We keep reading queries until the client closes the
+         * connection, or goes idle.
+         */
+        let read = async move {
+            loop {
+                let mut lbytes = [0u8; 2];
+                match tokio::time::timeout(TLS_IDLE_TIMEOUT, reader.read_exact(&mut lbytes)).await {
+                    Err(_) => return Ok(()),
+                    Ok(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        return Ok(())
+                    }
+                    Ok(Err(err)) => return Err(Error::RecvError(err)),
+                    Ok(Ok(_)) => (),
+                }
+
+                let mut buffer = vec![0u8; u16::from_be_bytes(lbytes) as usize];
+                reader
+                    .read_exact(&mut buffer[..])
+                    .await
+                    .map_err(Error::RecvError)?;
+                let timer = IN_QUERY_LATENCY.with_label_values(&["TLS"]).start_timer();
+
+                log::trace!(
+                    "Received TLS {:?} ⇒ {:?} ({})",
+                    sock_addr,
+                    local_ip,
+                    buffer.len()
+                );
+
+                let msg = match Self::build_dns_message(
+                    &buffer,
+                    local_ip,
+                    sock_addr,
+                    Protocol::Tls,
+                    interface.clone(),
+                ) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        IN_QUERY_RESULT
+                            .with_label_values(&["TLS", "parse fail"])
+                            .inc();
+                        return Err(err);
+                    }
+                };
+                let local_s = s.clone();
+                let reply_tx = reply_tx.clone();
+                tokio::spawn(async move {
+                    let Some(in_reply) = Self::recv_in_query(&local_s, &msg).await else {
+                        return;
+                    };
+                    let serialised =
+                        Self::prepare_to_send(&in_reply, msg.in_query.bufsize as usize);
+                    let mut in_reply_bytes = Vec::with_capacity(2 + serialised.len());
+                    in_reply_bytes.extend((serialised.len() as u16).to_be_bytes().iter());
+                    in_reply_bytes.extend(serialised);
+                    /* If the connection has gone away, there's nobody left to reply to. */
+                    let _ = reply_tx.send((msg.in_query.qid, in_reply_bytes)).await;
+                    drop(timer);
+                });
+            }
+        };
+
+        /* All the replies are written from here, so they don't interleave on the connection. */
+        let write = async move {
+            while let Some((qid, in_reply_bytes)) = reply_rx.recv().await {
+                if let Err(io) = writer.write_all(&in_reply_bytes).await {
+                    log::warn!("[{:x}] Failed to send DNS reply: {}", qid, io);
+                    IN_QUERY_RESULT
+                        .with_label_values(&["TLS", "send fail"])
+                        .inc();
+                    return;
+                }
+            }
+        };
+
+        let mut write = std::pin::pin!(write.fuse());
+        futures::select! {
+            /* Once the client has stopped sending queries, finish sending the replies to the
+             * queries that are still in flight.
+             */
+            ret = read.fuse() => {
+                write.await;
+                ret
+            },
+            () = write => Ok(()),
+        }
+    }
+
+    async fn run_tls_listener(
+        tcp: &tokio::net::TcpListener,
+        acceptor: &tokio_rustls::TlsAcceptor,
+        s: &std::sync::Arc<tokio::sync::RwLock<Self>>,
+    ) -> Result<(), Error> {
+        let (sock, sock_addr) = tcp.accept().await.map_err(Error::AcceptError)?;
+        let local_ip = sock.local_addr().map_err(Error::AcceptError)?.ip();
+        let local_s = s.clone();
+        let local_acceptor = acceptor.clone();
+
+        log::trace!("Received TLS connection {:?} ⇒ {:?}", sock_addr, local_ip);
+
+        tokio::spawn(async move {
+            let sock =
+                match tokio::time::timeout(TLS_IDLE_TIMEOUT, local_acceptor.accept(sock)).await {
+                    Ok(Ok(sock)) => sock,
+                    Ok(Err(err)) => {
+                        log::debug!("{}: TLS handshake failed: {}", sock_addr, err);
+                        return;
+                    }
+                    Err(_) => {
+                        log::debug!("{}: TLS handshake timed out", sock_addr);
+                        return;
+                    }
+                };
+            if let Err(err) = Self::run_tls(&local_s, sock, local_ip, sock_addr.into()).await {
+                log::warn!("{}: {}", sock_addr, err);
+            }
+        });
+
+        Ok(())
+    }
+
     async fn run(s: &std::sync::Arc<tokio::sync::RwLock<Self>>) -> Result<(), Error> {
         use futures::StreamExt as _;
         let mut services = futures::stream::FuturesUnordered::new();
@@ -1158,12 +1449,37 @@ impl DnsListenerHandler {
             }));
         }
 
+        if let Some(tls_acceptor) = my_self.tls_acceptor.clone() {
+            for listener in my_self.tls_listeners.drain(..) {
+                let s_clone = s.clone();
+                let acceptor = tls_acceptor.clone();
+                services.push(tokio::spawn(async move {
+                    loop {
+                        match Self::run_tls_listener(&listener, &acceptor, &s_clone).await {
+                            Ok(()) => (),
+                            Err(err) => {
+                                log::warn!(
+                                    "{}: {}",
+                                    listener
+                                        .local_addr()
+                                        .map(|a| format!("{}", a))
+                                        .unwrap_or_else(|e| format!("<unknown: {}>", e)),
+                                    err
+                                )
+                            }
+                        }
+                    }
+                }));
+            }
+        }
+
         drop(my_self);
 
         services.next().await.unwrap().unwrap()
     }
 }
 
+#[derive(Clone)]
 pub struct DnsService {
     next: std::sync::Arc<tokio::sync::RwLock<DnsListenerHandler>>,
 }
@@ -1175,6 +1491,44 @@ impl DnsService {
         }
     }
 
+    /// Answers a query that arrived over DNS over HTTPS (RFC8484).
+    ///
+    /// Returns the serialised reply, and the smallest TTL in it, so the HTTP response can be
+    /// given a matching freshness lifetime.
+    pub async fn handle_https_query(
+        &self,
+        pkt: &[u8],
+        local_ip: std::net::IpAddr,
+        remote_addr: NetAddr,
+    ) -> Result<(Vec<u8>, u32), Error> {
+        let timer = IN_QUERY_LATENCY.with_label_values(&["HTTPS"]).start_timer();
+        let interface = DnsListenerHandler::find_interface(&self.next, None, local_ip).await;
+        let msg = DnsListenerHandler::build_dns_message(
+            pkt,
+            local_ip,
+            remote_addr,
+            Protocol::Https,
+            interface,
+        )
+        .inspect_err(|_| {
+            IN_QUERY_RESULT
+                .with_label_values(&["HTTPS", "parse fail"])
+                .inc();
+        })?;
+        let in_reply = DnsListenerHandler::recv_in_query(&self.next, &msg)
+            .await
+            .ok_or(Error::Dropped)?;
+        let ttl = in_reply
+            .answer
+            .iter()
+            .chain(in_reply.nameserver.iter())
+            .map(|rr| rr.ttl)
+            .min()
+            .unwrap_or(0);
+        drop(timer);
+        Ok((in_reply.serialise(), ttl))
+    }
+
     pub async fn new(
         conf: crate::config::SharedConfig,
         netinfo: &erbium_net::netinfo::SharedNetInfo,
@@ -1183,4 +1537,138 @@ impl DnsService {
             next: tokio::sync::RwLock::new(DnsListenerHandler::new(conf, netinfo).await?).into(),
         })
     }
+
+    /// Saves any state that should survive a restart, such as the cache.
+    pub async fn shutdown(&self) {
+        self.next.read().await.next.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn test_tls_multiple_queries() {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+addresses: [192.0.2.0/24]
+dns-routes:
+  - domain-suffixes: ['']
+    type: forge-nxdomain
+",
+    )
+    .unwrap();
+    let handler = std::sync::Arc::new(tokio::sync::RwLock::new(DnsListenerHandler {
+        next: acl::DnsAclHandler::new(conf.clone()).await,
+        udp_listeners: vec![],
+        tcp_listeners: vec![],
+        tls_listeners: vec![],
+        tls_acceptor: None,
+        rate_limiter: IpRateLimiter::new().into(),
+        netinfo: erbium_net::netinfo::SharedNetInfo::new_for_test(),
+        conf,
+    }));
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(async move {
+        DnsListenerHandler::run_tls(
+            &handler,
+            server,
+            "192.0.2.1".parse().unwrap(),
+            std::net::SocketAddr::from(([192, 0, 2, 2], 12345)).into(),
+        )
+        .await
+    });
+
+    /* Both queries are sent before reading any replies, and both are answered, although not
+     * necessarily in order.
+     */
+    for qid in [1, 2] {
+        let query = dnspkt::DNSPkt {
+            qid,
+            ..DnsMessage::new_for_test("example.invalid", dnspkt::RR_A).in_query
+        }
+        .serialise();
+        client
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        client.write_all(&query).await.unwrap();
+    }
+    let mut qids = vec![];
+    for _ in 0..2 {
+        let mut lbytes = [0u8; 2];
+        client.read_exact(&mut lbytes).await.unwrap();
+        let mut reply = vec![0u8; u16::from_be_bytes(lbytes) as usize];
+        client.read_exact(&mut reply).await.unwrap();
+        let reply = parse::PktParser::new(&reply).get_dns().unwrap();
+        assert_eq!(reply.rcode, dnspkt::NXDOMAIN);
+        qids.push(reply.qid);
+    }
+    qids.sort();
+    assert_eq!(qids, [1, 2]);
+
+    /* Closing the connection finishes cleanly */
+    drop(client);
+    assert!(server.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_tls_out_of_order() {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    /* A nameserver that never answers, so queries forwarded to it are slow. */
+    let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let conf = crate::config::load_config_from_string_for_test(&format!(
+        "---
+addresses: [192.0.2.0/24]
+dns-routes:
+  - domain-suffixes: ['']
+    type: forge-nxdomain
+  - domain-suffixes: ['slow.example']
+    type: forward
+    dns-servers: ['{}']
+",
+        silent.local_addr().unwrap()
+    ))
+    .unwrap();
+    let handler = std::sync::Arc::new(tokio::sync::RwLock::new(DnsListenerHandler {
+        next: acl::DnsAclHandler::new(conf.clone()).await,
+        udp_listeners: vec![],
+        tcp_listeners: vec![],
+        tls_listeners: vec![],
+        tls_acceptor: None,
+        rate_limiter: IpRateLimiter::new().into(),
+        netinfo: erbium_net::netinfo::SharedNetInfo::new_for_test(),
+        conf,
+    }));
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(async move {
+        DnsListenerHandler::run_tls(
+            &handler,
+            server,
+            "192.0.2.1".parse().unwrap(),
+            std::net::SocketAddr::from(([192, 0, 2, 2], 12345)).into(),
+        )
+        .await
+    });
+
+    /* The slow query is sent first, but the query behind it is answered without waiting. */
+    for (qid, name) in [(1, "slow.example"), (2, "example.invalid")] {
+        let query = dnspkt::DNSPkt {
+            qid,
+            ..DnsMessage::new_for_test(name, dnspkt::RR_A).in_query
+        }
+        .serialise();
+        client
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        client.write_all(&query).await.unwrap();
+    }
+    let mut lbytes = [0u8; 2];
+    client.read_exact(&mut lbytes).await.unwrap();
+    let mut reply = vec![0u8; u16::from_be_bytes(lbytes) as usize];
+    client.read_exact(&mut reply).await.unwrap();
+    let reply = parse::PktParser::new(&reply).get_dns().unwrap();
+    assert_eq!(reply.qid, 2);
+    assert_eq!(reply.rcode, dnspkt::NXDOMAIN);
+
+    server.abort();
 }
@@ -21,8 +21,9 @@ use rand::RngCore;
 use std::cell::Cell;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::Mutex;
 use tokio::time::{Duration, Instant};
+use tokio_rustls::rustls;
 
 use crate::dns::dnspkt;
 use crate::dns::parse;
@@ -42,13 +43,11 @@ use crate::dns::parse;
  * needed that DNS response still has some time to complete before the ~1s perceptual deadline is
  * hit.
  *
- * TODO:
- *  - This should be per nameserver, rather than for all nameservers.  We should construct a
- *  per nameserver object and pass that around instead of this global.
+ * Once we have heard back from a nameserver, we track its round trip time, and how much that
+ * varies, and derive the timeout from those in the same way as TCP (RFC6298).  This is kept per
+ * nameserver, as a resolver over a VPN might be much slower than the one at the ISP.
  */
-lazy_static::lazy_static! {
-    static ref DNS_TIMEOUT: RwLock<Duration> = RwLock::new(Duration::from_millis(800));
-}
+const INITIAL_DNS_TIMEOUT: Duration = Duration::from_millis(800);
 
 /* Since the DNS timeout is dynamic, we want to make sure it doesn't somehow get crazily out of
  * bounds due to some weird effects.
@@ -56,10 +55,21 @@ lazy_static::lazy_static! {
 const MIN_DNS_TIMEOUT: Duration = Duration::from_millis(300);
 const MAX_DNS_TIMEOUT: Duration = Duration::from_millis(2000);
 
+/* When a nameserver fails to answer, we try the other nameservers first for this long before
+ * giving it another chance.
+ */
+const NAMESERVER_DOWN_TIME: Duration = Duration::from_secs(30);
+
+/* If a TCP or TLS nameserver doesn't reply to a query in this long, give up on it so we can try
+ * another nameserver.
+ */
+const STREAM_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
 /* Wow, this is a surprising amount of code for handling outbound TCP queries.
  * We only want to create one TCP connection, and send all queries over that, handling the fact
  * that they can come back out of order.  We also don't want to hold open the TCP socket
- * needlessly.
+ * needlessly.  DNS over TLS (RFC7858) works exactly the same way, just with TLS over the TCP
+ * connection.
  *
  * So we spawn a separate task per nameserver, with a channel to send queries
  * (TcpNameserverMessage) on.  This message contains a oneshot reply channel that gets the reply
@@ -68,7 +78,15 @@ const MAX_DNS_TIMEOUT: Duration = Duration::from_millis(2000);
 type TcpNameserverChannel = tokio::sync::mpsc::Sender<TcpNameserverMessage>;
 
 lazy_static::lazy_static! {
-    static ref NAMESERVER_INFO: tokio::sync::Mutex<std::collections::HashMap<std::net::SocketAddr,TcpNameserverChannel>> = Default::default();
+    static ref NAMESERVER_INFO: tokio::sync::Mutex<std::collections::HashMap<Nameserver,TcpNameserverChannel>> = Default::default();
+
+    static ref NAMESERVER_STATE: std::sync::Mutex<std::collections::HashMap<Nameserver, NameserverState>> = Default::default();
+
+    static ref HTTPS_NAMESERVERS: std::sync::Mutex<std::collections::HashMap<hyper::Uri, Arc<HttpsNameserver>>> = Default::default();
+
+    static ref TLS_CLIENT_CONFIG: Arc<rustls::ClientConfig> = tls_client_config(webpki_roots(), b"dot");
+
+    static ref HTTPS_CLIENT_CONFIG: Arc<rustls::ClientConfig> = tls_client_config(webpki_roots(), b"h2");
 
     static ref DNS_SENT_QUERIES: prometheus::IntCounterVec =
         prometheus::register_int_counter_vec!("dns_out_query_packets_sent",
@@ -101,10 +119,114 @@ lazy_static::lazy_static! {
             &["dns_server"])
         .unwrap();
 
-    // TODO: This should be per nameserver.
-    static ref OUT_QUERY_TIMEOUT: prometheus::IntGauge =
-        prometheus::register_int_gauge!("dns_out_query_timeout_ms",
-            "The current dynamic timeout for out queries").unwrap();
+    static ref OUT_QUERY_SERVER_DOWN: prometheus::IntGaugeVec =
+        prometheus::register_int_gauge_vec!("dns_out_query_server_down",
+            "1 if the nameserver has recently failed, and is only used if the others also fail",
+            &["dns_server"])
+        .unwrap();
+
+    static ref OUT_QUERY_TIMEOUT: prometheus::IntGaugeVec =
+        prometheus::register_int_gauge_vec!("dns_out_query_timeout_ms",
+            "The current dynamic timeout for out queries",
+            &["dns_server"])
+        .unwrap();
+
+    static ref OUT_QUERY_SRTT: prometheus::IntGaugeVec =
+        prometheus::register_int_gauge_vec!("dns_out_query_srtt_ms",
+            "Smoothed round trip time estimate for out queries",
+            &["dns_server"])
+        .unwrap();
+
+    static ref OUT_QUERY_RTTVAR: prometheus::IntGaugeVec =
+        prometheus::register_int_gauge_vec!("dns_out_query_rttvar_ms",
+            "Round trip time variation estimate for out queries",
+            &["dns_server"])
+        .unwrap();
+
+    static ref OUT_QUERY_FAILURES: prometheus::IntGaugeVec =
+        prometheus::register_int_gauge_vec!("dns_out_query_consecutive_failures",
+            "Number of out queries that have failed in a row",
+            &["dns_server"])
+        .unwrap();
+}
+
+/// What we have learnt about a nameserver from previous queries.
+#[derive(Debug, Default)]
+struct NameserverState {
+    /// Smoothed round trip time, or None if we've never had a reply.
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// How many queries to this nameserver have failed in a row.
+    failures: u32,
+    /// If this nameserver has recently failed, when it should be tried first again.
+    down_until: Option<Instant>,
+}
+
+impl NameserverState {
+    /// How long to wait for a reply before retransmitting.
+    fn timeout(&self) -> Duration {
+        match self.srtt {
+            None => INITIAL_DNS_TIMEOUT,
+            Some(srtt) => (srtt + self.rttvar * 4).clamp(MIN_DNS_TIMEOUT, MAX_DNS_TIMEOUT),
+        }
+    }
+
+    /// Updates the round trip time estimates with a new measurement (RFC6298 Section 2).
+    fn add_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    fn record_result(&mut self, failed: bool, now: Instant) {
+        if failed {
+            self.failures += 1;
+            self.down_until = Some(now + NAMESERVER_DOWN_TIME);
+        } else {
+            self.failures = 0;
+            self.down_until = None;
+        }
+    }
+
+    fn is_down(&self, now: Instant) -> bool {
+        self.down_until.map(|until| until > now).unwrap_or(false)
+    }
+
+    fn update_metrics(&self, ns: &Nameserver) {
+        let addr = ns.to_string();
+        OUT_QUERY_TIMEOUT
+            .with_label_values(&[&addr])
+            .set(self.timeout().as_millis() as i64);
+        OUT_QUERY_SRTT
+            .with_label_values(&[&addr])
+            .set(self.srtt.unwrap_or_default().as_millis() as i64);
+        OUT_QUERY_RTTVAR
+            .with_label_values(&[&addr])
+            .set(self.rttvar.as_millis() as i64);
+        OUT_QUERY_FAILURES
+            .with_label_values(&[&addr])
+            .set(self.failures.into());
+        OUT_QUERY_SERVER_DOWN
+            .with_label_values(&[&addr])
+            .set(self.is_down(Instant::now()) as i64);
+    }
+}
+
+/// Runs f on the state for a nameserver, and updates the metrics for it afterwards.
+fn with_nameserver_state<T>(ns: &Nameserver, f: impl FnOnce(&mut NameserverState) -> T) -> T {
+    let mut states = NAMESERVER_STATE.lock().unwrap();
+    let state = states.entry(ns.clone()).or_default();
+    let ret = f(state);
+    state.update_metrics(ns);
+    ret
 }
 
 #[derive(Debug)]
@@ -115,6 +237,7 @@ pub enum Error {
     FailedToRecv(std::io::Error),
     FailedToRecvMsg(String),
     TcpConnection(String),
+    Http(String),
     Parse(String),
     Internal(String),
 }
@@ -131,6 +254,7 @@ impl std::fmt::Display for Error {
             TcpConnection(err) => {
                 write!(f, "TCP connection error while waiting for result: {}", err)
             }
+            Http(err) => write!(f, "HTTP error from upstream server: {}", err),
             Parse(err) => write!(f, "Failed to parse out reply: {}", err),
             Internal(err) => write!(f, "Internal error in out query handling: {}", err),
         }
@@ -139,6 +263,111 @@ impl std::fmt::Display for Error {
 
 type Protocol = super::Protocol;
 
+/// A nameserver that we forward queries to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Nameserver {
+    /// Plain DNS over UDP, falling back to TCP when needed.
+    Plain(std::net::SocketAddr),
+    /// DNS over TLS (RFC7858).  The nameserver's certificate must be valid for name.
+    Tls {
+        name: String,
+        addr: std::net::SocketAddr,
+    },
+    /// DNS over HTTPS (RFC8484), queries are POSTed to this URL.
+    Https(hyper::Uri),
+}
+
+impl Nameserver {
+    /// The protocol label used in metrics for queries sent over a stream to this nameserver.
+    fn stream_protocol(&self) -> &'static str {
+        match self {
+            Nameserver::Plain(_) => "TCP",
+            Nameserver::Tls { .. } => "TLS",
+            Nameserver::Https(_) => "doh",
+        }
+    }
+}
+
+impl std::fmt::Display for Nameserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Nameserver::Plain(addr) => write!(f, "{}", addr),
+            Nameserver::Tls { name, addr } => write!(f, "tls://{}@{}", name, addr),
+            Nameserver::Https(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Parses an address with an optional port.
+fn parse_addr(s: &str, default_port: u16) -> Option<std::net::SocketAddr> {
+    s.parse()
+        .ok()
+        .or_else(|| Some(std::net::SocketAddr::new(s.parse().ok()?, default_port)))
+}
+
+impl std::str::FromStr for Nameserver {
+    type Err = String;
+    /// Nameservers are either an IP address (with an optional port), tls://name@ip for DNS
+    /// over TLS, or an https:// URL for DNS over HTTPS.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://") {
+            let url: hyper::Uri = s
+                .parse()
+                .map_err(|e| format!("Invalid DNS over HTTPS URL {:?}: {}", s, e))?;
+            let host = url
+                .host()
+                .ok_or_else(|| format!("DNS over HTTPS URL {:?} has no host", s))?;
+            rustls::pki_types::ServerName::try_from(url_host(host))
+                .map_err(|e| format!("Invalid TLS name {:?}: {}", host, e))?;
+            Ok(Nameserver::Https(url))
+        } else if let Some(rest) = s.strip_prefix("tls://") {
+            let (name, addr) = rest
+                .split_once('@')
+                .ok_or_else(|| format!("{} should be in the form tls://name@ip", s))?;
+            rustls::pki_types::ServerName::try_from(name)
+                .map_err(|e| format!("Invalid TLS name {:?}: {}", name, e))?;
+            Ok(Nameserver::Tls {
+                name: name.into(),
+                addr: parse_addr(addr, 853)
+                    .ok_or_else(|| format!("Invalid nameserver address {:?}", addr))?,
+            })
+        } else {
+            Ok(Nameserver::Plain(parse_addr(s, 53).ok_or_else(|| {
+                format!("Invalid nameserver address {:?}", s)
+            })?))
+        }
+    }
+}
+
+/// Removes the brackets from an IPv6 literal in a URL.
+fn url_host(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+fn webpki_roots() -> rustls::RootCertStore {
+    rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+fn tls_client_config(roots: rustls::RootCertStore, alpn: &[u8]) -> Arc<rustls::ClientConfig> {
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .expect("ring supports the default protocol versions")
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Arc::new(config)
+}
+
+/// Either a plain TCP connection, or a TLS connection over TCP.
+trait Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send> Stream for T {}
+
 fn increment_result(dns_server: &str, result: &Result<dnspkt::DNSPkt, Error>) {
     OUT_QUERY_RESULT
         .with_label_values(&[
@@ -160,11 +389,70 @@ fn increment_result(dns_server: &str, result: &Result<dnspkt::DNSPkt, Error>) {
                 Err(Error::Parse(msg)) => format!("PARSE_ERROR: {}", msg),
                 Err(Error::Internal(msg)) => format!("INTERNAL: {}", msg),
                 Err(Error::TcpConnection(msg)) => format!("TCP: {}", msg),
+                Err(Error::Http(msg)) => format!("HTTP: {}", msg),
             },
         ])
         .inc()
 }
 
+/// Should we give up on this nameserver and try another one?
+fn is_failure(result: &Result<dnspkt::DNSPkt, Error>) -> bool {
+    match result {
+        Ok(pkt) => pkt.rcode == dnspkt::SERVFAIL || pkt.rcode == dnspkt::REFUSED,
+        Err(_) => true,
+    }
+}
+
+/// Returns the mean latency seen from a nameserver, or zero if we have never heard from it (so it
+/// gets tried).
+fn mean_latency(ns: &Nameserver) -> f64 {
+    let protocol = match ns {
+        Nameserver::Plain(_) => "UDP",
+        _ => ns.stream_protocol(),
+    };
+    let hist = OUT_QUERY_LATENCY.with_label_values(&[&ns.to_string(), protocol]);
+    match hist.get_sample_count() {
+        0 => 0.0,
+        count => hist.get_sample_sum() / count as f64,
+    }
+}
+
+/// Sorts nameservers into the order they should be tried.  Nameservers that are up are tried
+/// fastest first, followed by nameservers that are down, starting with the one that failed longest
+/// ago.
+fn rank_nameservers(mut candidates: Vec<(Option<Instant>, f64, Nameserver)>) -> Vec<Nameserver> {
+    candidates.sort_by(|a, b| {
+        a.0.cmp(&b.0)
+            .then(a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+    });
+    candidates.into_iter().map(|(_, _, ns)| ns).collect()
+}
+
+fn order_nameservers(servers: &[Nameserver]) -> Vec<Nameserver> {
+    use rand::Rng as _;
+    let now = Instant::now();
+    let states = NAMESERVER_STATE.lock().unwrap();
+    let mut rng = rand::thread_rng();
+    rank_nameservers(
+        servers
+            .iter()
+            .map(|ns| {
+                (
+                    states
+                        .get(ns)
+                        .and_then(|state| state.down_until)
+                        .filter(|until| *until > now),
+                    /* Add some jitter so nameservers that are about as fast as each other share
+                     * the load.
+                     */
+                    mean_latency(ns) * rng.gen_range(1.0..1.5),
+                    ns.clone(),
+                )
+            })
+            .collect(),
+    )
+}
+
 type Responder<T> = tokio::sync::oneshot::Sender<Result<T, Error>>;
 
 struct TcpNameserverMessage {
@@ -172,19 +460,29 @@ struct TcpNameserverMessage {
     out_reply: Responder<super::dnspkt::DNSPkt>,
 }
 
+/// A query that has been sent on a TCP channel, and is waiting for its reply.
+struct TcpNameserverQuery {
+    /// The qid the client asked with, which may differ from the one sent if it collided.
+    qid: u16,
+    question: super::dnspkt::Question,
+    reply: Responder<super::dnspkt::DNSPkt>,
+}
+
 struct TcpNameserver {
-    addr: std::net::SocketAddr,
-    tcp: Option<tokio::net::TcpStream>,
+    server: Nameserver,
+    tls_config: Arc<rustls::ClientConfig>,
+    tcp: Option<Box<dyn Stream>>,
     tcp_last_send_activity: Instant,
     tcp_last_recv_activity: Instant,
-    qid2reply: std::collections::HashMap<u16, Responder<super::dnspkt::DNSPkt>>,
+    qid2reply: std::collections::HashMap<u16, TcpNameserverQuery>,
 }
 
 impl TcpNameserver {
-    fn start(addr: std::net::SocketAddr) -> TcpNameserverChannel {
+    fn start(server: Nameserver, tls_config: Arc<rustls::ClientConfig>) -> TcpNameserverChannel {
         let (tx, rx) = tokio::sync::mpsc::channel(2);
         let ret = Box::new(Self {
-            addr,
+            server,
+            tls_config,
             tcp: None,
             tcp_last_send_activity: Instant::now(),
             tcp_last_recv_activity: Instant::now(),
@@ -197,18 +495,32 @@ impl TcpNameserver {
     }
 
     async fn send_query_to(
-        addr: &std::net::SocketAddr,
+        server: &Nameserver,
+        out_query: super::dnspkt::DNSPkt,
+    ) -> Result<super::dnspkt::DNSPkt, Error> {
+        let chan = {
+            let mut info = NAMESERVER_INFO.lock().await;
+            match info.get(server) {
+                Some(chan) if !chan.is_closed() => chan.clone(),
+                /* Either we've never talked to this server, or its task has died, (re)start it. */
+                _ => {
+                    let chan = TcpNameserver::start(server.clone(), TLS_CLIENT_CONFIG.clone());
+                    info.insert(server.clone(), chan.clone());
+                    chan
+                }
+            }
+        };
+        Self::send_query_on(&chan, server, out_query).await
+    }
+
+    async fn send_query_on(
+        chan: &TcpNameserverChannel,
+        server: &Nameserver,
         out_query: super::dnspkt::DNSPkt,
     ) -> Result<super::dnspkt::DNSPkt, Error> {
-        let chan = NAMESERVER_INFO
-            .lock()
-            .await
-            .entry(*addr)
-            .or_insert_with(|| TcpNameserver::start(*addr))
-            .clone();
         let (tx, rx) = tokio::sync::oneshot::channel();
         let _timer = OUT_QUERY_LATENCY
-            .with_label_values(&[&addr.to_string(), "TCP"])
+            .with_label_values(&[&server.to_string(), server.stream_protocol()])
             .start_timer();
         chan.send(TcpNameserverMessage {
             out_query,
@@ -216,25 +528,65 @@ impl TcpNameserver {
         })
         .await
         .map_err(|err| Error::Internal(format!("Channel send failed: {}", err)))?;
-        match rx.await {
-            Ok(ret) => ret,
-            Err(err) => Err(Error::Internal(format!("Channel recv failed: {}", err))),
+        match tokio::time::timeout(STREAM_QUERY_TIMEOUT, rx).await {
+            Ok(Ok(ret)) => ret,
+            Ok(Err(err)) => Err(Error::Internal(format!("Channel recv failed: {}", err))),
+            Err(_) => Err(Error::Timeout),
         }
     }
 
-    async fn send_tcp_reply(&mut self, qid: u16, reply: Result<super::dnspkt::DNSPkt, Error>) {
-        if let Some(resp) = self.qid2reply.remove(&qid) {
-            resp.send(reply).unwrap();
-        } else {
-            log::error!("Sending reply to unknown request: {:?}", reply);
+    async fn send_tcp_reply(&mut self, mut reply: super::dnspkt::DNSPkt) {
+        match self.qid2reply.remove(&reply.qid) {
+            Some(query) if query.question == reply.question => {
+                reply.qid = query.qid;
+                /* If the client has given up waiting, there's nobody to tell. */
+                let _ = query.reply.send(Ok(reply));
+            }
+            Some(query) => {
+                log::error!("Reply doesn't match the question asked: {:?}", reply);
+                self.qid2reply.insert(reply.qid, query);
+            }
+            None => log::error!("Sending reply to unknown request: {:?}", reply),
         }
     }
 
-    async fn send_tcp_query(&mut self, msg: TcpNameserverMessage) -> Result<(), Error> {
-        assert!(self
-            .qid2reply
-            .insert(msg.out_query.qid, msg.out_reply)
-            .is_none()); // TODO: Collisions!
+    /// Picks the qid to send a query with on this channel.  This is the client's own qid unless
+    /// another outstanding query is already using it, in which case a random unused one is chosen.
+    fn allocate_qid(&mut self, qid: u16) -> Option<u16> {
+        /* Clients that have timed out have dropped their receiver, forget about them. */
+        self.qid2reply.retain(|_, query| !query.reply.is_closed());
+        if !self.qid2reply.contains_key(&qid) {
+            return Some(qid);
+        }
+        if self.qid2reply.len() > u16::MAX as usize {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        loop {
+            let qid = rng.next_u32() as u16;
+            if !self.qid2reply.contains_key(&qid) {
+                return Some(qid);
+            }
+        }
+    }
+
+    async fn send_tcp_query(&mut self, mut msg: TcpNameserverMessage) -> Result<(), Error> {
+        let Some(qid) = self.allocate_qid(msg.out_query.qid) else {
+            let _ = msg.out_reply.send(Err(Error::Internal(format!(
+                "No free query ids left on {}",
+                self.server
+            ))));
+            return Ok(());
+        };
+        self.qid2reply.insert(
+            qid,
+            TcpNameserverQuery {
+                qid: msg.out_query.qid,
+                question: msg.out_query.question.clone(),
+                reply: msg.out_reply,
+            },
+        );
+        msg.out_query.qid = qid;
         if let Some(ref mut tcp_sock) = self.tcp {
             use tokio::io::AsyncWriteExt as _;
             let bytes = msg.out_query.serialise();
@@ -246,7 +598,7 @@ impl TcpNameserver {
             buf.extend((bytes.len() as u16).to_be_bytes().iter());
             buf.extend(bytes);
             DNS_SENT_QUERIES
-                .with_label_values(&[&self.addr.to_string(), "TCP"])
+                .with_label_values(&[&self.server.to_string(), self.server.stream_protocol()])
                 .inc();
             let ret = tcp_sock.write_all(&buf).await.map_err(Error::FailedToSend);
             self.tcp_last_send_activity = Instant::now();
@@ -287,18 +639,46 @@ impl TcpNameserver {
                 return;
             }
         };
-        self.send_tcp_reply(pkt.qid, Ok(pkt)).await
+        self.send_tcp_reply(pkt).await
     }
 
     fn tcp_teardown(&mut self, err: Error) {
         self.tcp = None;
-        log::trace!("Tearing down {} TCP channel: {}", self.addr, err);
-        for (_qid, chan) in self.qid2reply.drain() {
-            chan.send(Err(Error::TcpConnection(format!(
+        log::trace!("Tearing down {} TCP channel: {}", self.server, err);
+        for (_qid, query) in self.qid2reply.drain() {
+            let _ = query.reply.send(Err(Error::TcpConnection(format!(
                 "TCP channel closed before reply: {}",
                 err
-            ))))
-            .unwrap();
+            ))));
+        }
+    }
+
+    async fn connect(
+        server: &Nameserver,
+        tls_config: &Arc<rustls::ClientConfig>,
+    ) -> Result<Box<dyn Stream>, Error> {
+        let addr = match server {
+            Nameserver::Plain(addr) | Nameserver::Tls { addr, .. } => *addr,
+            Nameserver::Https(_) => {
+                return Err(Error::Internal(
+                    "DNS over HTTPS nameservers don't use TCP channels".into(),
+                ))
+            }
+        };
+        let tcp = tokio::net::TcpStream::connect(addr)
+            .await
+            .map_err(Error::FailedToSend)?;
+        match server {
+            Nameserver::Tls { name, .. } => {
+                let name = rustls::pki_types::ServerName::try_from(name.clone())
+                    .map_err(|e| Error::TcpConnection(e.to_string()))?;
+                let tls = tokio_rustls::TlsConnector::from(tls_config.clone())
+                    .connect(name, tcp)
+                    .await
+                    .map_err(|e| Error::TcpConnection(format!("TLS handshake failed: {}", e)))?;
+                Ok(Box::new(tls))
+            }
+            _ => Ok(Box::new(tcp)),
         }
     }
 
@@ -348,12 +728,12 @@ impl TcpNameserver {
                 }
             } else if let Some(msg) = chan.recv().await {
                 /* We've not already opened the tcp connection, so open it now. */
-                log::trace!("Opening new TCP channel to {}", self.addr);
-                match tokio::net::TcpStream::connect(self.addr).await {
+                log::trace!("Opening new TCP channel to {}", self.server);
+                match Self::connect(&self.server, &self.tls_config).await {
                     Ok(sock) => self.tcp = Some(sock),
                     /* If we can't open the channel, report the error, and give up. */
                     Err(err) => {
-                        msg.out_reply.send(Err(Error::FailedToSend(err))).unwrap();
+                        let _ = msg.out_reply.send(Err(err));
                         continue;
                     }
                 }
@@ -372,6 +752,150 @@ impl TcpNameserver {
     }
 }
 
+/* DNS over HTTPS (RFC8484) is a lot simpler, as HTTP/2 already multiplexes requests over a single
+ * connection and matches up the replies for us.  We keep the connection open for as long as the
+ * server is willing to, and reconnect if it goes away.
+ */
+type HttpsSender = hyper::client::conn::http2::SendRequest<http_body_util::Full<bytes::Bytes>>;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+struct HttpsNameserver {
+    server: Nameserver,
+    url: hyper::Uri,
+    tls_config: Arc<rustls::ClientConfig>,
+    sender: Mutex<Option<HttpsSender>>,
+}
+
+impl HttpsNameserver {
+    fn new(url: hyper::Uri, tls_config: Arc<rustls::ClientConfig>) -> Self {
+        Self {
+            server: Nameserver::Https(url.clone()),
+            url,
+            tls_config,
+            sender: Default::default(),
+        }
+    }
+
+    async fn send_query_to(
+        url: &hyper::Uri,
+        out_query: dnspkt::DNSPkt,
+    ) -> Result<dnspkt::DNSPkt, Error> {
+        let ns = HTTPS_NAMESERVERS
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_insert_with(|| {
+                Arc::new(HttpsNameserver::new(
+                    url.clone(),
+                    HTTPS_CLIENT_CONFIG.clone(),
+                ))
+            })
+            .clone();
+        ns.send_query(out_query).await
+    }
+
+    async fn connect(&self) -> Result<HttpsSender, Error> {
+        let host = url_host(self.url.host().unwrap_or_default());
+        let port = self.url.port_u16().unwrap_or(443);
+        log::trace!("Opening new HTTPS connection to {}", self.url);
+        let tcp = tokio::net::TcpStream::connect((host, port))
+            .await
+            .map_err(Error::FailedToSend)?;
+        let name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(|e| Error::TcpConnection(e.to_string()))?;
+        let tls = tokio_rustls::TlsConnector::from(self.tls_config.clone())
+            .connect(name, tcp)
+            .await
+            .map_err(|e| Error::TcpConnection(format!("TLS handshake failed: {}", e)))?;
+        let (sender, conn) = hyper::client::conn::http2::handshake(
+            hyper_util::rt::TokioExecutor::new(),
+            hyper_util::rt::TokioIo::new(tls),
+        )
+        .await
+        .map_err(|e| Error::TcpConnection(format!("HTTP/2 handshake failed: {}", e)))?;
+        let url = self.url.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                log::trace!("HTTPS connection to {} closed: {}", url, err);
+            }
+        });
+        Ok(sender)
+    }
+
+    /// Returns the open connection to the server, opening a new one if needed.
+    async fn sender(&self) -> Result<HttpsSender, Error> {
+        let mut sender = self.sender.lock().await;
+        match &*sender {
+            Some(open) if !open.is_closed() => Ok(open.clone()),
+            _ => {
+                let open = self.connect().await?;
+                *sender = Some(open.clone());
+                Ok(open)
+            }
+        }
+    }
+
+    async fn send_query_internal(
+        &self,
+        out_query: &dnspkt::DNSPkt,
+    ) -> Result<dnspkt::DNSPkt, Error> {
+        /* RFC8484 Section 4.1: Use a query id of 0 to make the query more cache friendly.  HTTP
+         * tells us which reply goes with which query.
+         */
+        let mut query = out_query.clone();
+        query.qid = 0;
+        let request = hyper::Request::post(self.url.clone())
+            .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(hyper::header::ACCEPT, DNS_MESSAGE)
+            .body(http_body_util::Full::new(bytes::Bytes::from(
+                query.serialise(),
+            )))
+            .map_err(|e| Error::Internal(format!("Failed to build HTTP request: {}", e)))?;
+        let mut sender = self.sender().await?;
+        DNS_SENT_QUERIES
+            .with_label_values(&[&self.server.to_string(), "doh"])
+            .inc();
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| Error::Http(e.to_string()))?;
+        if response.status() != hyper::StatusCode::OK {
+            return Err(Error::Http(format!(
+                "Unexpected HTTP status {}",
+                response.status()
+            )));
+        }
+        let content_type = response.headers().get(hyper::header::CONTENT_TYPE);
+        if content_type.map(|ct| ct != DNS_MESSAGE).unwrap_or(true) {
+            return Err(Error::Http(format!(
+                "Unexpected Content-Type {:?}",
+                content_type
+            )));
+        }
+        use http_body_util::BodyExt as _;
+        let body = http_body_util::Limited::new(response.into_body(), 65535)
+            .collect()
+            .await
+            .map_err(|e| Error::FailedToRecvMsg(e.to_string()))?
+            .to_bytes();
+        let mut reply = parse::PktParser::new(&body)
+            .get_dns()
+            .map_err(Error::Parse)?;
+        reply.qid = out_query.qid;
+        Ok(reply)
+    }
+
+    async fn send_query(&self, out_query: dnspkt::DNSPkt) -> Result<dnspkt::DNSPkt, Error> {
+        let _timer = OUT_QUERY_LATENCY
+            .with_label_values(&[&self.server.to_string(), "doh"])
+            .start_timer();
+        tokio::time::timeout(STREAM_QUERY_TIMEOUT, self.send_query_internal(&out_query))
+            .await
+            .unwrap_or(Err(Error::Timeout))
+    }
+}
+
 fn create_outquery(id: u16, in_query: &dnspkt::DNSPkt) -> dnspkt::DNSPkt {
     dnspkt::DNSPkt {
         qid: id,
@@ -459,9 +983,8 @@ impl OutQuery {
         let mut attempts = futures::stream::FuturesUnordered::new();
         log::trace!("OutQuery: {:?}", oq);
 
-        let initial_timeout: Duration = *DNS_TIMEOUT.read().await;
-        OUT_QUERY_TIMEOUT.set(initial_timeout.as_millis() as i64);
-        let mut timeout = initial_timeout;
+        let ns = Nameserver::Plain(addr);
+        let mut timeout = with_nameserver_state(&ns, |state| state.timeout());
         let _timer = OUT_QUERY_LATENCY
             .with_label_values(&[&addr.to_string(), "UDP"])
             .start_timer();
@@ -477,46 +1000,10 @@ impl OutQuery {
                         None => Err(Error::FailedToRecvMsg("No attempts made".into())),
                         Some(Err(e)) => Err(e),
                         Some(Ok((dur, pkt))) => {
-                            if attempts.len() > 1 {
-                                // If we made multiple attempts, then we should figure out what
-                                // caused us to make multiple attempts, and try and optimise for
-                                // that situation.
-                                let mut timeout = DNS_TIMEOUT.write().await;
-                                if dur < initial_timeout {
-                                    // If the reply was shorter than the initial timeout, then
-                                    // there's a good chance that this was due to the original
-                                    // packet being lost.  In that case, we should lower our
-                                    // estimate of the initial timeout, so we recover from packet
-                                    // loss faster.  We EWMA our estimate towards the time it took.
-                                    if dur <= *timeout {
-                                        // Alpha is how fast we adapt to changes (between 0..BASE)
-                                        const ALPHA : u32 = 10;
-                                        const BASE : u32 = 1000;
-                                        let new_timeout = (dur * ALPHA + *timeout * ( BASE - ALPHA)) / BASE;
-                                        *timeout = std::cmp::max(
-                                            std::cmp::min(new_timeout, MAX_DNS_TIMEOUT),
-                                            MIN_DNS_TIMEOUT);
-                                    }
-                                } else {
-                                    // If the reply was not shorter than the initial timeout, then
-                                    // the initial timeout was too short (the recursive server just
-                                    // took a long time to reply) and we needlessly sent a
-                                    // retransmission.  In this case, we should increase our
-                                    // estimate of the initial timeout to be at least as long as
-                                    // the timeout.
-
-                                    // We don't want to set this directly to the time it took, we
-                                    // want to provide some headroom as this is unlikely to be the
-                                    // slowest that the server can be.  So increase duration by
-                                    // the ratio HEADROOM/BASE.
-                                    const HEADROOM : u32 = 10;
-                                    const BASE : u32 = 100;
-                                    let new_timeout = dur * (BASE + HEADROOM/BASE);
-                                    *timeout = std::cmp::max(
-                                        std::cmp::min(std::cmp::max(*timeout, new_timeout), MAX_DNS_TIMEOUT),
-                                        MIN_DNS_TIMEOUT);
-                                }
-                            }
+                            // Each attempt is sent from its own socket, so we know exactly which
+                            // attempt this is the reply to, and the round trip time is
+                            // unambiguous.
+                            with_nameserver_state(&ns, |state| state.add_rtt_sample(dur));
                             Ok(pkt)
                         }
                     },
@@ -552,19 +1039,29 @@ impl OutQuery {
     async fn handle_query_internal(
         &self,
         msg: &super::DnsMessage,
-        addr: std::net::SocketAddr,
+        ns: &Nameserver,
     ) -> Result<dnspkt::DNSPkt, Error> {
         let id = self.rng.lock().await.get().next_u32() as u16;
         let oq = create_outquery(id, &msg.in_query);
 
         let out_reply;
-        match msg.protocol {
-            Protocol::Udp => {
+        match (ns, &msg.protocol) {
+            /* DNS over TLS always uses the (hopefully already open) TLS connection. */
+            (Nameserver::Tls { .. }, _) => {
+                out_reply = TcpNameserver::send_query_to(ns, oq).await?;
+            }
+            (Nameserver::Https(url), _) => {
+                out_reply = HttpsNameserver::send_query_to(url, oq).await?;
+            }
+            /* Encrypted queries from clients are forwarded over UDP, there's no reason to believe
+             * they couldn't be.
+             */
+            (Nameserver::Plain(addr), Protocol::Udp | Protocol::Tls | Protocol::Https) => {
                 /* TODO: If we have a warm TCP connection already open, _and_ we have stats that
                  * say TCP is faster than UDP (which is likely if packet loss is high), then we
                  * should skip UDP and just use the existing TCP connection.
                  */
-                let reply = self.send_udp(addr, &oq).await?;
+                let reply = self.send_udp(*addr, &oq).await?;
                 if reply.qid != id {
                     /* This smells dangerously like a kaminisky attack.  Disregard the message, and immediately
                      * retry over TCP.
@@ -572,7 +1069,7 @@ impl OutQuery {
                     OUT_QUERY_RETRY
                         .with_label_values(&[&addr.to_string(), "KAMINSKY"])
                         .inc();
-                    out_reply = TcpNameserver::send_query_to(&addr, oq).await?;
+                    out_reply = TcpNameserver::send_query_to(ns, oq).await?;
                 } else if reply.tc {
                     /* If it's a truncated reply, then retry again over TCP, so we can get the full
                      * reply.  Truncated replies are also used by servers that suspect that we are
@@ -581,7 +1078,7 @@ impl OutQuery {
                     OUT_QUERY_RETRY
                         .with_label_values(&[&addr.to_string(), "TRUNCATED"])
                         .inc();
-                    out_reply = TcpNameserver::send_query_to(&addr, oq).await?;
+                    out_reply = TcpNameserver::send_query_to(ns, oq).await?;
                 } else {
                     out_reply = reply;
                 }
@@ -590,8 +1087,8 @@ impl OutQuery {
              * good reason for it (eg, a previous reply was truncated, or due to kaminsky attacks
              * or whatever), so we're going to follow suit.
              */
-            Protocol::Tcp => {
-                out_reply = TcpNameserver::send_query_to(&addr, oq).await?;
+            (Nameserver::Plain(_), Protocol::Tcp) => {
+                out_reply = TcpNameserver::send_query_to(ns, oq).await?;
             }
         }
 
@@ -602,19 +1099,332 @@ impl OutQuery {
         Ok(out_reply)
     }
 
-    pub async fn handle_query(
+    async fn query_nameserver(
         &self,
         msg: &super::DnsMessage,
-        addr: std::net::SocketAddr,
-    ) -> Result<dnspkt::DNSPkt, super::Error> {
+        ns: &Nameserver,
+    ) -> Result<dnspkt::DNSPkt, Error> {
         OUT_QUERY_OUTSTANDING
-            .with_label_values(&[&addr.to_string()])
+            .with_label_values(&[&ns.to_string()])
             .inc();
-        let ret = self.handle_query_internal(msg, addr).await;
+        let ret = self.handle_query_internal(msg, ns).await;
         OUT_QUERY_OUTSTANDING
-            .with_label_values(&[&addr.to_string()])
+            .with_label_values(&[&ns.to_string()])
             .dec();
-        increment_result(&addr.to_string(), &ret);
+        increment_result(&ns.to_string(), &ret);
+        ret
+    }
+
+    pub async fn handle_query(
+        &self,
+        msg: &super::DnsMessage,
+        servers: &[Nameserver],
+    ) -> Result<dnspkt::DNSPkt, super::Error> {
+        let mut ret = Err(Error::Internal("No nameservers configured".into()));
+        let mut servers = order_nameservers(servers).into_iter().peekable();
+        while let Some(ns) = servers.next() {
+            ret = self.query_nameserver(msg, &ns).await;
+            let failed = is_failure(&ret);
+            with_nameserver_state(&ns, |state| state.record_result(failed, Instant::now()));
+            if !failed {
+                break;
+            }
+            if let Some(next) = servers.peek() {
+                log::debug!("[{:x}] {} failed, trying {}", msg.in_query.qid, ns, next);
+                OUT_QUERY_RETRY
+                    .with_label_values(&[&ns.to_string(), "FAILOVER"])
+                    .inc();
+            }
+        }
         ret.map_err(super::Error::OutReply)
     }
 }
+
+#[test]
+fn test_nameserver_timeout() {
+    let mut state = NameserverState::default();
+    assert_eq!(state.timeout(), INITIAL_DNS_TIMEOUT);
+    state.add_rtt_sample(Duration::from_millis(100));
+    assert_eq!(state.srtt, Some(Duration::from_millis(100)));
+    assert_eq!(state.rttvar, Duration::from_millis(50));
+    assert_eq!(state.timeout(), Duration::from_millis(300));
+    /* A slow nameserver gets a longer timeout */
+    for _ in 0..20 {
+        state.add_rtt_sample(Duration::from_millis(700));
+    }
+    assert!(state.timeout() > Duration::from_millis(700));
+    /* But never longer than the limit */
+    state.add_rtt_sample(Duration::from_secs(30));
+    assert_eq!(state.timeout(), MAX_DNS_TIMEOUT);
+
+    let now = Instant::now();
+    state.record_result(true, now);
+    state.record_result(true, now);
+    assert_eq!(state.failures, 2);
+    assert!(state.is_down(now));
+    assert!(!state.is_down(now + NAMESERVER_DOWN_TIME));
+    state.record_result(false, now);
+    assert_eq!(state.failures, 0);
+    assert!(!state.is_down(now));
+}
+
+#[test]
+fn test_rank_nameservers() {
+    let now = Instant::now();
+    let ns = |n| Nameserver::Plain(std::net::SocketAddr::from(([192, 0, 2, n], 53)));
+    assert_eq!(
+        rank_nameservers(vec![
+            (Some(now + Duration::from_secs(20)), 0.01, ns(1)),
+            (None, 0.2, ns(2)),
+            (Some(now + Duration::from_secs(10)), 0.01, ns(3)),
+            (None, 0.05, ns(4)),
+        ]),
+        vec![ns(4), ns(2), ns(3), ns(1)]
+    );
+}
+
+#[test]
+fn test_parse_nameserver() {
+    assert_eq!(
+        "192.0.2.1".parse(),
+        Ok(Nameserver::Plain("192.0.2.1:53".parse().unwrap()))
+    );
+    assert_eq!(
+        "[2001:db8::1]:5353".parse(),
+        Ok(Nameserver::Plain("[2001:db8::1]:5353".parse().unwrap()))
+    );
+    assert_eq!(
+        "tls://dns.example@192.0.2.1".parse(),
+        Ok(Nameserver::Tls {
+            name: "dns.example".into(),
+            addr: "192.0.2.1:853".parse().unwrap()
+        })
+    );
+    assert_eq!(
+        "tls://dns.example@[2001:db8::1]:8853"
+            .parse::<Nameserver>()
+            .map(|ns| ns.to_string()),
+        Ok("tls://dns.example@[2001:db8::1]:8853".into())
+    );
+    assert!("tls://192.0.2.1".parse::<Nameserver>().is_err());
+    assert!("tls://dns.example@".parse::<Nameserver>().is_err());
+    assert!("dns.example".parse::<Nameserver>().is_err());
+    assert_eq!(
+        "https://dns.example/dns-query".parse(),
+        Ok(Nameserver::Https(
+            "https://dns.example/dns-query".parse().unwrap()
+        ))
+    );
+    assert!("https:///dns-query".parse::<Nameserver>().is_err());
+}
+
+#[cfg(test)]
+fn test_query(qid: u16, name: &str) -> dnspkt::DNSPkt {
+    dnspkt::DNSPkt {
+        qid,
+        ..super::DnsMessage::new_for_test(name, dnspkt::RR_A).in_query
+    }
+}
+
+/// Creates a TLS acceptor with a self signed certificate for name, and the roots to trust it.
+#[cfg(test)]
+fn test_tls_acceptor(
+    name: &str,
+    alpn: &[u8],
+) -> (tokio_rustls::TlsAcceptor, rustls::RootCertStore) {
+    let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(
+        vec![cert.cert.der().clone()],
+        rustls::pki_types::PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into()),
+    )
+    .unwrap();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    (tokio_rustls::TlsAcceptor::from(Arc::new(config)), roots)
+}
+
+/// Starts a DNS over TLS server for "dns.test" that waits for two queries, and then replies to
+/// them in the opposite order.  Returns the address it's listening on, and the roots to trust it.
+#[cfg(test)]
+async fn start_test_tls_server() -> (std::net::SocketAddr, rustls::RootCertStore) {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    let (acceptor, roots) = test_tls_acceptor("dns.test", b"dot");
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            let Ok(mut tls) = acceptor.accept(tcp).await else {
+                continue;
+            };
+            let mut queries = vec![];
+            for _ in 0..2 {
+                let len = tls.read_u16().await.unwrap();
+                let mut buf = vec![0; len as usize];
+                tls.read_exact(&mut buf).await.unwrap();
+                queries.push(parse::PktParser::new(&buf).get_dns().unwrap());
+            }
+            for mut reply in queries.into_iter().rev() {
+                reply.qr = true;
+                let buf = reply.serialise();
+                tls.write_u16(buf.len() as u16).await.unwrap();
+                tls.write_all(&buf).await.unwrap();
+            }
+        }
+    });
+    (addr, roots)
+}
+
+#[tokio::test]
+async fn test_tls_nameserver() {
+    let (addr, roots) = start_test_tls_server().await;
+    let server = Nameserver::Tls {
+        name: "dns.test".into(),
+        addr,
+    };
+    let chan = TcpNameserver::start(server.clone(), tls_client_config(roots, b"dot"));
+    let (first, second) = futures::join!(
+        TcpNameserver::send_query_on(&chan, &server, test_query(1, "one.example")),
+        TcpNameserver::send_query_on(&chan, &server, test_query(2, "two.example")),
+    );
+    let (first, second) = (first.unwrap(), second.unwrap());
+    assert_eq!(first.qid, 1);
+    assert_eq!(first.question.qdomain, "one.example".parse().unwrap());
+    assert_eq!(second.qid, 2);
+    assert_eq!(second.question.qdomain, "two.example".parse().unwrap());
+}
+
+#[tokio::test]
+async fn test_tls_nameserver_duplicate_qid() {
+    let (addr, roots) = start_test_tls_server().await;
+    let server = Nameserver::Tls {
+        name: "dns.test".into(),
+        addr,
+    };
+    let chan = TcpNameserver::start(server.clone(), tls_client_config(roots, b"dot"));
+    let (first, second) = futures::join!(
+        TcpNameserver::send_query_on(&chan, &server, test_query(1, "one.example")),
+        TcpNameserver::send_query_on(&chan, &server, test_query(1, "two.example")),
+    );
+    let (first, second) = (first.unwrap(), second.unwrap());
+    assert_eq!(first.qid, 1);
+    assert_eq!(first.question.qdomain, "one.example".parse().unwrap());
+    assert_eq!(second.qid, 1);
+    assert_eq!(second.question.qdomain, "two.example".parse().unwrap());
+}
+
+#[tokio::test]
+async fn test_tls_nameserver_wrong_name() {
+    let (addr, roots) = start_test_tls_server().await;
+    let server = Nameserver::Tls {
+        name: "wrong.test".into(),
+        addr,
+    };
+    let chan = TcpNameserver::start(server.clone(), tls_client_config(roots, b"dot"));
+    assert!(matches!(
+        TcpNameserver::send_query_on(&chan, &server, test_query(1, "one.example")).await,
+        Err(Error::TcpConnection(_))
+    ));
+}
+
+/// Starts a DNS over HTTPS server for 127.0.0.1 that answers queries to /dns-query.  Returns the
+/// address it's listening on, the roots to trust it, and a count of connections accepted.
+#[cfg(test)]
+async fn start_test_https_server() -> (
+    std::net::SocketAddr,
+    rustls::RootCertStore,
+    Arc<std::sync::atomic::AtomicUsize>,
+) {
+    use http_body_util::BodyExt as _;
+    let (acceptor, roots) = test_tls_acceptor("127.0.0.1", b"h2");
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let count = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let Ok(tls) = acceptor.accept(tcp).await else {
+                continue;
+            };
+            let service = hyper::service::service_fn(
+                |req: hyper::Request<hyper::body::Incoming>| async move {
+                    let response = hyper::Response::builder();
+                    if req.uri().path() != "/dns-query"
+                        || req.method() != hyper::Method::POST
+                        || req.headers()[hyper::header::CONTENT_TYPE] != DNS_MESSAGE
+                    {
+                        return response
+                            .status(hyper::StatusCode::NOT_FOUND)
+                            .body(http_body_util::Full::new(bytes::Bytes::new()));
+                    }
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    let mut reply = parse::PktParser::new(&body).get_dns().unwrap();
+                    assert_eq!(reply.qid, 0);
+                    reply.qr = true;
+                    response
+                        .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE)
+                        .body(http_body_util::Full::new(bytes::Bytes::from(
+                            reply.serialise(),
+                        )))
+                },
+            );
+            tokio::spawn(
+                hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                    .serve_connection(hyper_util::rt::TokioIo::new(tls), service),
+            );
+        }
+    });
+    (addr, roots, connections)
+}
+
+#[tokio::test]
+async fn test_https_nameserver() {
+    let (addr, roots, connections) = start_test_https_server().await;
+    let url: hyper::Uri = format!("https://{}/dns-query", addr).parse().unwrap();
+    let ns = HttpsNameserver::new(url, tls_client_config(roots, b"h2"));
+    let (first, second) = futures::join!(
+        ns.send_query(test_query(1, "one.example")),
+        ns.send_query(test_query(2, "two.example")),
+    );
+    let (first, second) = (first.unwrap(), second.unwrap());
+    assert_eq!(first.qid, 1);
+    assert_eq!(first.question.qdomain, "one.example".parse().unwrap());
+    assert_eq!(second.qid, 2);
+    assert_eq!(second.question.qdomain, "two.example".parse().unwrap());
+    assert_eq!(
+        ns.send_query(test_query(3, "three.example"))
+            .await
+            .unwrap()
+            .qid,
+        3
+    );
+    /* All the queries share a single connection */
+    assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_https_nameserver_errors() {
+    let (addr, roots, _) = start_test_https_server().await;
+    let url: hyper::Uri = format!("https://{}/not-found", addr).parse().unwrap();
+    let ns = HttpsNameserver::new(url, tls_client_config(roots.clone(), b"h2"));
+    assert!(matches!(
+        ns.send_query(test_query(1, "one.example")).await,
+        Err(Error::Http(_))
+    ));
+    /* The certificate isn't valid for localhost */
+    let url: hyper::Uri = format!("https://localhost:{}/dns-query", addr.port())
+        .parse()
+        .unwrap();
+    let ns = HttpsNameserver::new(url, tls_client_config(roots, b"h2"));
+    assert!(matches!(
+        ns.send_query(test_query(1, "one.example")).await,
+        Err(Error::TcpConnection(_))
+    ));
+}
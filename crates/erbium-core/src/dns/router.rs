@@ -25,62 +25,159 @@ use super::Error;
 pub struct DnsRouteHandler {
     conf: crate::config::SharedConfig,
     next: super::cache::CacheHandler,
+    #[cfg(feature = "dhcp")]
+    leases: super::leases::DhcpLeaseHandler,
 }
 
 impl DnsRouteHandler {
     pub async fn new(conf: crate::config::SharedConfig) -> Self {
+        let cache_config = conf.read().await.dns_cache.clone();
         DnsRouteHandler {
             conf,
-            next: super::cache::CacheHandler::new().await,
+            next: super::cache::CacheHandler::new(cache_config).await,
+            #[cfg(feature = "dhcp")]
+            leases: super::leases::DhcpLeaseHandler::new(),
         }
     }
 
+    pub async fn shutdown(&self) {
+        self.next.shutdown().await;
+    }
+
     pub async fn handle_query(&self, msg: &super::DnsMessage) -> Result<dnspkt::DNSPkt, Error> {
         let conf = self.conf.clone();
         let locked_conf = conf.read().await;
 
-        let mut best_route = None;
-        let mut best_suffix: Option<&super::dnspkt::Domain> = None;
-        for route in 0..locked_conf.dns_routes.len() {
-            for suffix in &locked_conf.dns_routes[route].suffixes {
+        /* The first view that matches the client picks the routes, otherwise use the top level
+         * dns-routes.
+         */
+        let attr = msg.client_attributes();
+        let view = locked_conf
+            .dns_views
+            .iter()
+            .find(|view| view.client.matches(&attr));
+        let routes = match view {
+            Some(view) => {
+                log::trace!("[{:x}] Using view {}", msg.in_query.qid, view.name);
+                &view.routes
+            }
+            None => &locked_conf.dns_routes,
+        };
+
+        /* Every route that matches, with the longest suffix first.  The sort is stable, so
+         * routes with the same suffix are tried in the order they were configured.
+         */
+        let mut candidates = vec![];
+        for route in routes {
+            for suffix in &route.suffixes {
                 if msg.in_query.question.qdomain.ends_with(suffix) {
-                    if let Some(ref best) = best_suffix {
-                        log::trace!("Comparing {} with {}", best, suffix);
-                        if super::dnspkt::compare_longest_suffix(best, suffix)
-                            == std::cmp::Ordering::Greater
-                        {
-                            best_route = Some(route);
-                            best_suffix = Some(suffix);
-                        }
-                    } else {
-                        best_route = Some(route);
-                        best_suffix = Some(suffix);
-                    }
+                    candidates.push((suffix, route));
                 }
             }
         }
+        candidates.sort_by(|(a, _), (b, _)| super::dnspkt::compare_longest_suffix(a, b));
 
-        if let Some(route_num) = best_route {
-            let route = &locked_conf.dns_routes[route_num];
-            log::trace!(
-                "[{:x}] \"{}\" is the best route",
-                msg.in_query.qid,
-                best_suffix.unwrap()
-            );
+        for (suffix, route) in candidates {
+            log::trace!("[{:x}] \"{}\" is the best route", msg.in_query.qid, suffix);
             use super::config::Handler;
-            match route.dest {
+            return match route.dest {
                 Handler::Forward(ref dest) => {
                     if !msg.in_query.rd {
                         // We will only forward queries when requested to do so.
                         Err(Error::NotAuthoritative)
                     } else {
-                        self.next.handle_query(msg, dest[0]).await
+                        self.next
+                            .handle_query(msg, view.map(|view| view.name.as_str()), dest)
+                            .await
                     }
                 }
                 Handler::ForgeNxDomain => Err(Error::Blocked),
-            }
-        } else {
-            Err(Error::NoRouteConfigured)
+                #[cfg(feature = "dhcp")]
+                Handler::DhcpLeases => self.leases.handle_query(msg, &route.suffixes).await,
+                Handler::Zones(ref zones) => super::zone::handle_query(msg, zones),
+                /* Names that aren't on the blocklist are handled by the next best route. */
+                Handler::Blocklist(ref blocklist) => match blocklist.handle_query(msg) {
+                    Some(result) => result,
+                    None => continue,
+                },
+            };
         }
+        Err(Error::NoRouteConfigured)
     }
 }
+
+#[tokio::test]
+async fn test_views() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+dns-views:
+  - name: guests
+    match-subnets: [192.0.2.128/25]
+    dns-routes:
+      - domain-suffixes: [internal.example]
+        type: forge-nxdomain
+dns-routes:
+  - domain-suffixes: [internal.example]
+    type: static
+    records:
+      - name: router.internal.example
+        type: A
+        value: 192.0.2.1
+",
+    )
+    .unwrap();
+    let handler = DnsRouteHandler::new(conf).await;
+    let query = |client: [u8; 4]| super::DnsMessage {
+        remote_addr: std::net::SocketAddr::from((client, 12345)).into(),
+        ..super::DnsMessage::new_for_test("router.internal.example", dnspkt::RR_A)
+    };
+
+    /* Clients that don't match a view use the top level routes */
+    let reply = handler.handle_query(&query([192, 0, 2, 10])).await.unwrap();
+    assert_eq!(reply.answer.len(), 1);
+
+    /* Guests only use the routes in their view */
+    assert!(matches!(
+        handler.handle_query(&query([192, 0, 2, 200])).await,
+        Err(Error::Blocked)
+    ));
+}
+
+#[tokio::test]
+async fn test_blocklist_route() {
+    let path = crate::test_util::TempPath::new("blocklist");
+    std::fs::write(&path, "0.0.0.0 ads.example.com\n").unwrap();
+    let conf = crate::config::load_config_from_string_for_test(&format!(
+        "---
+dns-routes:
+  - domain-suffixes: ['']
+    type: blocklist
+    blocklists: [{}]
+  - domain-suffixes: ['']
+    type: static
+    records:
+      - name: www.example.com
+        type: A
+        value: 192.0.2.1
+",
+        path.display()
+    ))
+    .unwrap();
+    let handler = DnsRouteHandler::new(conf).await;
+
+    /* Blocked names get NXDOMAIN, with the Blocked extended error */
+    let msg = super::DnsMessage::new_for_test("ads.example.com", dnspkt::RR_A);
+    let err = handler.handle_query(&msg).await.unwrap_err();
+    assert!(matches!(err, Error::Blocked));
+    let reply = super::DnsListenerHandler::create_in_error(&msg, err).await;
+    assert_eq!(reply.rcode, dnspkt::NXDOMAIN);
+    assert_eq!(
+        reply.edns.unwrap().get_extended_dns_error().unwrap().0,
+        dnspkt::EDE_BLOCKED
+    );
+
+    /* Names that aren't blocked fall through to the next route */
+    let msg = super::DnsMessage::new_for_test("www.example.com", dnspkt::RR_A);
+    let reply = handler.handle_query(&msg).await.unwrap();
+    assert_eq!(reply.answer.len(), 1);
+}
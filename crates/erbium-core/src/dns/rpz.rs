@@ -0,0 +1,838 @@
+/*   Copyright 2024 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Response Policy Zones (RPZ).
+ *
+ *  A policy zone is an ordinary DNS zone, where the owner names describe what to match (the
+ *  "trigger"), and the records describe what to do about it (the "action").  For a policy zone
+ *  rpz.example:
+ *
+ *    ads.example.com.rpz.example          CNAME .              ; NXDOMAIN
+ *    *.ads.example.com.rpz.example        CNAME *.             ; NODATA for subdomains
+ *    ok.ads.example.com.rpz.example       CNAME rpz-passthru.  ; Answer normally
+ *    evil.example.net.rpz.example         CNAME rpz-drop.      ; Don't answer at all
+ *    printer.example.org.rpz.example      A 192.0.2.10         ; Local data
+ *    32.1.2.0.192.rpz-ip.rpz.example      CNAME .              ; Answers containing 192.0.2.1
+ *    ns.evil.example.rpz-nsdname.rpz.example CNAME .           ; Domains served by ns.evil.example
+ *
+ *  QNAME triggers are checked before the query is resolved, response IP and NSDNAME triggers are
+ *  checked against the reply.  As we are a forwarder rather than a full resolver, NSDNAME triggers
+ *  only see the NS records that are in the reply.  Policy zones are checked in the order they are
+ *  configured, and the first trigger that matches wins.
+ */
+
+use super::dnspkt;
+use super::router;
+use super::{DnsMessage, Error};
+use crate::config::Match as _;
+use std::sync::Arc;
+
+lazy_static::lazy_static! {
+    static ref RPZ_HITS: prometheus::IntCounterVec =
+        prometheus::register_int_counter_vec!("dns_rpz_hits",
+            "DNS queries rewritten by response policy zones",
+            &["zone", "trigger", "action"])
+        .unwrap();
+}
+
+/// How often to check if policy zones need to be reloaded.
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long to wait for a zone transfer to complete.
+const AXFR_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// TTL for the SOA on negative answers if the policy zone doesn't have one.
+const DEFAULT_NEGATIVE_TTL: u32 = 30;
+
+#[derive(Debug)]
+pub enum Source {
+    File(std::path::PathBuf),
+    Axfr(std::net::SocketAddr),
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    NxDomain,
+    NoData,
+    Passthru,
+    Drop,
+    TcpOnly,
+    LocalData(Vec<dnspkt::RR>),
+}
+
+impl Action {
+    fn new(rrs: Vec<dnspkt::RR>) -> Self {
+        match rrs.as_slice() {
+            [dnspkt::RR {
+                rdata: dnspkt::RData::CName(target),
+                ..
+            }] => match target.to_string().to_ascii_lowercase().as_str() {
+                "" => Action::NxDomain,
+                "*" => Action::NoData,
+                "rpz-passthru" => Action::Passthru,
+                "rpz-drop" => Action::Drop,
+                "rpz-tcp-only" => Action::TcpOnly,
+                _ => Action::LocalData(rrs),
+            },
+            _ => Action::LocalData(rrs),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Action::NxDomain => "NXDOMAIN",
+            Action::NoData => "NODATA",
+            Action::Passthru => "PASSTHRU",
+            Action::Drop => "DROP",
+            Action::TcpOnly => "TCP-ONLY",
+            Action::LocalData(_) => "LOCAL-DATA",
+        }
+    }
+}
+
+/// Triggers on domain names (either QNAME or NSDNAME).
+#[derive(Debug, Default)]
+struct NameTriggers {
+    exact: std::collections::HashMap<String, Action>,
+    /* Keyed by the parent, eg "example.com" for "*.example.com" */
+    wildcard: std::collections::HashMap<String, Action>,
+}
+
+impl NameTriggers {
+    fn insert(&mut self, name: &str, action: Action) {
+        match name.strip_prefix("*.") {
+            Some(parent) => self.wildcard.insert(parent.into(), action),
+            None => self.exact.insert(name.into(), action),
+        };
+    }
+
+    /// Finds the action for a (lowercased) name, with the most specific wildcard winning.
+    fn lookup(&self, name: &str) -> Option<&Action> {
+        if let Some(action) = self.exact.get(name) {
+            return Some(action);
+        }
+        let mut parent = name;
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(action) = self.wildcard.get(rest) {
+                return Some(action);
+            }
+            parent = rest;
+        }
+        None
+    }
+}
+
+/// Parses the "32.1.2.0.192" or "128.1.zz.db8.2001" form of a response IP trigger.
+fn parse_ip_trigger(name: &str) -> Option<crate::config::Prefix> {
+    use crate::config::{Prefix, Prefix4, Prefix6};
+    let mut labels = name.split('.');
+    let prefixlen: u8 = labels.next()?.parse().ok()?;
+    let mut labels = labels.collect::<Vec<_>>();
+    labels.reverse();
+    /* IPv4 addresses always have four labels, and IPv6 addresses with fewer than eight labels
+     * always have a "zz".  The prefix length can't tell them apart, as both can be 32 or less.
+     */
+    let is_v4 = labels.len() == 4 && !labels.contains(&"zz");
+    if is_v4 && prefixlen <= 32 {
+        Some(Prefix::V4(Prefix4 {
+            addr: labels.join(".").parse().ok()?,
+            prefixlen,
+        }))
+    } else if !is_v4 && prefixlen <= 128 {
+        let addr = labels
+            .iter()
+            .map(|l| if *l == "zz" { "" } else { l })
+            .collect::<Vec<_>>()
+            .join(":");
+        /* "zz" at either end becomes a single ":", but "::" is needed */
+        let addr = match addr.as_str() {
+            "" => "::".to_string(),
+            a if a.starts_with(':') => format!(":{}", a),
+            a if a.ends_with(':') => format!("{}:", a),
+            a => a.to_string(),
+        };
+        Some(Prefix::V6(Prefix6 {
+            addr: addr.parse().ok()?,
+            prefixlen,
+        }))
+    } else {
+        None
+    }
+}
+
+/// The triggers and actions loaded from a policy zone.
+#[derive(Debug)]
+struct Policy {
+    name: String,
+    ede: dnspkt::EdeCode,
+    soa: dnspkt::RR,
+    qname: NameTriggers,
+    nsdname: NameTriggers,
+    ip: Vec<(crate::config::Prefix, Action)>,
+}
+
+impl Policy {
+    fn new<'a, I>(origin: &dnspkt::Domain, ede: dnspkt::EdeCode, rrs: I) -> Self
+    where
+        I: IntoIterator<Item = &'a dnspkt::RR>,
+    {
+        let name = origin.to_string().to_ascii_lowercase();
+        let suffix = format!(".{}", name);
+        let mut soa = None;
+        let mut owners: std::collections::HashMap<String, Vec<dnspkt::RR>> = Default::default();
+        for rr in rrs {
+            let owner = rr.domain.to_string().to_ascii_lowercase();
+            match owner.strip_suffix(&suffix) {
+                Some(trigger) => owners.entry(trigger.into()).or_default().push(rr.clone()),
+                /* The zone's own SOA and NS records */
+                None if owner == name && rr.rrtype == dnspkt::RR_SOA => soa = Some(rr.clone()),
+                None => (),
+            }
+        }
+
+        let mut policy = Self {
+            soa: soa.unwrap_or_else(|| dnspkt::RR {
+                ttl: DEFAULT_NEGATIVE_TTL,
+                ..super::zone::default_soa(origin)
+            }),
+            name,
+            ede,
+            qname: Default::default(),
+            nsdname: Default::default(),
+            ip: vec![],
+        };
+        for (trigger, rrs) in owners {
+            let action = Action::new(rrs);
+            if let Some(ip) = trigger.strip_suffix(".rpz-ip") {
+                match parse_ip_trigger(ip) {
+                    Some(prefix) => policy.ip.push((prefix, action)),
+                    None => log::warn!("{}: Invalid rpz-ip trigger {}", policy.name, trigger),
+                }
+            } else if let Some(nsdname) = trigger.strip_suffix(".rpz-nsdname") {
+                policy.nsdname.insert(nsdname, action);
+            } else if trigger.ends_with(".rpz-client-ip") || trigger.ends_with(".rpz-nsip") {
+                log::debug!("{}: Unsupported trigger {}", policy.name, trigger);
+            } else {
+                policy.qname.insert(&trigger, action);
+            }
+        }
+        /* The most specific prefix wins */
+        policy.ip.sort_by_key(|(prefix, _)| {
+            std::cmp::Reverse(match prefix {
+                crate::config::Prefix::V4(p4) => p4.prefixlen + 96,
+                crate::config::Prefix::V6(p6) => p6.prefixlen,
+            })
+        });
+        policy
+    }
+
+    /// Checks the reply for response IP or NSDNAME triggers.
+    fn check_reply(&self, reply: &dnspkt::DNSPkt) -> Option<(&'static str, &Action)> {
+        for rr in &reply.answer {
+            let ip = match &rr.rdata {
+                dnspkt::RData::Other(v) if rr.rrtype == dnspkt::RR_A && v.len() == 4 => {
+                    std::net::IpAddr::from(<[u8; 4]>::try_from(v.as_slice()).unwrap())
+                }
+                dnspkt::RData::Other(v) if rr.rrtype == dnspkt::RR_AAAA && v.len() == 16 => {
+                    std::net::IpAddr::from(<[u8; 16]>::try_from(v.as_slice()).unwrap())
+                }
+                _ => continue,
+            };
+            if let Some((_, action)) = self.ip.iter().find(|(prefix, _)| prefix.contains(ip)) {
+                return Some(("IP", action));
+            }
+        }
+        for rr in reply.answer.iter().chain(reply.nameserver.iter()) {
+            if let dnspkt::RData::Ns(ns) = &rr.rdata {
+                if let Some(action) = self.nsdname.lookup(&ns.to_string().to_ascii_lowercase()) {
+                    return Some(("NSDNAME", action));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn load_file(
+    origin: &dnspkt::Domain,
+    ede: dnspkt::EdeCode,
+    path: &std::path::Path,
+) -> Result<Policy, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let zone = super::zone::parse_master_file(&text, origin.clone())
+        .map_err(|(line, e)| format!("{}:{}: {}", path.display(), line, e))?;
+    Ok(Policy::new(origin, ede, zone.records()))
+}
+
+/// Transfers the zone from a primary server (RFC5936).
+async fn axfr(
+    origin: &dnspkt::Domain,
+    primary: std::net::SocketAddr,
+) -> Result<Vec<dnspkt::RR>, String> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    let qid = rand::random();
+    let query = dnspkt::DNSPkt {
+        qid,
+        rd: false,
+        tc: false,
+        aa: false,
+        qr: false,
+        opcode: dnspkt::OPCODE_QUERY,
+        cd: false,
+        ad: false,
+        ra: false,
+        rcode: dnspkt::NOERROR,
+        bufsize: 65535,
+        edns_ver: None,
+        edns_do: false,
+        question: dnspkt::Question {
+            qdomain: origin.clone(),
+            qclass: dnspkt::CLASS_IN,
+            qtype: dnspkt::RR_AXFR,
+        },
+        answer: vec![],
+        nameserver: vec![],
+        additional: vec![],
+        edns: None,
+    }
+    .serialise();
+
+    let mut sock = tokio::net::TcpStream::connect(primary)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut out = Vec::with_capacity(2 + query.len());
+    out.extend((query.len() as u16).to_be_bytes().iter());
+    out.extend(query);
+    sock.write_all(&out).await.map_err(|e| e.to_string())?;
+
+    /* The transfer starts and ends with the SOA, and can be split over many messages */
+    let mut rrs: Vec<dnspkt::RR> = vec![];
+    loop {
+        let mut lbytes = [0u8; 2];
+        sock.read_exact(&mut lbytes)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut buffer = vec![0u8; u16::from_be_bytes(lbytes) as usize];
+        sock.read_exact(&mut buffer)
+            .await
+            .map_err(|e| e.to_string())?;
+        let reply = super::parse::PktParser::new(&buffer).get_dns()?;
+        if reply.qid != qid {
+            return Err(format!(
+                "Zone transfer reply has the wrong id {}, expected {}",
+                reply.qid, qid
+            ));
+        }
+        if reply.rcode != dnspkt::NOERROR {
+            return Err(format!("Zone transfer refused: {}", reply.rcode));
+        }
+        for rr in reply.answer {
+            if rrs.is_empty() && rr.rrtype != dnspkt::RR_SOA {
+                return Err("Zone transfer didn't start with a SOA".into());
+            }
+            let done = !rrs.is_empty() && rr.rrtype == dnspkt::RR_SOA;
+            rrs.push(rr);
+            if done {
+                return Ok(rrs);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RefreshState {
+    next_refresh: std::time::Instant,
+    modified: Option<std::time::SystemTime>,
+}
+
+/// A configured policy zone, and the policy most recently loaded from it.
+#[derive(Debug)]
+pub struct PolicyZone {
+    pub origin: dnspkt::Domain,
+    pub source: Source,
+    pub ede: dnspkt::EdeCode,
+    policy: std::sync::RwLock<Arc<Policy>>,
+    refresh: std::sync::Mutex<RefreshState>,
+}
+
+impl PolicyZone {
+    /// Creates a policy zone.  Zone files are loaded immediately so errors can be reported, a zone
+    /// file that doesn't exist yet, or a zone transferred from a primary, is empty until it is
+    /// first loaded.
+    pub fn new(
+        origin: dnspkt::Domain,
+        source: Source,
+        ede: dnspkt::EdeCode,
+    ) -> Result<Self, String> {
+        let (policy, modified) = match &source {
+            Source::File(path) if !path.exists() => {
+                log::warn!(
+                    "Response policy zone file {} does not exist, {} is empty until it is created",
+                    path.display(),
+                    origin
+                );
+                (Policy::new(&origin, ede, []), None)
+            }
+            Source::File(path) => (load_file(&origin, ede, path)?, crate::file_modified(path)),
+            Source::Axfr(_) => (Policy::new(&origin, ede, []), None),
+        };
+        Ok(Self {
+            origin,
+            source,
+            ede,
+            policy: std::sync::RwLock::new(policy.into()),
+            refresh: std::sync::Mutex::new(RefreshState {
+                next_refresh: std::time::Instant::now(),
+                modified,
+            }),
+        })
+    }
+
+    fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Reloads the zone file if it has changed, or transfers the zone again if the SOA refresh
+    /// interval has passed.
+    async fn refresh(&self) {
+        let now = std::time::Instant::now();
+        let policy = match &self.source {
+            Source::File(path) => {
+                {
+                    let mut state = self.refresh.lock().unwrap();
+                    let modified = crate::file_modified(path);
+                    if modified == state.modified {
+                        return;
+                    }
+                    state.modified = modified;
+                }
+                /* Parsing a large zone file takes a while, keep it off the async runtime. */
+                let (origin, ede, path) = (self.origin.clone(), self.ede, path.clone());
+                tokio::task::spawn_blocking(move || load_file(&origin, ede, &path))
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+            }
+            Source::Axfr(primary) => {
+                if self.refresh.lock().unwrap().next_refresh > now {
+                    return;
+                }
+                match tokio::time::timeout(AXFR_TIMEOUT, axfr(&self.origin, *primary)).await {
+                    Ok(Ok(rrs)) => Ok(Policy::new(&self.origin, self.ede, &rrs)),
+                    Ok(Err(e)) => Err(format!("{}: {}", primary, e)),
+                    Err(_) => Err(format!("{}: Zone transfer timed out", primary)),
+                }
+            }
+        };
+        match policy {
+            Ok(policy) => {
+                log::info!("Loaded response policy zone {}", self.origin);
+                let refresh = match &policy.soa.rdata {
+                    dnspkt::RData::Soa(soa) => std::time::Duration::from_secs(soa.refresh.into()),
+                    _ => CHECK_INTERVAL,
+                };
+                self.refresh.lock().unwrap().next_refresh = now + refresh;
+                *self.policy.write().unwrap() = policy.into();
+            }
+            Err(err) => {
+                log::warn!(
+                    "Failed to load response policy zone {}: {}",
+                    self.origin,
+                    err
+                );
+                self.refresh.lock().unwrap().next_refresh = now + CHECK_INTERVAL;
+            }
+        }
+    }
+}
+
+async fn refresh_zones(config: crate::config::SharedConfig) {
+    loop {
+        let zones = config.read().await.dns_rpz.clone();
+        for zone in zones {
+            zone.refresh().await;
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+pub(super) struct DnsRpzHandler {
+    config: crate::config::SharedConfig,
+    next: router::DnsRouteHandler,
+}
+
+impl DnsRpzHandler {
+    pub async fn new(config: crate::config::SharedConfig) -> Self {
+        if !config.read().await.dns_rpz.is_empty() {
+            tokio::spawn(refresh_zones(config.clone()));
+        }
+        Self {
+            config: config.clone(),
+            next: router::DnsRouteHandler::new(config).await,
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        self.next.shutdown().await;
+    }
+
+    fn create_reply(
+        msg: &DnsMessage,
+        policy: &Policy,
+        rcode: dnspkt::RCode,
+        answer: Vec<dnspkt::RR>,
+    ) -> dnspkt::DNSPkt {
+        let mut edns = dnspkt::EdnsData::default();
+        edns.set_extended_dns_error(
+            policy.ede,
+            &format!("Rewritten by response policy zone {}", policy.name),
+        );
+        dnspkt::DNSPkt {
+            nameserver: if answer.is_empty() {
+                vec![policy.soa.clone()]
+            } else {
+                vec![]
+            },
+            answer,
+            edns: Some(edns),
+            ..dnspkt::DNSPkt::reply_to(&msg.in_query, rcode)
+        }
+    }
+
+    async fn local_data(
+        &self,
+        msg: &DnsMessage,
+        policy: &Policy,
+        rrs: &[dnspkt::RR],
+    ) -> dnspkt::DNSPkt {
+        let question = &msg.in_query.question;
+        let rewrite = |rr: &dnspkt::RR| dnspkt::RR {
+            domain: question.qdomain.clone(),
+            ..rr.clone()
+        };
+        let answer = rrs
+            .iter()
+            .filter(|rr| question.qtype == dnspkt::RR_ANY || rr.rrtype == question.qtype)
+            .map(rewrite)
+            .collect::<Vec<_>>();
+        if !answer.is_empty() {
+            return Self::create_reply(msg, policy, dnspkt::NOERROR, answer);
+        }
+        match rrs.iter().find(|rr| rr.rrtype == dnspkt::RR_CNAME) {
+            /* Resolve the target of a CNAME as normal, without applying policy to it again. */
+            Some(
+                cname @ dnspkt::RR {
+                    rdata: dnspkt::RData::CName(target),
+                    ..
+                },
+            ) => {
+                let mut in_query = msg.in_query.clone();
+                in_query.question.qdomain = target.clone();
+                let chased = DnsMessage {
+                    in_query,
+                    in_size: msg.in_size,
+                    local_ip: msg.local_ip,
+                    remote_addr: msg.remote_addr,
+                    protocol: msg.protocol,
+                    interface: msg.interface.clone(),
+                };
+                let mut answer = vec![rewrite(cname)];
+                let mut rcode = dnspkt::NOERROR;
+                if let Ok(reply) = self.next.handle_query(&chased).await {
+                    answer.extend(reply.answer);
+                    rcode = reply.rcode;
+                }
+                Self::create_reply(msg, policy, rcode, answer)
+            }
+            _ => Self::create_reply(msg, policy, dnspkt::NOERROR, vec![]),
+        }
+    }
+
+    async fn apply(
+        &self,
+        msg: &DnsMessage,
+        policy: &Policy,
+        trigger: &str,
+        action: &Action,
+        reply: Option<dnspkt::DNSPkt>,
+    ) -> Result<dnspkt::DNSPkt, Error> {
+        log::trace!(
+            "[{:x}] {} {} trigger in {}: {}",
+            msg.in_query.qid,
+            msg.in_query.question.qdomain,
+            trigger,
+            policy.name,
+            action.name()
+        );
+        RPZ_HITS
+            .with_label_values(&[&policy.name, trigger, action.name()])
+            .inc();
+        match action {
+            Action::NxDomain => Ok(Self::create_reply(msg, policy, dnspkt::NXDOMAIN, vec![])),
+            Action::NoData => Ok(Self::create_reply(msg, policy, dnspkt::NOERROR, vec![])),
+            Action::Drop => Err(Error::Dropped),
+            /* Make UDP clients retry over TCP, to make it harder to spoof replies */
+            Action::TcpOnly if matches!(msg.protocol, super::Protocol::Udp) => {
+                let mut reply = Self::create_reply(msg, policy, dnspkt::NOERROR, vec![]);
+                reply.tc = true;
+                reply.nameserver = vec![];
+                Ok(reply)
+            }
+            Action::Passthru | Action::TcpOnly => match reply {
+                Some(reply) => Ok(reply),
+                None => self.next.handle_query(msg).await,
+            },
+            Action::LocalData(rrs) => Ok(self.local_data(msg, policy, rrs).await),
+        }
+    }
+
+    pub async fn handle_query(&self, msg: &DnsMessage) -> Result<dnspkt::DNSPkt, Error> {
+        let policies = self
+            .config
+            .read()
+            .await
+            .dns_rpz
+            .iter()
+            .map(|zone| zone.policy())
+            .collect::<Vec<_>>();
+        if policies.is_empty() {
+            return self.next.handle_query(msg).await;
+        }
+
+        let qname = msg
+            .in_query
+            .question
+            .qdomain
+            .to_string()
+            .to_ascii_lowercase();
+        for policy in &policies {
+            if let Some(action) = policy.qname.lookup(&qname) {
+                return self.apply(msg, policy, "QNAME", action, None).await;
+            }
+        }
+
+        let reply = self.next.handle_query(msg).await?;
+        for policy in &policies {
+            if let Some((trigger, action)) = policy.check_reply(&reply) {
+                return self.apply(msg, policy, trigger, action, Some(reply)).await;
+            }
+        }
+        Ok(reply)
+    }
+}
+
+#[test]
+fn test_parse_ip_trigger() {
+    use crate::config::{Prefix, Prefix4, Prefix6};
+    assert_eq!(
+        parse_ip_trigger("32.1.2.0.192"),
+        Some(Prefix::V4(Prefix4 {
+            addr: "192.0.2.1".parse().unwrap(),
+            prefixlen: 32
+        }))
+    );
+    assert_eq!(
+        parse_ip_trigger("24.0.2.0.192"),
+        Some(Prefix::V4(Prefix4 {
+            addr: "192.0.2.0".parse().unwrap(),
+            prefixlen: 24
+        }))
+    );
+    assert_eq!(
+        parse_ip_trigger("128.1.zz.db8.2001"),
+        Some(Prefix::V6(Prefix6 {
+            addr: "2001:db8::1".parse().unwrap(),
+            prefixlen: 128
+        }))
+    );
+    assert_eq!(
+        parse_ip_trigger("48.zz.db8.2001"),
+        Some(Prefix::V6(Prefix6 {
+            addr: "2001:db8::".parse().unwrap(),
+            prefixlen: 48
+        }))
+    );
+    assert_eq!(
+        parse_ip_trigger("32.1.zz.db8.2001"),
+        Some(Prefix::V6(Prefix6 {
+            addr: "2001:db8::1".parse().unwrap(),
+            prefixlen: 32
+        }))
+    );
+    assert_eq!(parse_ip_trigger("33.1.2.0.192"), None);
+    assert_eq!(parse_ip_trigger("rpz"), None);
+}
+
+#[test]
+fn test_policy() {
+    let origin: dnspkt::Domain = "rpz.example".parse().unwrap();
+    let zone = super::zone::parse_master_file(
+        "@ SOA ns hostmaster 1 3600 600 86400 60
+@ NS ns
+ads.example.com CNAME .
+*.ads.example.com CNAME *.
+ok.ads.example.com CNAME rpz-passthru.
+drop.example.com CNAME rpz-drop.
+printer.example.org A 192.0.2.10
+alias.example.org CNAME www.example.net.
+32.1.2.0.192.rpz-ip CNAME .
+ns.evil.example.rpz-nsdname CNAME .
+",
+        origin.clone(),
+    )
+    .unwrap();
+    let policy = Policy::new(&origin, dnspkt::EDE_BLOCKED, zone.records());
+    let lookup = |name| policy.qname.lookup(name).map(Action::name);
+    assert_eq!(lookup("ads.example.com"), Some("NXDOMAIN"));
+    assert_eq!(lookup("www.ads.example.com"), Some("NODATA"));
+    assert_eq!(lookup("ok.ads.example.com"), Some("PASSTHRU"));
+    assert_eq!(lookup("drop.example.com"), Some("DROP"));
+    assert_eq!(lookup("printer.example.org"), Some("LOCAL-DATA"));
+    assert_eq!(lookup("alias.example.org"), Some("LOCAL-DATA"));
+    assert_eq!(lookup("example.com"), None);
+    assert_eq!(lookup("rpz-ip"), None);
+    assert_eq!(policy.ip.len(), 1);
+    assert!(policy.nsdname.lookup("ns.evil.example").is_some());
+    assert!(matches!(policy.soa.rdata, dnspkt::RData::Soa(_)));
+}
+
+#[tokio::test]
+async fn test_rpz_actions() {
+    let path = crate::test_util::TempPath::new("rpz-handler");
+    std::fs::write(
+        &path,
+        "@ SOA ns hostmaster 1 3600 600 86400 60
+blocked.example.com CNAME .
+drop.example.com CNAME rpz-drop.
+printer.example.com A 192.0.2.10
+alias.example.com CNAME www.example.com.
+32.1.2.0.192.rpz-ip CNAME *.
+",
+    )
+    .unwrap();
+    let conf = crate::config::load_config_from_string_for_test(&format!(
+        "---
+dns-rpz:
+  - zone: rpz.example
+    zone-file: {}
+dns-routes:
+  - domain-suffixes: [example.com]
+    type: static
+    records:
+      - name: www.example.com
+        type: A
+        value: 192.0.2.2
+      - name: bad.example.com
+        type: A
+        value: 192.0.2.1
+",
+        path.display()
+    ));
+    let handler = DnsRpzHandler {
+        next: router::DnsRouteHandler::new(conf.as_ref().unwrap().clone()).await,
+        config: conf.unwrap(),
+    };
+    let query = |name: &str| DnsMessage::new_for_test(name, dnspkt::RR_A);
+
+    let reply = handler
+        .handle_query(&query("www.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(reply.rcode, dnspkt::NOERROR);
+    assert_eq!(reply.answer.len(), 1);
+    assert!(reply.edns.is_none());
+
+    let reply = handler
+        .handle_query(&query("blocked.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(reply.rcode, dnspkt::NXDOMAIN);
+    assert_eq!(reply.nameserver.len(), 1);
+    assert_eq!(
+        reply.edns.unwrap().get_extended_dns_error().unwrap().0,
+        dnspkt::EDE_BLOCKED
+    );
+
+    assert!(matches!(
+        handler.handle_query(&query("drop.example.com")).await,
+        Err(Error::Dropped)
+    ));
+
+    let reply = handler
+        .handle_query(&query("printer.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(reply.rcode, dnspkt::NOERROR);
+    assert_eq!(reply.answer.len(), 1);
+    assert_eq!(reply.answer[0].domain.to_string(), "printer.example.com");
+
+    let reply = handler
+        .handle_query(&query("alias.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(reply.answer.len(), 2);
+    assert_eq!(reply.answer[0].rrtype, dnspkt::RR_CNAME);
+    assert_eq!(reply.answer[1].domain.to_string(), "www.example.com");
+
+    /* bad.example.com resolves to an address with a response IP trigger */
+    let reply = handler
+        .handle_query(&query("bad.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(reply.rcode, dnspkt::NOERROR);
+    assert!(reply.answer.is_empty());
+}
+
+#[tokio::test]
+async fn test_axfr() {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    let origin: dnspkt::Domain = "rpz.example".parse().unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let primary = listener.local_addr().unwrap();
+
+    /* A primary that answers each transfer with just the SOA, using the given change to the id */
+    let serve = |qid_offset: u16| {
+        let origin = origin.clone();
+        let listener = &listener;
+        async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut lbytes = [0u8; 2];
+            sock.read_exact(&mut lbytes).await.unwrap();
+            let mut buffer = vec![0u8; u16::from_be_bytes(lbytes) as usize];
+            sock.read_exact(&mut buffer).await.unwrap();
+            let query = super::parse::PktParser::new(&buffer).get_dns().unwrap();
+            let soa = super::zone::default_soa(&origin);
+            let reply = dnspkt::DNSPkt {
+                qid: query.qid.wrapping_add(qid_offset),
+                qr: true,
+                aa: true,
+                answer: vec![soa.clone(), soa],
+                ..query
+            }
+            .serialise();
+            sock.write_all(&(reply.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            sock.write_all(&reply).await.unwrap();
+        }
+    };
+
+    let (rrs, _) = tokio::join!(axfr(&origin, primary), serve(0));
+    assert_eq!(rrs.unwrap().len(), 2);
+
+    /* Replies to some other query are rejected */
+    let (rrs, _) = tokio::join!(axfr(&origin, primary), serve(1));
+    assert!(rrs.is_err());
+}
@@ -0,0 +1,733 @@
+/*   Copyright 2024 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Locally authoritative zones.
+ *
+ *  A zone is a set of records under a single origin that we answer for ourselves, rather than
+ *  forwarding upstream.  Records are written in the usual master file presentation format (eg
+ *  "10 mail.home.arpa" for an MX record).
+ */
+
+use super::dnspkt;
+use super::Error;
+
+/// TTL used for records that don't specify one.
+pub const DEFAULT_TTL: u32 = 300;
+
+/// Limit on how many CNAMEs we will follow inside a zone before giving up.
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug)]
+pub struct Zone {
+    pub origin: dnspkt::Domain,
+    /* Keyed by the lowercased name, as DNS names are case insensitive. */
+    records: std::collections::HashMap<String, Vec<dnspkt::RR>>,
+    /* Every name in the zone, including empty non-terminals, so we can tell NODATA from NXDOMAIN */
+    names: std::collections::HashSet<String>,
+}
+
+fn name_key(name: &dnspkt::Domain) -> String {
+    name.to_string().to_ascii_lowercase()
+}
+
+/// Returns the SOA we use for zones that don't provide their own.
+pub fn default_soa(origin: &dnspkt::Domain) -> dnspkt::RR {
+    let hostmaster = if origin.to_string().is_empty() {
+        "hostmaster".to_string()
+    } else {
+        format!("hostmaster.{}", origin)
+    };
+    dnspkt::RR {
+        domain: origin.clone(),
+        class: dnspkt::CLASS_IN,
+        rrtype: dnspkt::RR_SOA,
+        ttl: DEFAULT_TTL,
+        rdata: dnspkt::RData::Soa(dnspkt::SoaData {
+            mname: origin.clone(),
+            rname: hostmaster
+                .parse()
+                .expect("origin is already a valid domain"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: DEFAULT_TTL,
+        }),
+    }
+}
+
+impl Zone {
+    pub fn new(origin: dnspkt::Domain) -> Self {
+        let mut names = std::collections::HashSet::new();
+        names.insert(name_key(&origin));
+        Self {
+            origin,
+            records: Default::default(),
+            names,
+        }
+    }
+
+    /// Adds a record to the zone.
+    pub fn insert(&mut self, rr: dnspkt::RR) -> Result<(), String> {
+        if !rr.domain.ends_with(&self.origin) {
+            return Err(format!("{} is not within {}", rr.domain, self.origin));
+        }
+        let key = name_key(&rr.domain);
+        if rr.rrtype == dnspkt::RR_SOA && key != name_key(&self.origin) {
+            return Err(format!("SOA for {} must be at {}", rr.domain, self.origin));
+        }
+        let existing = self.records.get(&key).map(Vec::as_slice).unwrap_or(&[]);
+        if existing.iter().any(|e| e.rrtype == dnspkt::RR_SOA) && rr.rrtype == dnspkt::RR_SOA {
+            return Err(format!("{} has more than one SOA", rr.domain));
+        }
+        if existing
+            .iter()
+            .any(|e| (e.rrtype == dnspkt::RR_CNAME) != (rr.rrtype == dnspkt::RR_CNAME))
+            || (rr.rrtype == dnspkt::RR_CNAME && !existing.is_empty())
+        {
+            return Err(format!("{} has a CNAME and other records", rr.domain));
+        }
+        self.records.entry(key.clone()).or_default().push(rr);
+
+        /* Record all the parent names between here and the origin */
+        let origin = name_key(&self.origin);
+        let mut name = key.as_str();
+        while name != origin && self.names.insert(name.to_string()) {
+            name = match name.split_once('.') {
+                Some((_, parent)) => parent,
+                None => "",
+            };
+        }
+        Ok(())
+    }
+
+    /// The SOA for this zone, as used in the authority section of negative answers.
+    pub fn soa(&self) -> dnspkt::RR {
+        self.records
+            .get(&name_key(&self.origin))
+            .and_then(|rrs| rrs.iter().find(|rr| rr.rrtype == dnspkt::RR_SOA))
+            .cloned()
+            .unwrap_or_else(|| default_soa(&self.origin))
+    }
+
+    /// Every record in the zone.
+    pub fn records(&self) -> impl Iterator<Item = &dnspkt::RR> {
+        self.records.values().flatten()
+    }
+
+    /// Answers a question from this zone, returning the rcode, answers and authority records.
+    pub fn lookup(
+        &self,
+        question: &dnspkt::Question,
+    ) -> (dnspkt::RCode, Vec<dnspkt::RR>, Vec<dnspkt::RR>) {
+        let mut answer = vec![];
+        let mut qdomain = question.qdomain.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let key = name_key(&qdomain);
+            if question.qtype == dnspkt::RR_SOA && key == name_key(&self.origin) {
+                answer.push(dnspkt::RR {
+                    domain: qdomain,
+                    ..self.soa()
+                });
+                return (dnspkt::NOERROR, answer, vec![]);
+            }
+            let rrs = self.records.get(&key).map(Vec::as_slice).unwrap_or(&[]);
+            let matching = rrs
+                .iter()
+                .filter(|rr| question.qtype == dnspkt::RR_ANY || rr.rrtype == question.qtype)
+                .map(|rr| dnspkt::RR {
+                    domain: qdomain.clone(),
+                    ..rr.clone()
+                })
+                .collect::<Vec<_>>();
+            if !matching.is_empty() {
+                answer.extend(matching);
+                return (dnspkt::NOERROR, answer, vec![]);
+            }
+            match rrs.iter().find(|rr| rr.rrtype == dnspkt::RR_CNAME) {
+                Some(cname) => {
+                    answer.push(dnspkt::RR {
+                        domain: qdomain.clone(),
+                        ..cname.clone()
+                    });
+                    match &cname.rdata {
+                        dnspkt::RData::CName(target) if target.ends_with(&self.origin) => {
+                            qdomain = target.clone()
+                        }
+                        /* Points outside the zone, the resolver will have to chase it */
+                        _ => return (dnspkt::NOERROR, answer, vec![]),
+                    }
+                }
+                None if self.names.contains(&key) => {
+                    return (dnspkt::NOERROR, answer, vec![self.soa()])
+                }
+                None => return (dnspkt::NXDOMAIN, answer, vec![self.soa()]),
+            }
+        }
+        log::warn!("CNAME chain too long looking up {}", question.qdomain);
+        (dnspkt::SERVFAIL, vec![], vec![])
+    }
+}
+
+/// Finds the zone that should answer a question, and builds an authoritative reply from it.
+pub fn handle_query(msg: &super::DnsMessage, zones: &[Zone]) -> Result<dnspkt::DNSPkt, Error> {
+    let zone = zones
+        .iter()
+        .filter(|z| msg.in_query.question.qdomain.ends_with(&z.origin))
+        .min_by(|a, b| dnspkt::compare_longest_suffix(&a.origin, &b.origin))
+        .ok_or(Error::NoRouteConfigured)?;
+    let (rcode, answer, nameserver) = zone.lookup(&msg.in_query.question);
+    Ok(dnspkt::DNSPkt {
+        aa: true,
+        answer,
+        nameserver,
+        ..dnspkt::DNSPkt::reply_to(&msg.in_query, rcode)
+    })
+}
+
+/// Parses a record type mnemonic (eg "AAAA"), or the generic "TYPEnn" form from RFC3597.
+pub fn parse_type(s: &str) -> Option<dnspkt::Type> {
+    match s.to_ascii_uppercase().as_str() {
+        "A" => Some(dnspkt::RR_A),
+        "NS" => Some(dnspkt::RR_NS),
+        "CNAME" => Some(dnspkt::RR_CNAME),
+        "SOA" => Some(dnspkt::RR_SOA),
+        "PTR" => Some(dnspkt::RR_PTR),
+        "MX" => Some(dnspkt::RR_MX),
+        "TXT" => Some(dnspkt::RR_TXT),
+        "AAAA" => Some(dnspkt::RR_AAAA),
+        "SRV" => Some(dnspkt::RR_SRV),
+        t => t
+            .strip_prefix("TYPE")
+            .and_then(|n| n.parse().ok())
+            .map(dnspkt::Type),
+    }
+}
+
+/// Parses a domain name.  Names without a trailing dot are relative to origin if there is one.
+pub fn parse_name(s: &str, origin: Option<&dnspkt::Domain>) -> Result<dnspkt::Domain, String> {
+    let name = match origin {
+        Some(origin) if s == "@" => return Ok(origin.clone()),
+        Some(origin) if !s.ends_with('.') && !origin.to_string().is_empty() => {
+            format!("{}.{}", s, origin)
+        }
+        /* The root domain is the only name that is just a trailing dot */
+        _ if s == "." => String::new(),
+        _ => s.to_string(),
+    };
+    name.parse()
+        .map_err(|e| format!("Invalid domain name {:?}: {}", s, e))
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&str>, what: &str) -> Result<T, String> {
+    let field = field.ok_or_else(|| format!("Missing {}", what))?;
+    field
+        .parse()
+        .map_err(|_| format!("Invalid {} {:?}", what, field))
+}
+
+/// Builds TXT rdata out of a list of strings.
+pub fn txt_rdata<S: AsRef<[u8]>>(strings: &[S]) -> Result<dnspkt::RData, String> {
+    let mut v = vec![];
+    for s in strings {
+        let s = s.as_ref();
+        if s.len() > 255 {
+            return Err("TXT strings must be 255 bytes or less".into());
+        }
+        v.push(s.len() as u8);
+        v.extend_from_slice(s);
+    }
+    Ok(dnspkt::RData::Other(v))
+}
+
+/// Parses the presentation format of rdata, already split into fields.
+pub fn parse_rdata<S: AsRef<str>>(
+    rrtype: dnspkt::Type,
+    fields: &[S],
+    origin: Option<&dnspkt::Domain>,
+) -> Result<dnspkt::RData, String> {
+    let mut it = fields.iter().map(AsRef::as_ref);
+    let rdata = match rrtype {
+        dnspkt::RR_A => dnspkt::RData::Other(
+            parse_field::<std::net::Ipv4Addr>(it.next(), "IPv4 address")?
+                .octets()
+                .to_vec(),
+        ),
+        dnspkt::RR_AAAA => dnspkt::RData::Other(
+            parse_field::<std::net::Ipv6Addr>(it.next(), "IPv6 address")?
+                .octets()
+                .to_vec(),
+        ),
+        dnspkt::RR_CNAME | dnspkt::RR_NS | dnspkt::RR_PTR => {
+            let name = parse_name(it.next().ok_or("Missing domain name")?, origin)?;
+            match rrtype {
+                dnspkt::RR_CNAME => dnspkt::RData::CName(name),
+                dnspkt::RR_NS => dnspkt::RData::Ns(name),
+                _ => dnspkt::RData::Ptr(name),
+            }
+        }
+        dnspkt::RR_MX => dnspkt::RData::Mx(dnspkt::PrefDomainData {
+            pref: parse_field(it.next(), "preference")?,
+            domain: parse_name(it.next().ok_or("Missing exchange")?, origin)?,
+        }),
+        dnspkt::RR_SRV => {
+            let mut v = vec![];
+            for what in ["priority", "weight", "port"] {
+                v.extend_from_slice(&parse_field::<u16>(it.next(), what)?.to_be_bytes());
+            }
+            v.extend(parse_name(it.next().ok_or("Missing target")?, origin)?.to_wire());
+            dnspkt::RData::Other(v)
+        }
+        dnspkt::RR_SOA => dnspkt::RData::Soa(dnspkt::SoaData {
+            mname: parse_name(it.next().ok_or("Missing mname")?, origin)?,
+            rname: parse_name(it.next().ok_or("Missing rname")?, origin)?,
+            serial: parse_field(it.next(), "serial")?,
+            refresh: parse_field(it.next(), "refresh")?,
+            retry: parse_field(it.next(), "retry")?,
+            expire: parse_field(it.next(), "expire")?,
+            minimum: parse_field(it.next(), "minimum")?,
+        }),
+        dnspkt::RR_TXT => return txt_rdata(&it.collect::<Vec<_>>()),
+        t => return Err(format!("Unsupported record type {}", t)),
+    };
+    match it.next() {
+        Some(extra) => Err(format!("Unexpected {:?} in {} record", extra, rrtype)),
+        None => Ok(rdata),
+    }
+}
+
+/// A single logical entry from a master file, which may have spanned several lines.
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    line: usize,
+    /// The entry started with whitespace, so uses the previous owner name.
+    blank_owner: bool,
+    fields: Vec<String>,
+}
+
+/// Splits a master file into entries, handling comments, quoting, escapes and parentheses.
+fn tokenise(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = vec![];
+    let mut line = 1;
+    let mut depth = 0;
+    let mut paren_line = 0;
+    let mut entry: Option<Entry> = None;
+    let mut field: Option<String> = None;
+    let mut in_quote = false;
+    let mut start_of_line = true;
+    let mut chars = text.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if start_of_line && depth == 0 {
+            if let Some(e) = entry.take() {
+                if !e.fields.is_empty() {
+                    entries.push(e);
+                }
+            }
+            entry = Some(Entry {
+                line,
+                blank_owner: ch == ' ' || ch == '\t',
+                fields: vec![],
+            });
+        }
+        start_of_line = false;
+        let e = entry
+            .as_mut()
+            .expect("entry is always started at the start of a line");
+        match ch {
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some(d) if d.is_ascii_digit() => {
+                        let mut num = d.to_digit(10).unwrap();
+                        for _ in 0..2 {
+                            match chars.next().and_then(|d| d.to_digit(10)) {
+                                Some(d) => num = num * 10 + d,
+                                None => return Err((line, "Invalid \\DDD escape".into())),
+                            }
+                        }
+                        match char::from_u32(num).filter(char::is_ascii) {
+                            Some(c) => c,
+                            None => {
+                                return Err((line, "Only ASCII \\DDD escapes are supported".into()))
+                            }
+                        }
+                    }
+                    Some('\n') | None => return Err((line, "Escape at end of line".into())),
+                    Some(c) => c,
+                };
+                field.get_or_insert_with(String::new).push(escaped);
+            }
+            '"' => {
+                in_quote = !in_quote;
+                field.get_or_insert_with(String::new);
+            }
+            '\n' if in_quote => return Err((line, "Unterminated quoted string".into())),
+            c if in_quote => field.get_or_insert_with(String::new).push(c),
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' => {
+                e.fields.extend(field.take());
+                if depth == 0 {
+                    paren_line = line;
+                }
+                depth += 1;
+            }
+            ')' => {
+                e.fields.extend(field.take());
+                if depth == 0 {
+                    return Err((line, "Unbalanced )".into()));
+                }
+                depth -= 1;
+            }
+            '\n' => {
+                e.fields.extend(field.take());
+                line += 1;
+                start_of_line = true;
+            }
+            c if c.is_whitespace() => e.fields.extend(field.take()),
+            c => field.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if in_quote {
+        return Err((line, "Unterminated quoted string".into()));
+    }
+    if depth != 0 {
+        return Err((paren_line, "Unbalanced (".into()));
+    }
+    if let Some(mut e) = entry {
+        e.fields.extend(field);
+        if !e.fields.is_empty() {
+            entries.push(e);
+        }
+    }
+    Ok(entries)
+}
+
+/// Parses a TTL, either as a number of seconds or with units (eg "1h30m").
+fn parse_ttl(s: &str) -> Option<u32> {
+    if let Ok(ttl) = s.parse() {
+        return Some(ttl);
+    }
+    let mut total: u32 = 0;
+    let mut num: Option<u32> = None;
+    for c in s.chars() {
+        match c.to_digit(10) {
+            Some(d) => num = Some(num.unwrap_or(0).checked_mul(10)?.checked_add(d)?),
+            None => {
+                let mult = match c.to_ascii_lowercase() {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86400,
+                    'w' => 604800,
+                    _ => return None,
+                };
+                total = total.checked_add(num.take()?.checked_mul(mult)?)?;
+            }
+        }
+    }
+    if num.is_some() {
+        return None;
+    }
+    Some(total)
+}
+
+/// Loads an RFC1035 master file into a zone.  Errors are returned with the line they occurred
+/// on.
+pub fn parse_master_file(text: &str, origin: dnspkt::Domain) -> Result<Zone, (usize, String)> {
+    let mut zone = Zone::new(origin.clone());
+    let mut origin = origin;
+    let mut default_ttl = None;
+    let mut last_owner: Option<dnspkt::Domain> = None;
+    let mut last_ttl = None;
+
+    for entry in tokenise(text)? {
+        let line = entry.line;
+        let mut fields = entry.fields.iter().map(String::as_str).peekable();
+        let first = *fields.peek().expect("entries are never empty");
+        match first {
+            "$ORIGIN" => {
+                fields.next();
+                origin = match (fields.next(), fields.next()) {
+                    (Some(name), None) => parse_name(name, Some(&origin)).map_err(|e| (line, e))?,
+                    _ => return Err((line, "$ORIGIN takes exactly one domain name".into())),
+                };
+                continue;
+            }
+            "$TTL" => {
+                fields.next();
+                default_ttl = match (fields.next().and_then(parse_ttl), fields.next()) {
+                    (Some(ttl), None) => Some(ttl),
+                    _ => return Err((line, "$TTL takes exactly one TTL".into())),
+                };
+                continue;
+            }
+            d if d.starts_with('$') => {
+                return Err((line, format!("Unsupported directive {}", d)));
+            }
+            _ => (),
+        }
+
+        let owner = if entry.blank_owner {
+            last_owner
+                .clone()
+                .ok_or((line, "No previous owner name".to_string()))?
+        } else {
+            let name = fields.next().expect("entries are never empty");
+            parse_name(name, Some(&origin)).map_err(|e| (line, e))?
+        };
+
+        /* The TTL and class are both optional, and can come in either order */
+        let mut ttl = None;
+        let rrtype = loop {
+            match fields.next() {
+                Some(f) if f.eq_ignore_ascii_case("IN") => (),
+                Some(f) if ["CH", "HS", "CS"].iter().any(|c| f.eq_ignore_ascii_case(c)) => {
+                    return Err((line, format!("Unsupported class {}", f)))
+                }
+                Some(f) if ttl.is_none() && f.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl = Some(parse_ttl(f).ok_or((line, format!("Invalid TTL {}", f)))?)
+                }
+                Some(f) => {
+                    break parse_type(f).ok_or((line, format!("Unknown record type {}", f)))?
+                }
+                None => return Err((line, "Missing record type".into())),
+            }
+        };
+        let rdata = parse_rdata(rrtype, &fields.collect::<Vec<_>>(), Some(&origin))
+            .map_err(|e| (line, e))?;
+        /* RFC2308 Section 4: Without an explicit TTL, use $TTL, otherwise the previous TTL */
+        let ttl = ttl.or(default_ttl).or(last_ttl).unwrap_or(DEFAULT_TTL);
+        zone.insert(dnspkt::RR {
+            domain: owner.clone(),
+            class: dnspkt::CLASS_IN,
+            rrtype,
+            ttl,
+            rdata,
+        })
+        .map_err(|e| (line, e))?;
+        last_owner = Some(owner);
+        last_ttl = Some(ttl);
+    }
+    Ok(zone)
+}
+
+#[cfg(test)]
+fn mk_rr(name: &str, rrtype: &str, value: &str) -> dnspkt::RR {
+    let rrtype = parse_type(rrtype).unwrap();
+    dnspkt::RR {
+        domain: name.parse().unwrap(),
+        class: dnspkt::CLASS_IN,
+        rrtype,
+        ttl: DEFAULT_TTL,
+        rdata: parse_rdata(rrtype, &value.split_whitespace().collect::<Vec<_>>(), None).unwrap(),
+    }
+}
+
+#[cfg(test)]
+fn mk_question(name: &str, qtype: dnspkt::Type) -> dnspkt::Question {
+    dnspkt::Question {
+        qdomain: name.parse().unwrap(),
+        qclass: dnspkt::CLASS_IN,
+        qtype,
+    }
+}
+
+#[test]
+fn test_parse_rdata() {
+    assert_eq!(
+        mk_rr("a.example", "AAAA", "2001:db8::1").rdata,
+        dnspkt::RData::Other(vec![
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1
+        ])
+    );
+    assert_eq!(
+        mk_rr("_http._tcp.example", "srv", "1 2 80 www.example").rdata,
+        dnspkt::RData::Other(vec![
+            0, 1, 0, 2, 0, 80, 3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0
+        ])
+    );
+    assert_eq!(
+        parse_rdata(dnspkt::RR_TXT, &["hello", "world"], None).unwrap(),
+        dnspkt::RData::Other(b"\x05hello\x05world".to_vec())
+    );
+    let origin = "example".parse().unwrap();
+    assert_eq!(
+        parse_rdata(dnspkt::RR_MX, &["10", "mail"], Some(&origin)).unwrap(),
+        dnspkt::RData::Mx(dnspkt::PrefDomainData {
+            pref: 10,
+            domain: "mail.example".parse().unwrap()
+        })
+    );
+    assert_eq!(parse_type("TYPE99"), Some(dnspkt::Type(99)));
+    assert!(parse_rdata(dnspkt::RR_A, &["192.0.2.300"], None).is_err());
+    assert!(parse_rdata(dnspkt::RR_A, &["192.0.2.1", "extra"], None).is_err());
+    assert!(parse_rdata(dnspkt::RR_MX, &["10"], None).is_err());
+}
+
+#[test]
+fn test_zone_lookup() {
+    let mut zone = Zone::new("home.arpa".parse().unwrap());
+    zone.insert(mk_rr("router.home.arpa", "A", "192.0.2.1"))
+        .unwrap();
+    zone.insert(mk_rr("www.home.arpa", "CNAME", "Router.home.arpa"))
+        .unwrap();
+    zone.insert(mk_rr("ext.home.arpa", "CNAME", "www.example.com"))
+        .unwrap();
+    zone.insert(mk_rr(
+        "_http._tcp.home.arpa",
+        "SRV",
+        "0 0 80 router.home.arpa",
+    ))
+    .unwrap();
+    assert!(zone
+        .insert(mk_rr("www.home.arpa", "A", "192.0.2.2"))
+        .is_err());
+    assert!(zone
+        .insert(mk_rr("www.example.com", "A", "192.0.2.2"))
+        .is_err());
+
+    let (rcode, answer, authority) = zone.lookup(&mk_question("ROUTER.home.arpa", dnspkt::RR_A));
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(answer.len(), 1);
+    assert_eq!(answer[0].domain, "ROUTER.home.arpa".parse().unwrap());
+    assert!(authority.is_empty());
+
+    /* CNAMEs inside the zone are followed */
+    let (rcode, answer, _) = zone.lookup(&mk_question("www.home.arpa", dnspkt::RR_A));
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(answer.len(), 2);
+    assert_eq!(answer[1].rrtype, dnspkt::RR_A);
+
+    let (rcode, answer, authority) = zone.lookup(&mk_question("ext.home.arpa", dnspkt::RR_A));
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(answer.len(), 1);
+    assert!(authority.is_empty());
+
+    /* NODATA, including for the empty non-terminal _tcp.home.arpa */
+    for name in ["router.home.arpa", "_tcp.home.arpa", "home.arpa"] {
+        let (rcode, answer, authority) = zone.lookup(&mk_question(name, dnspkt::RR_AAAA));
+        assert_eq!(rcode, dnspkt::NOERROR, "{}", name);
+        assert!(answer.is_empty());
+        assert_eq!(authority[0].rrtype, dnspkt::RR_SOA);
+    }
+
+    let (rcode, answer, authority) = zone.lookup(&mk_question("nas.home.arpa", dnspkt::RR_A));
+    assert_eq!(rcode, dnspkt::NXDOMAIN);
+    assert!(answer.is_empty());
+    assert_eq!(authority, vec![default_soa(&zone.origin)]);
+
+    let (rcode, answer, _) = zone.lookup(&mk_question("home.arpa", dnspkt::RR_SOA));
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(answer[0].rrtype, dnspkt::RR_SOA);
+}
+
+#[test]
+fn test_tokenise() {
+    let entries = tokenise(
+        "$ORIGIN example. ; comment\n@ SOA ns ( hostmaster\n  1 ; serial\n 2 3 4 5 )\n\tTXT \"a \\\"b\\\" ;c\" \"\" d\\065\n",
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].fields, vec!["$ORIGIN", "example."]);
+    assert_eq!(entries[1].line, 2);
+    assert_eq!(
+        entries[1].fields,
+        vec!["@", "SOA", "ns", "hostmaster", "1", "2", "3", "4", "5"]
+    );
+    assert!(entries[2].blank_owner);
+    assert_eq!(entries[2].line, 5);
+    assert_eq!(entries[2].fields, vec!["TXT", "a \"b\" ;c", "", "dA"]);
+    assert_eq!(tokenise("a A (\n1.2.3.4\n").unwrap_err().0, 1);
+    assert_eq!(tokenise("a A 1.2.3.4\nb A )\n").unwrap_err().0, 2);
+}
+
+#[test]
+fn test_parse_master_file() {
+    let zone = parse_master_file(
+        "$TTL 1h
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                3600 600 86400 300 )
+        IN  NS  ns1
+ns1         A   192.0.2.53
+router  60  IN A 192.0.2.1
+            AAAA 2001:db8::1
+www         CNAME router
+mail        MX  10 router.home.arpa.
+$ORIGIN lab
+printer     A   192.0.2.5
+",
+        "home.arpa".parse().unwrap(),
+    )
+    .unwrap();
+
+    let (rcode, answer, _) = zone.lookup(&mk_question("home.arpa", dnspkt::RR_SOA));
+    assert_eq!(rcode, dnspkt::NOERROR);
+    match &answer[0].rdata {
+        dnspkt::RData::Soa(soa) => {
+            assert_eq!(soa.mname, "ns1.home.arpa".parse().unwrap());
+            assert_eq!(soa.serial, 2024010101);
+            assert_eq!(soa.minimum, 300);
+        }
+        rdata => panic!("Expected SOA, got {:?}", rdata),
+    }
+    assert_eq!(answer[0].ttl, 3600);
+
+    let (_, answer, _) = zone.lookup(&mk_question("router.home.arpa", dnspkt::RR_A));
+    assert_eq!(answer[0].ttl, 60);
+    /* Records without a TTL use $TTL, not the previous record's TTL */
+    let (_, answer, _) = zone.lookup(&mk_question("router.home.arpa", dnspkt::RR_AAAA));
+    assert_eq!(answer[0].ttl, 3600);
+    let (_, answer, _) = zone.lookup(&mk_question("home.arpa", dnspkt::RR_NS));
+    assert_eq!(
+        answer[0].rdata,
+        dnspkt::RData::Ns("ns1.home.arpa".parse().unwrap())
+    );
+    let (_, answer, _) = zone.lookup(&mk_question("www.home.arpa", dnspkt::RR_A));
+    assert_eq!(answer.len(), 2);
+    let (_, answer, _) = zone.lookup(&mk_question("mail.home.arpa", dnspkt::RR_MX));
+    assert_eq!(
+        answer[0].rdata,
+        dnspkt::RData::Mx(dnspkt::PrefDomainData {
+            pref: 10,
+            domain: "router.home.arpa".parse().unwrap()
+        })
+    );
+    let (rcode, answer, _) = zone.lookup(&mk_question("printer.lab.home.arpa", dnspkt::RR_A));
+    assert_eq!(rcode, dnspkt::NOERROR);
+    assert_eq!(answer[0].rdata, dnspkt::RData::Other(vec![192, 0, 2, 5]));
+}
+
+#[test]
+fn test_master_file_errors() {
+    let origin: dnspkt::Domain = "home.arpa".parse().unwrap();
+    for (text, line) in [
+        ("a A 192.0.2.1\nb A 192.0.2.300\n", 2),
+        ("a A 192.0.2.1\n\nb BOGUS x\n", 3),
+        ("  A 192.0.2.1\n", 1),
+        ("a.example.com. A 192.0.2.1\n", 1),
+        ("$INCLUDE other.zone\n", 1),
+        ("a CH A 192.0.2.1\n", 1),
+        ("@ SOA ns hostmaster (\n 1 2 3 4\n", 1),
+    ] {
+        assert_eq!(
+            parse_master_file(text, origin.clone()).unwrap_err().0,
+            line,
+            "{}",
+            text
+        );
+    }
+}
@@ -31,12 +31,18 @@ use std::convert::Infallible;
 // TODO: the code here that depends on nix should move into erbium-net
 use erbium_net::nix;
 
+#[cfg(feature = "dns")]
+pub type DnsService = crate::dns::DnsService;
+#[cfg(not(feature = "dns"))]
+pub type DnsService = Infallible;
+
 #[derive(Debug)]
 pub enum Error {
     InvalidName(String),
     ListenError(String, std::io::Error),
     SocketInUse(String),
     CleanupFailed(String, std::io::Error),
+    TlsError(String),
 }
 
 impl std::error::Error for Error {}
@@ -55,6 +61,7 @@ impl std::fmt::Display for Error {
                 )
             }
             CleanupFailed(sock_name, err) => write!(f, "Failed to cleanup {}: {}", sock_name, err),
+            TlsError(msg) => write!(f, "Failed to set up TLS: {}", msg),
         }
     }
 }
@@ -154,6 +161,118 @@ async fn serve_leases<IB: Body>(
         .unwrap())
 }
 
+#[cfg(feature = "dns")]
+fn dns_query_error(status: hyper::StatusCode, msg: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(format!("{}\n", msg).into())
+        .unwrap()
+}
+
+/// Answers DNS over HTTPS (RFC8484) queries, either POSTed as the body, or base64url encoded in
+/// the "dns" parameter of a GET.
+#[cfg(feature = "dns")]
+async fn serve_dns_query<IB>(
+    req: Request<IB>,
+    dns: &DnsService,
+    local_ip: Option<std::net::IpAddr>,
+    addr: &NetAddr,
+) -> Result<Response<Full<Bytes>>, Infallible>
+where
+    IB: Body,
+    IB::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    use erbium_net::addr::NetAddrExt as _;
+    use hyper::{Method, StatusCode};
+    const DNS_MESSAGE: &str = "application/dns-message";
+
+    /* The DNS server needs to know the IP addresses at both ends, eg for DNS cookies */
+    let local_ip = match (local_ip, addr.ip()) {
+        (Some(local_ip), Some(_)) => local_ip,
+        _ => {
+            return Ok(dns_query_error(
+                StatusCode::BAD_REQUEST,
+                "DNS queries are only supported over IP",
+            ))
+        }
+    };
+
+    let query = match *req.method() {
+        Method::GET => {
+            use base64::Engine as _;
+            let Some(param) = req
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+            else {
+                return Ok(dns_query_error(
+                    StatusCode::BAD_REQUEST,
+                    "Missing dns parameter",
+                ));
+            };
+            match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(param) {
+                Ok(query) => query,
+                Err(err) => {
+                    return Ok(dns_query_error(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Invalid dns parameter: {}", err),
+                    ))
+                }
+            }
+        }
+        Method::POST => {
+            use http_body_util::BodyExt as _;
+            if req
+                .headers()
+                .get(hyper::header::CONTENT_TYPE)
+                .map(|ct| ct != DNS_MESSAGE)
+                .unwrap_or(true)
+            {
+                return Ok(dns_query_error(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "Expected application/dns-message",
+                ));
+            }
+            match http_body_util::Limited::new(req.into_body(), 65535)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(err) => {
+                    return Ok(dns_query_error(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Failed to read query: {}", err),
+                    ))
+                }
+            }
+        }
+        _ => {
+            return Ok(dns_query_error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Only GET and POST are supported",
+            ))
+        }
+    };
+
+    match dns.handle_https_query(&query, local_ip, *addr).await {
+        Ok((reply, ttl)) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", DNS_MESSAGE)
+            .header("Cache-Control", format!("max-age={}", ttl))
+            .body(reply.into())
+            .unwrap()),
+        /* There is no way to not answer a HTTP request, so behave as if the query timed out */
+        Err(crate::dns::Error::Dropped) => Ok(dns_query_error(
+            StatusCode::GATEWAY_TIMEOUT,
+            "Query dropped by policy",
+        )),
+        Err(err) => Ok(dns_query_error(StatusCode::BAD_REQUEST, &err.to_string())),
+    }
+}
+
 fn permission_denied() -> Response<Full<Bytes>> {
     use hyper::StatusCode;
     Response::builder()
@@ -187,15 +306,41 @@ fn require_http_permission(
     }
 }
 
-async fn serve_request<IB: Body>(
+#[cfg_attr(not(feature = "dns"), allow(unused_variables))]
+async fn serve_request<IB>(
     conf: crate::config::SharedConfig,
     req: Request<IB>,
     addr: std::sync::Arc<NetAddr>,
+    local_ip: Option<std::net::IpAddr>,
     dhcp: std::sync::Arc<crate::dhcp::DhcpService>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
+    dns: Option<DnsService>,
+) -> Result<Response<Full<Bytes>>, Infallible>
+where
+    IB: Body,
+    IB::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
     use hyper::{Method, StatusCode};
 
-    let client = acl::Attributes { addr: *addr };
+    let client = acl::Attributes {
+        addr: *addr,
+        interface: None,
+    };
+
+    /* Only DNS over HTTPS listeners are given a DNS service, and they only serve DNS.  Access to
+     * DNS is controlled by the DNS ACLs, the same as any other DNS query.
+     */
+    #[cfg(feature = "dns")]
+    if let Some(dns) = &dns {
+        if req.uri().path() == "/dns-query" {
+            return serve_dns_query(req, dns, local_ip, &addr).await;
+        }
+    }
+    if dns.is_some() {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("Not found".into())
+            .unwrap());
+    }
 
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => {
@@ -221,7 +366,17 @@ async fn serve_request<IB: Body>(
                 serve_metrics(req).await
             }
         }
-        (&Method::GET, "/api/v1/leases.json") => serve_leases(req, &dhcp).await,
+        (&Method::GET, "/api/v1/leases.json") => {
+            if let Some(ret) = require_http_permission(
+                &conf.read().await.acls,
+                &client,
+                acl::PermissionType::HttpLeases,
+            ) {
+                Ok(ret)
+            } else {
+                serve_leases(req, &dhcp).await
+            }
+        }
         _ => {
             if let Some(ret) = require_http_permission(
                 &conf.read().await.acls,
@@ -239,6 +394,38 @@ async fn serve_request<IB: Body>(
     }
 }
 
+/// Serves HTTP/1.1, or HTTP/2 if the client asks for it (which DNS over HTTPS clients usually do).
+async fn serve_connection<S>(
+    conf: crate::config::SharedConfig,
+    dhcp: std::sync::Arc<crate::dhcp::DhcpService>,
+    dns: Option<DnsService>,
+    stream: S,
+    addr: NetAddr,
+    local_ip: Option<std::net::IpAddr>,
+) where
+    S: Unpin + Send + 'static + hyper::rt::Write + hyper::rt::Read,
+{
+    use hyper::service::service_fn;
+    use hyper_util::{rt::TokioExecutor, server::conn::auto};
+
+    let addr = std::sync::Arc::new(addr);
+    let srv = move |req| {
+        serve_request(
+            conf.clone(),
+            req,
+            addr.clone(),
+            local_ip,
+            dhcp.clone(),
+            dns.clone(),
+        )
+    };
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1().keep_alive(true);
+    if let Err(http_err) = builder.serve_connection(stream, service_fn(srv)).await {
+        log::warn!("Error while serving HTTP connection: {}", http_err);
+    }
+}
+
 async fn run_listener<L>(
     conf: crate::config::SharedConfig,
     dhcp: std::sync::Arc<crate::dhcp::DhcpService>,
@@ -248,35 +435,123 @@ where
     L: Accepter + Unpin,
     <L as Accepter>::AcceptedSocket: Unpin + hyper::rt::Write + hyper::rt::Read + Send + 'static,
 {
-    use hyper::service::service_fn;
-
     loop {
         let (stream, addr) = match listener.accept_connection().await {
-            Ok((stream, addr)) => (stream, std::sync::Arc::new(addr)),
+            Ok((stream, addr)) => (stream, addr),
             Err(e) => {
                 log::warn!("Failed to accept on API server: {}", e);
                 continue;
             }
         };
+        tokio::task::spawn(serve_connection(
+            conf.clone(),
+            dhcp.clone(),
+            None,
+            stream,
+            addr,
+            None,
+        ));
+    }
+}
+
+/// Serves HTTPS, doing the TLS handshake for each connection in its own task so a slow client
+/// can't hold up accepting new connections.
+#[cfg(feature = "dns")]
+async fn run_tls_listener(
+    conf: crate::config::SharedConfig,
+    dhcp: std::sync::Arc<crate::dhcp::DhcpService>,
+    dns: Option<DnsService>,
+    listener: tokio::net::TcpListener,
+    acceptor: tokio_rustls::TlsAcceptor,
+) {
+    const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("Failed to accept on HTTPS server: {}", e);
+                continue;
+            }
+        };
+        let local_ip = stream.local_addr().ok().map(|local| local.ip());
         let conf_copy = conf.clone();
         let dhcp_copy = dhcp.clone();
-        let srv = move |req| serve_request(conf_copy.clone(), req, addr.clone(), dhcp_copy.clone());
+        let dns_copy = dns.clone();
+        let acceptor_copy = acceptor.clone();
         tokio::task::spawn(async move {
-            if let Err(http_err) = hyper::server::conn::http1::Builder::new()
-                .keep_alive(true)
-                .serve_connection(stream, service_fn(srv))
-                .await
-            {
-                log::warn!("Error while serving HTTP connection: {}", http_err);
-            }
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor_copy.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        log::debug!("{}: TLS handshake failed: {}", addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        log::debug!("{}: TLS handshake timed out", addr);
+                        return;
+                    }
+                };
+            serve_connection(
+                conf_copy,
+                dhcp_copy,
+                dns_copy,
+                TokioIo::new(stream),
+                addr.into(),
+                local_ip,
+            )
+            .await
         });
     }
 }
 
+#[cfg(feature = "dns")]
+async fn run_https(
+    dhcp: &std::sync::Arc<crate::dhcp::DhcpService>,
+    dns: &Option<DnsService>,
+    conf: &crate::config::SharedConfig,
+) -> Result<(), Error> {
+    use erbium_net::addr::NetAddrExt as _;
+    let roconf = conf.read().await;
+    let Some(cert) = &roconf.dns_tls_certificate else {
+        return Ok(());
+    };
+    if roconf.dns_https_listeners.is_empty() {
+        return Ok(());
+    }
+    let acceptor = tokio_rustls::TlsAcceptor::from(
+        std::sync::Arc::new(
+            crate::tls::CertResolver::new(&cert.certificate, &cert.private_key)
+                .map_err(Error::TlsError)?,
+        )
+        .server_config(&[b"h2", b"http/1.1"]),
+    );
+    for addr in &roconf.dns_https_listeners {
+        let s = addr.to_std_socket_addr().ok_or_else(|| {
+            Error::ListenError(addr.to_string(), std::io::ErrorKind::Unsupported.into())
+        })?;
+        let listener = tokio::net::TcpListener::bind(s)
+            .await
+            .map_err(|e| Error::ListenError(s.to_string(), e))?;
+        log::info!("Listening for HTTPS on {}", s);
+        tokio::task::spawn(run_tls_listener(
+            conf.clone(),
+            dhcp.clone(),
+            dns.clone(),
+            listener,
+            acceptor.clone(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "dns"), allow(unused_variables))]
 pub async fn run(
     dhcp: std::sync::Arc<crate::dhcp::DhcpService>,
+    dns: Option<DnsService>,
     conf: crate::config::SharedConfig,
 ) -> Result<(), Error> {
+    #[cfg(feature = "dns")]
+    run_https(&dhcp, &dns, &conf).await?;
     // Set up all the listeners and listen on them.
     for addr in &conf.read().await.listeners {
         use nix::sys::socket::{AddressFamily::*, SockaddrLike as _};
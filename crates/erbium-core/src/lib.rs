@@ -28,6 +28,26 @@ pub mod http;
 pub mod lldp;
 pub mod pktparser;
 pub mod radv;
+#[cfg(any(feature = "dns", fuzzing))]
+pub mod tls;
 
 #[cfg(test)]
 mod test_man_configs;
+#[cfg(test)]
+mod test_util;
+
+/// The last time a file was modified, or None if it can't be read (eg it doesn't exist yet), so
+/// files can be reloaded when they change.
+pub fn file_modified(path: &std::path::Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Waits until erbium is asked to exit (SIGTERM or SIGINT), so state can be saved first.
+pub async fn shutdown_signal() -> Result<(), std::io::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => Ok(()),
+        x = tokio::signal::ctrl_c() => x,
+    }
+}
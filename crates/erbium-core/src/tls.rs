@@ -0,0 +1,158 @@
+/*   Copyright 2024 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  TLS server certificates.
+ *
+ *  Certificates are short lived these days (eg from an ACME client), so rather than only loading
+ *  the certificate at startup, we check if the files have changed on each new connection and load
+ *  the new certificate if they have.
+ */
+
+use std::sync::Arc;
+use tokio_rustls::rustls;
+
+type ModifiedTimes = (Option<std::time::SystemTime>, Option<std::time::SystemTime>);
+
+#[derive(Debug)]
+struct Loaded {
+    modified: ModifiedTimes,
+    key: Arc<rustls::sign::CertifiedKey>,
+}
+
+/// Serves the certificate and private key from a pair of PEM files, reloading them when they
+/// change.
+#[derive(Debug)]
+pub struct CertResolver {
+    certificate: std::path::PathBuf,
+    private_key: std::path::PathBuf,
+    loaded: std::sync::Mutex<Loaded>,
+}
+
+fn load(
+    certificate: &std::path::Path,
+    private_key: &std::path::Path,
+) -> Result<rustls::sign::CertifiedKey, String> {
+    use rustls::pki_types::pem::PemObject as _;
+    let chain = rustls::pki_types::CertificateDer::pem_file_iter(certificate)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", certificate.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("{}: No certificates found", certificate.display()));
+    }
+    let key = rustls::pki_types::PrivateKeyDer::from_pem_file(private_key)
+        .map_err(|e| format!("{}: {}", private_key.display(), e))?;
+    rustls::sign::CertifiedKey::from_der(chain, key, &rustls::crypto::ring::default_provider())
+        .map_err(|e| format!("{}: {}", private_key.display(), e))
+}
+
+impl CertResolver {
+    /// Loads the certificate (and any intermediate certificates) and private key.
+    pub fn new(
+        certificate: &std::path::Path,
+        private_key: &std::path::Path,
+    ) -> Result<Self, String> {
+        let modified = (
+            crate::file_modified(certificate),
+            crate::file_modified(private_key),
+        );
+        Ok(Self {
+            certificate: certificate.into(),
+            private_key: private_key.into(),
+            loaded: std::sync::Mutex::new(Loaded {
+                modified,
+                key: load(certificate, private_key)?.into(),
+            }),
+        })
+    }
+
+    /// Returns the current certificate, reloading it first if the files have changed.
+    fn current(&self) -> Arc<rustls::sign::CertifiedKey> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let modified = (
+            crate::file_modified(&self.certificate),
+            crate::file_modified(&self.private_key),
+        );
+        if modified != loaded.modified {
+            /* If the new files are broken (eg only one of them has been replaced so far), keep
+             * using the old certificate until they change again.
+             */
+            loaded.modified = modified;
+            match load(&self.certificate, &self.private_key) {
+                Ok(key) => {
+                    log::info!("Reloaded TLS certificate {}", self.certificate.display());
+                    loaded.key = key.into();
+                }
+                Err(err) => log::warn!("Failed to reload TLS certificate: {}", err),
+            }
+        }
+        loaded.key.clone()
+    }
+
+    /// Builds a TLS server configuration using this certificate, negotiating one of the alpn
+    /// protocols.
+    pub fn server_config(self: &Arc<Self>, alpn: &[&[u8]]) -> Arc<rustls::ServerConfig> {
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(self.clone());
+        config.alpn_protocols = alpn.iter().map(|proto| proto.to_vec()).collect();
+        Arc::new(config)
+    }
+}
+
+impl rustls::server::ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        _client_hello: rustls::server::ClientHello<'_>,
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+#[cfg(test)]
+fn write_test_cert(dir: &std::path::Path, name: &str, secs: u64) {
+    let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+    std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+    crate::test_util::set_modified(&dir.join("cert.pem"), secs);
+    crate::test_util::set_modified(&dir.join("key.pem"), secs);
+}
+
+#[test]
+fn test_cert_reload() {
+    let dir = crate::test_util::TempPath::new("tls");
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    assert!(CertResolver::new(&cert, &key).is_err());
+
+    write_test_cert(&dir, "one.test", 1);
+    let resolver = CertResolver::new(&cert, &key).unwrap();
+    let first = resolver.current();
+    assert!(Arc::ptr_eq(&first, &resolver.current()));
+
+    /* Replacing the files loads the new certificate */
+    write_test_cert(&dir, "two.test", 2);
+    let second = resolver.current();
+    assert_ne!(first.cert, second.cert);
+
+    /* A broken certificate is ignored, and the previous one is still used */
+    std::fs::write(&key, "garbage").unwrap();
+    crate::test_util::set_modified(&key, 3);
+    assert_eq!(resolver.current().cert, second.cert);
+}
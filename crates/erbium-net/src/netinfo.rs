@@ -517,7 +517,8 @@ impl SharedNetInfo {
         shared
     }
 
-    #[cfg(test)]
+    /// A fixed set of interfaces (lo and eth0), for tests that don't want to depend on the host.
+    #[cfg(any(test, feature = "test-util"))]
     pub fn new_for_test() -> Self {
         let mut ni = NetInfo::new();
         ni.add_interface(
@@ -193,6 +193,37 @@ impl<'a> Fragment<'a> {
             t,
         )
     }
+
+    /// Builds an ARP probe (RFC5227) asking if anyone is using `target`.  The sender IP is
+    /// 0.0.0.0 so that other hosts don't update their ARP caches.
+    pub fn new_arp_probe(srcmac: &[u8; 6], target: &net::Ipv4Addr) -> Fragment<'static> {
+        let mut f = Fragment::from_tail(Tail::None);
+        f.push_be16(0x0001); /* Hardware type: Ethernet */
+        f.push_be16(0x0800); /* Protocol type: IPv4 */
+        f.push_u8(6); /* Hardware address length */
+        f.push_u8(4); /* Protocol address length */
+        f.push_be16(0x0001); /* Operation: Request */
+        f.push_bytes(srcmac);
+        f.push_bytes(&net::Ipv4Addr::UNSPECIFIED.octets());
+        f.push_bytes(&[0; 6]);
+        f.push_bytes(&target.octets());
+        Self::new_ethernet(&[0xff; 6], srcmac, 0x0806_u16, Tail::Fragment(Box::new(f)))
+    }
+
+    /// Builds an ICMP echo request, without an IP header, suitable for sending on a raw IPv4
+    /// socket.
+    pub fn new_icmp4_echo<'l>(ident: u16, seq: u16, payload: Tail<'l>) -> Fragment<'l> {
+        let mut f = Fragment::from_tail(payload);
+        f.push_u8(8); /* Type: Echo Request */
+        f.push_u8(0); /* Code */
+        f.push_be16(0x0000); /* Checksum - filled in below */
+        f.push_be16(ident);
+        f.push_be16(seq);
+        let netsum = f.netsum();
+        f.buffer[2] = (netsum >> 8) as u8;
+        f.buffer[3] = (netsum & 0xFF) as u8;
+        f
+    }
 }
 
 #[test]
@@ -213,3 +244,19 @@ fn test_checksum() {
 
     assert_eq!(finish_netsum(partial_netsum(0, &data)), 0xE5CA);
 }
+
+#[test]
+fn test_icmp4_echo() {
+    let f = Fragment::new_icmp4_echo(0x1234, 1, Tail::Payload(&[1, 2, 3, 4])).flatten();
+    assert_eq!(&f[..2], &[8, 0]);
+    /* A packet including a correct checksum sums to zero */
+    assert_eq!(finish_netsum(partial_netsum(0, &f)), 0);
+}
+
+#[test]
+fn test_arp_probe() {
+    let f = Fragment::new_arp_probe(&[2, 0, 0, 0, 0, 1], &"192.0.2.1".parse().unwrap()).flatten();
+    assert_eq!(f.len(), 14 + 28);
+    assert_eq!(&f[12..14], &[0x08, 0x06]);
+    assert_eq!(&f[38..42], &[192, 0, 2, 1]);
+}
@@ -71,14 +71,18 @@ async fn go() -> Result<(), Error> {
     /* Initialise each of the services, and record them */
     let mut services = futures::stream::FuturesUnordered::<JoinHandle<Result<(), String>>>::new();
     #[cfg(feature = "dns")]
-    {
+    let dns = {
         let dns = dns::DnsService::new(conf.clone(), &netinfo)
             .await
             .map_err(|err| Error::Service(err.to_string()))?;
+        let dns_copy = dns.clone();
         services.push(tokio::spawn(async move {
-            dns.run().await.map_err(|err| err.to_string())
+            dns_copy.run().await.map_err(|err| err.to_string())
         }));
-    }
+        Some(dns)
+    };
+    #[cfg(all(not(feature = "dns"), feature = "http", feature = "dhcp"))]
+    let dns: Option<http::DnsService> = None;
 
     #[cfg(feature = "dhcp")]
     let dhcp;
@@ -102,15 +106,24 @@ async fn go() -> Result<(), Error> {
         services.push(tokio::spawn(async move { radv.run().await }));
     }
     #[cfg(all(feature = "http", feature = "dhcp"))]
-    http::run(dhcp, conf.clone())
+    http::run(dhcp, dns.clone(), conf.clone())
         .await
         .map_err(|x| Error::Service(x.to_string()))?;
 
     /* TODO: Perhaps drop some of the capabilities we don't need? */
 
-    /* Now start running them */
-    let x = services.next().await.unwrap();
-    error!("Service complete: {:?}", x);
+    /* Now start running them, until one fails or we're asked to exit */
+    tokio::select! {
+        x = services.next() => error!("Service complete: {:?}", x.unwrap()),
+        x = erbium::shutdown_signal() => {
+            x.map_err(|e| Error::Service(format!("Failed to listen for signals: {}", e)))?;
+            info!("Shutting down")
+        }
+    }
+    #[cfg(feature = "dns")]
+    if let Some(dns) = dns {
+        dns.shutdown().await;
+    }
 
     Ok(())
 }